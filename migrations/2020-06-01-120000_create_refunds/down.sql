@@ -0,0 +1,12 @@
+drop table refunds;
+
+alter table payments drop column order_code;
+
+alter type payment_state rename to payment_state_old;
+create type payment_state as enum ('open', 'paid', 'complete');
+alter table payments alter column state drop default;
+alter table payments alter column state type payment_state using (
+    case when state::text in ('refunded', 'partially_refunded') then 'paid' else state::text end
+)::payment_state;
+alter table payments alter column state set default 'open';
+drop type payment_state_old;
\ No newline at end of file
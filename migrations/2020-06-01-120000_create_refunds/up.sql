@@ -0,0 +1,18 @@
+alter type payment_state rename to payment_state_old;
+create type payment_state as enum ('open', 'paid', 'complete', 'refunded', 'partially_refunded');
+alter table payments alter column state drop default;
+alter table payments alter column state type payment_state using state::text::payment_state;
+alter table payments alter column state set default 'open';
+drop type payment_state_old;
+
+alter table payments add column order_code varchar;
+
+create table refunds (
+    id uuid not null primary key,
+    payment_id uuid not null references payments(id),
+    time timestamp not null default now(),
+    payment_item_id uuid references payment_items(id),
+    quantity int,
+    amount money not null,
+    order_code varchar not null
+);
\ No newline at end of file
@@ -0,0 +1 @@
+drop table payment_attempts;
\ No newline at end of file
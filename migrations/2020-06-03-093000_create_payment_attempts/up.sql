@@ -0,0 +1,11 @@
+create table payment_attempts (
+    id bigserial not null primary key,
+    payment_id uuid not null references payments(id),
+    gateway varchar not null,
+    order_code varchar,
+    payment_status varchar,
+    card_issuer varchar,
+    masked_pan varchar,
+    timestamp timestamp not null default now(),
+    raw_response jsonb not null
+);
\ No newline at end of file
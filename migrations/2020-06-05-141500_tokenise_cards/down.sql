@@ -0,0 +1,10 @@
+drop table cards;
+
+create table cards (
+    id uuid not null primary key,
+    customer_id uuid not null,
+    pan varchar not null,
+    exp_month int not null,
+    exp_year int not null,
+    name_on_card varchar not null
+);
\ No newline at end of file
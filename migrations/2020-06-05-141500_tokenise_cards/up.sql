@@ -0,0 +1,15 @@
+-- Existing rows hold raw card numbers and can't be tokenised from SQL, so they are purged
+drop table cards;
+
+create table cards (
+    id uuid not null primary key,
+    customer_id uuid not null,
+    token varchar not null,
+    last_four varchar(4) not null,
+    brand varchar not null,
+    exp_month int not null,
+    exp_year int not null,
+    name_on_card varchar not null
+);
+
+create index cards_customer_id on cards(customer_id);
\ No newline at end of file
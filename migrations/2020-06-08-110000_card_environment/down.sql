@@ -0,0 +1 @@
+alter table cards drop column environment;
\ No newline at end of file
@@ -0,0 +1 @@
+alter table cards add column environment payment_environment not null default 'live';
\ No newline at end of file
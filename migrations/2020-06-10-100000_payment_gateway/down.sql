@@ -0,0 +1 @@
+alter table payments drop column gateway;
\ No newline at end of file
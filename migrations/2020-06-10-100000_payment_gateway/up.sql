@@ -0,0 +1 @@
+alter table payments add column gateway varchar not null default 'worldpay';
\ No newline at end of file
@@ -0,0 +1,10 @@
+drop table gateway_events;
+
+alter type payment_state rename to payment_state_old;
+create type payment_state as enum ('open', 'paid', 'complete', 'refunded', 'partially_refunded');
+alter table payments alter column state drop default;
+alter table payments alter column state type payment_state using (
+    case when state::text = 'charged_back' then 'paid' else state::text end
+)::payment_state;
+alter table payments alter column state set default 'open';
+drop type payment_state_old;
\ No newline at end of file
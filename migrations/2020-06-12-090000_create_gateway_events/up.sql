@@ -0,0 +1,17 @@
+alter type payment_state rename to payment_state_old;
+create type payment_state as enum ('open', 'paid', 'complete', 'refunded', 'partially_refunded', 'charged_back');
+alter table payments alter column state drop default;
+alter table payments alter column state type payment_state using state::text::payment_state;
+alter table payments alter column state set default 'open';
+drop type payment_state_old;
+
+create table gateway_events (
+    id bigserial not null primary key,
+    gateway varchar not null,
+    order_code varchar not null,
+    event_status varchar not null,
+    payment_id uuid references payments(id),
+    timestamp timestamp not null default now(),
+    raw_body jsonb not null,
+    unique (gateway, order_code, event_status)
+);
\ No newline at end of file
@@ -0,0 +1,16 @@
+alter table payments drop column captured_amount;
+alter table payments drop column authorised_until;
+alter table payments drop column authorise_only;
+
+alter type payment_state rename to payment_state_old;
+create type payment_state as enum ('open', 'paid', 'complete', 'refunded', 'partially_refunded', 'charged_back');
+alter table payments alter column state drop default;
+alter table payments alter column state type payment_state using (
+    case state::text
+        when 'authorised' then 'open'
+        when 'cancelled' then 'open'
+        else state::text
+    end
+)::payment_state;
+alter table payments alter column state set default 'open';
+drop type payment_state_old;
\ No newline at end of file
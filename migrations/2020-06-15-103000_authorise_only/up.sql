@@ -0,0 +1,10 @@
+alter type payment_state rename to payment_state_old;
+create type payment_state as enum ('open', 'authorised', 'paid', 'complete', 'refunded', 'partially_refunded', 'charged_back', 'cancelled');
+alter table payments alter column state drop default;
+alter table payments alter column state type payment_state using state::text::payment_state;
+alter table payments alter column state set default 'open';
+drop type payment_state_old;
+
+alter table payments add column authorise_only boolean not null default false;
+alter table payments add column authorised_until timestamp;
+alter table payments add column captured_amount money;
\ No newline at end of file
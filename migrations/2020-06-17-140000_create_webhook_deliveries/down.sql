@@ -0,0 +1,4 @@
+drop table webhook_delivery_attempts;
+drop table webhook_deliveries;
+
+alter table payments drop column callback_url;
\ No newline at end of file
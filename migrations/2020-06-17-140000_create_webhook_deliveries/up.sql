@@ -0,0 +1,22 @@
+alter table payments add column callback_url varchar;
+
+create table webhook_deliveries (
+    id uuid not null primary key,
+    payment_id uuid not null references payments(id),
+    url varchar not null,
+    event_type varchar not null,
+    payload jsonb not null,
+    created timestamp not null default now(),
+    attempts int not null default 0,
+    next_attempt timestamp not null default now(),
+    delivered timestamp
+);
+create index webhook_deliveries_pending on webhook_deliveries (next_attempt) where delivered is null;
+
+create table webhook_delivery_attempts (
+    id bigserial not null primary key,
+    delivery_id uuid not null references webhook_deliveries(id),
+    timestamp timestamp not null default now(),
+    status_code int,
+    error varchar
+);
\ No newline at end of file
@@ -0,0 +1 @@
+drop table event_outbox;
\ No newline at end of file
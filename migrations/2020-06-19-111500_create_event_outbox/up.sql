@@ -0,0 +1,10 @@
+create table event_outbox (
+    id uuid not null primary key,
+    payment_id uuid not null references payments(id),
+    routing_key varchar not null,
+    payload jsonb not null,
+    created timestamp not null default now(),
+    attempts int not null default 0,
+    published timestamp
+);
+create index event_outbox_pending on event_outbox (created) where published is null;
\ No newline at end of file
@@ -0,0 +1,15 @@
+create type job_state as enum ('pending', 'running', 'complete', 'dead');
+
+create table jobs (
+    id uuid not null primary key,
+    job_type varchar not null,
+    payload jsonb not null,
+    state job_state not null default 'pending',
+    attempts int not null default 0,
+    max_attempts int not null,
+    run_at timestamp not null default now(),
+    last_error varchar,
+    created timestamp not null default now(),
+    updated timestamp not null default now()
+);
+create index jobs_pending on jobs (run_at) where state in ('pending', 'running');
\ No newline at end of file
@@ -0,0 +1 @@
+drop table invoices;
\ No newline at end of file
@@ -0,0 +1,5 @@
+create table invoices (
+    id bigint not null primary key,
+    payment_id uuid not null unique references payments(id),
+    issued timestamp not null default now()
+);
\ No newline at end of file
@@ -0,0 +1,6 @@
+alter table payment_items drop column tax_amount;
+alter table payment_items drop column net_amount;
+alter table payment_items drop column tax_rate;
+alter table payment_items drop column tax_category;
+
+drop type tax_category;
\ No newline at end of file
@@ -0,0 +1,12 @@
+create type tax_category as enum ('standard', 'reduced', 'zero', 'exempt');
+
+alter table payment_items add column tax_category tax_category not null default 'standard';
+alter table payment_items add column tax_rate int not null default 2000;
+alter table payment_items add column net_amount money;
+alter table payment_items add column tax_amount money;
+
+update payment_items set tax_amount = round(price::numeric * quantity * 2000 / 12000, 2)::money;
+update payment_items set net_amount = price * quantity - tax_amount;
+
+alter table payment_items alter column net_amount set not null;
+alter table payment_items alter column tax_amount set not null;
\ No newline at end of file
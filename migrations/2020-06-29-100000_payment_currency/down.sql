@@ -0,0 +1,7 @@
+alter table refunds alter column amount type money using (amount::numeric / 100)::money;
+alter table payment_items alter column tax_amount type money using (tax_amount::numeric / 100)::money;
+alter table payment_items alter column net_amount type money using (net_amount::numeric / 100)::money;
+alter table payment_items alter column price type money using (price::numeric / 100)::money;
+alter table payments alter column captured_amount type money using (captured_amount::numeric / 100)::money;
+
+alter table payments drop column currency;
\ No newline at end of file
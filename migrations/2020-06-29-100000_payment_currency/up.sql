@@ -0,0 +1,7 @@
+alter table payments add column currency varchar(3) not null default 'GBP' check (currency ~ '^[A-Z]{3}$');
+
+alter table payments alter column captured_amount type bigint using (captured_amount::numeric * 100)::bigint;
+alter table payment_items alter column price type bigint using (price::numeric * 100)::bigint;
+alter table payment_items alter column net_amount type bigint using (net_amount::numeric * 100)::bigint;
+alter table payment_items alter column tax_amount type bigint using (tax_amount::numeric * 100)::bigint;
+alter table refunds alter column amount type bigint using (amount::numeric * 100)::bigint;
\ No newline at end of file
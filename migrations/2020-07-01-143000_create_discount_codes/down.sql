@@ -0,0 +1,3 @@
+drop table discount_code_uses;
+drop table discount_codes;
+drop type discount_type;
\ No newline at end of file
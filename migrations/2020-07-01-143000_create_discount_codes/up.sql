@@ -0,0 +1,26 @@
+create type discount_type as enum ('fixed', 'percentage');
+
+create table discount_codes (
+    id uuid not null primary key,
+    code varchar not null unique,
+    discount_type discount_type not null,
+    amount bigint not null check (amount > 0),
+    currency varchar(3),
+    valid_from timestamp,
+    valid_until timestamp,
+    max_uses int,
+    max_uses_per_customer int,
+    item_types varchar[],
+    created timestamp not null default now(),
+    check (discount_type = 'percentage' or currency is not null),
+    check (discount_type = 'fixed' or amount <= 10000)
+);
+
+create table discount_code_uses (
+    id bigserial not null primary key,
+    discount_code_id uuid not null references discount_codes(id),
+    payment_id uuid not null unique references payments(id),
+    customer_id uuid not null,
+    timestamp timestamp not null default now()
+);
+create index discount_code_uses_customer on discount_code_uses (discount_code_id, customer_id);
\ No newline at end of file
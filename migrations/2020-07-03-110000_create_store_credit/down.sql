@@ -0,0 +1,4 @@
+alter table payments drop column store_credit_amount;
+
+drop table store_credit_entries;
+drop type store_credit_entry_type;
\ No newline at end of file
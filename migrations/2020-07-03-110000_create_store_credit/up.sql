@@ -0,0 +1,16 @@
+create type store_credit_entry_type as enum ('issue', 'adjustment', 'spend', 'reversal', 'refund');
+
+create table store_credit_entries (
+    id uuid not null primary key,
+    customer_id uuid not null,
+    currency varchar(3) not null,
+    amount bigint not null,
+    entry_type store_credit_entry_type not null,
+    payment_id uuid references payments(id),
+    reason varchar,
+    created_by uuid,
+    timestamp timestamp not null default now()
+);
+create index store_credit_entries_customer on store_credit_entries (customer_id, currency);
+
+alter table payments add column store_credit_amount bigint not null default 0;
\ No newline at end of file
@@ -0,0 +1,2 @@
+drop table payment_tenders;
+drop type payment_tender_state;
\ No newline at end of file
@@ -0,0 +1,14 @@
+create type payment_tender_state as enum ('pending', 'succeeded', 'failed');
+
+create table payment_tenders (
+    id uuid not null primary key,
+    payment_id uuid not null references payments(id),
+    gateway varchar not null,
+    order_code varchar not null,
+    payment_method varchar,
+    amount bigint not null,
+    state payment_tender_state not null,
+    created timestamp not null default now(),
+    unique (gateway, order_code)
+);
+create index payment_tenders_payment on payment_tenders (payment_id);
\ No newline at end of file
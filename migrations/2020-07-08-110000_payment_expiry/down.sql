@@ -0,0 +1,16 @@
+drop table payment_links;
+
+drop index payments_expires_at;
+alter table payments drop column expires_at;
+
+alter type payment_state rename to payment_state_old;
+create type payment_state as enum ('open', 'authorised', 'paid', 'complete', 'refunded', 'partially_refunded', 'charged_back', 'cancelled');
+alter table payments alter column state drop default;
+alter table payments alter column state type payment_state using (
+    case state::text
+        when 'expired' then 'cancelled'
+        else state::text
+    end
+)::payment_state;
+alter table payments alter column state set default 'open';
+drop type payment_state_old;
\ No newline at end of file
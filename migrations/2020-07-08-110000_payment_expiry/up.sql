@@ -0,0 +1,18 @@
+alter type payment_state rename to payment_state_old;
+create type payment_state as enum ('open', 'authorised', 'paid', 'complete', 'refunded', 'partially_refunded', 'charged_back', 'cancelled', 'expired');
+alter table payments alter column state drop default;
+alter table payments alter column state type payment_state using state::text::payment_state;
+alter table payments alter column state set default 'open';
+drop type payment_state_old;
+
+alter table payments add column expires_at timestamp;
+create index payments_expires_at on payments (expires_at) where state = 'open';
+
+create table payment_links (
+    id uuid not null primary key,
+    payment_id uuid not null references payments(id),
+    expires_at timestamp not null,
+    used_at timestamp,
+    created timestamp not null default now()
+);
+create index payment_links_payment on payment_links (payment_id);
\ No newline at end of file
@@ -0,0 +1 @@
+drop table payment_admin_actions;
\ No newline at end of file
@@ -0,0 +1,12 @@
+create table payment_admin_actions (
+    id bigserial not null primary key,
+    payment_id uuid not null references payments(id),
+    actor uuid not null,
+    action varchar not null,
+    from_state payment_state,
+    to_state payment_state,
+    details jsonb not null default '{}',
+    reason varchar,
+    timestamp timestamp not null default now()
+);
+create index payment_admin_actions_payment on payment_admin_actions (payment_id);
\ No newline at end of file
@@ -0,0 +1,4 @@
+drop trigger payment_events_append_only on payment_events;
+drop function payment_events_append_only();
+drop table payment_events;
+drop type payment_event_actor_type;
\ No newline at end of file
@@ -0,0 +1,23 @@
+create type payment_event_actor_type as enum ('system', 'client', 'user', 'anonymous', 'gateway');
+
+create table payment_events (
+    id bigserial not null primary key,
+    payment_id uuid not null references payments(id),
+    actor_type payment_event_actor_type not null,
+    actor varchar,
+    ip_address varchar,
+    action varchar not null,
+    old_values jsonb,
+    new_values jsonb,
+    timestamp timestamp not null default now()
+);
+create index payment_events_payment on payment_events (payment_id, id);
+
+create function payment_events_append_only() returns trigger as $$
+begin
+    raise exception 'payment_events is append-only';
+end;
+$$ language plpgsql;
+
+create trigger payment_events_append_only before update or delete on payment_events
+    for each row execute procedure payment_events_append_only();
\ No newline at end of file
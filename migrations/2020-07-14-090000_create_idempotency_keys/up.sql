@@ -0,0 +1,11 @@
+create table idempotency_keys (
+    scope varchar not null,
+    key varchar not null,
+    request_hash varchar not null,
+    response_status int4,
+    response_body jsonb,
+    created timestamp not null default now(),
+    expires_at timestamp not null,
+    primary key (scope, key)
+);
+create index idempotency_keys_expires_at on idempotency_keys (expires_at);
\ No newline at end of file
@@ -0,0 +1 @@
+alter table idempotency_keys drop column locked_until;
\ No newline at end of file
@@ -0,0 +1 @@
+alter table idempotency_keys add column locked_until timestamp;
\ No newline at end of file
@@ -0,0 +1,2 @@
+alter table refunds drop column state;
+drop type refund_state;
\ No newline at end of file
@@ -0,0 +1,3 @@
+create type refund_state as enum ('pending', 'succeeded', 'failed');
+
+alter table refunds add column state refund_state not null default 'succeeded';
\ No newline at end of file
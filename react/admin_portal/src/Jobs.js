@@ -0,0 +1,98 @@
+import React, {Component} from "react";
+import {API_ROOT} from "./admin";
+import SVG from "react-inlinesvg";
+import loader from "./loader.svg";
+
+export default class Jobs extends Component {
+    constructor(props, context) {
+        super(props, context);
+
+        this.updateJobs = this.updateJobs.bind(this);
+        this.retryJob = this.retryJob.bind(this);
+
+        this.state = {
+            loading: true,
+            jobs: []
+        };
+    }
+
+    componentDidMount() {
+        this.updateJobs();
+    }
+
+    updateJobs() {
+        this.setState({
+            loading: true,
+        })
+        fetch(`${API_ROOT}jobs/failed/`, {
+            credentials: 'include',
+        })
+            .then(resp => {
+                if (resp.ok) {
+                    return resp.json();
+                } else {
+                    throw new Error('Something went wrong');
+                }
+            })
+            .then(resp => {
+                this.setState({
+                    loading: false,
+                    jobs: resp
+                })
+            })
+    }
+
+    retryJob(id) {
+        this.setState({
+            loading: true,
+        })
+        fetch(`${API_ROOT}jobs/${id}/retry/`, {
+            method: 'POST',
+            credentials: 'include',
+        })
+            .then(resp => {
+                if (!resp.ok) {
+                    throw new Error('Something went wrong');
+                }
+            })
+            .then(this.updateJobs)
+    }
+
+    render() {
+        return <React.Fragment>
+            <h2>Failed jobs</h2>
+            {this.state.loading ? <div className="loading">
+                <SVG src={loader} className="loader"/>
+            </div> : <table className="orders">
+                <thead>
+                    <tr>
+                        <th>ID</th>
+                        <th>Type</th>
+                        <th>Data</th>
+                        <th>Attempts</th>
+                        <th>Last error</th>
+                        <th>Last attempted</th>
+                        <th/>
+                    </tr>
+                </thead>
+                <tbody>
+                    {this.state.jobs.map(job => {
+                        return <tr key={job.id}>
+                            <td>{job.id}</td>
+                            <td>{job.type}</td>
+                            <td>{JSON.stringify(job.data)}</td>
+                            <td>{job.attempts}/{job.max_attempts}</td>
+                            <td>{job.last_error}</td>
+                            <td>{job.updated}</td>
+                            <td>
+                                <div className="buttons">
+                                    <button onClick={() => this.retryJob(job.id)}>Retry</button>
+                                </div>
+                            </td>
+                        </tr>
+                    })}
+                </tbody>
+            </table>}
+        </React.Fragment>
+    }
+}
\ No newline at end of file
@@ -9,10 +9,19 @@ export default class Order extends Component {
         console.log(props);
 
         this.updateOrder = this.updateOrder.bind(this);
+        this.changeState = this.changeState.bind(this);
+        this.editItems = this.editItems.bind(this);
+        this.handleItemChange = this.handleItemChange.bind(this);
+        this.addItem = this.addItem.bind(this);
+        this.removeItem = this.removeItem.bind(this);
+        this.saveItems = this.saveItems.bind(this);
 
         this.state = {
             loading: true,
             order: null,
+            events: [],
+            editItems: null,
+            error: null,
         };
     }
 
@@ -37,11 +46,134 @@ export default class Order extends Component {
             .then(resp => {
                 this.setState({
                     loading: false,
-                    order: resp
+                    order: resp,
+                    editItems: null,
+                })
+            })
+        fetch(`${API_ROOT}payment/${this.props.match.params.id}/events/`, {
+            credentials: 'include',
+        })
+            .then(resp => {
+                if (resp.ok) {
+                    return resp.json();
+                } else {
+                    throw new Error('Something went wrong');
+                }
+            })
+            .then(resp => {
+                this.setState({
+                    events: resp
                 })
             })
     }
 
+    changeState(state) {
+        const reason = window.prompt(`Reason for moving this order to ${state}`);
+        if (reason === null) {
+            return;
+        }
+        this.setState({
+            error: null,
+        });
+        fetch(`${API_ROOT}payment/${this.props.match.params.id}/state/`, {
+            method: 'POST',
+            credentials: 'include',
+            headers: {
+                'Content-Type': 'application/json',
+            },
+            body: JSON.stringify({
+                state: state,
+                reason: reason,
+            }),
+        })
+            .then(resp => {
+                if (!resp.ok) {
+                    return resp.text().then(text => {
+                        throw new Error(text || 'Something went wrong');
+                    });
+                }
+            })
+            .then(this.updateOrder)
+            .catch(err => this.setState({
+                error: err.message,
+            }))
+    }
+
+    editItems() {
+        this.setState({
+            editItems: this.state.order.items.map(item => ({
+                item_type: item.type,
+                item_data: item.data,
+                title: item.title,
+                price: item.price,
+                quantity: item.quantity,
+                tax_category: item.tax_category,
+            })),
+        });
+    }
+
+    handleItemChange(index, event) {
+        const items = this.state.editItems.slice();
+        items[index] = {
+            ...items[index],
+            [event.target.name]: event.target.value,
+        };
+        this.setState({
+            editItems: items,
+        });
+    }
+
+    addItem() {
+        this.setState({
+            editItems: [...this.state.editItems, {
+                item_type: "",
+                item_data: {},
+                title: "",
+                price: "0",
+                quantity: 1,
+                tax_category: "STANDARD",
+            }],
+        });
+    }
+
+    removeItem(index) {
+        this.setState({
+            editItems: this.state.editItems.filter((_, i) => i !== index),
+        });
+    }
+
+    saveItems(event) {
+        event.preventDefault();
+        this.setState({
+            error: null,
+        });
+        fetch(`${API_ROOT}payment/${this.props.match.params.id}/items/`, {
+            method: 'PUT',
+            credentials: 'include',
+            headers: {
+                'Content-Type': 'application/json',
+            },
+            body: JSON.stringify({
+                items: this.state.editItems.map(item => ({
+                    ...item,
+                    price: item.price.toString(),
+                    quantity: parseInt(item.quantity, 10),
+                })),
+            }),
+        })
+            .then(resp => {
+                if (!resp.ok) {
+                    return resp.text().then(text => {
+                        throw new Error(text || 'Something went wrong');
+                    });
+                }
+            })
+            .then(this.updateOrder)
+            .catch(err => this.setState({
+                error: err.message,
+            }))
+    }
+
     render() {
         return <React.Fragment>
             <h2>Order</h2>
@@ -60,8 +192,59 @@ export default class Order extends Component {
                     </a><br/>
                     <b>Payment method:</b> {this.state.order.payment_method}<br/>
                 </p>
+                {this.state.error ? <p className="error">{this.state.error}</p> : null}
+                <div className="buttons sideways">
+                    {this.state.order.state === "OPEN" ?
+                        <button onClick={() => this.changeState("CANCELLED")}>Cancel</button> : null}
+                    {this.state.order.state === "PAID" || this.state.order.state === "PARTIALLY_REFUNDED" ?
+                        <button onClick={() => this.changeState("COMPLETE")}>Mark complete</button> : null}
+                    {this.state.order.state === "OPEN" && this.state.editItems === null ?
+                        <button onClick={this.editItems}>Edit items</button> : null}
+                </div>
                 <h2>Items</h2>
-                <table>
+                {this.state.editItems !== null ? <form onSubmit={this.saveItems}>
+                    <table>
+                        <thead>
+                            <tr>
+                                <th>Type</th>
+                                <th>Title</th>
+                                <th>Price</th>
+                                <th>Quantity</th>
+                                <th>Tax category</th>
+                                <th/>
+                            </tr>
+                        </thead>
+                        <tbody>
+                        {this.state.editItems.map((item, i) => <tr key={i}>
+                            <td><input type="text" name="item_type" value={item.item_type}
+                                       onChange={e => this.handleItemChange(i, e)}/></td>
+                            <td><input type="text" name="title" value={item.title}
+                                       onChange={e => this.handleItemChange(i, e)}/></td>
+                            <td><input type="number" name="price" step="0.01" min="0" value={item.price}
+                                       onChange={e => this.handleItemChange(i, e)}/></td>
+                            <td><input type="number" name="quantity" min="1" value={item.quantity}
+                                       onChange={e => this.handleItemChange(i, e)}/></td>
+                            <td>
+                                <select name="tax_category" value={item.tax_category}
+                                        onChange={e => this.handleItemChange(i, e)}>
+                                    {["STANDARD", "REDUCED", "ZERO", "EXEMPT"].map(c =>
+                                        <option key={c} value={c}>{c}</option>)}
+                                </select>
+                            </td>
+                            <td>
+                                <div className="buttons">
+                                    <button type="button" onClick={() => this.removeItem(i)}>Remove</button>
+                                </div>
+                            </td>
+                        </tr>)}
+                        </tbody>
+                    </table>
+                    <div className="buttons sideways">
+                        <button type="button" onClick={this.addItem}>Add item</button>
+                        <button type="button" onClick={() => this.setState({editItems: null})}>Discard</button>
+                        <button type="submit">Save</button>
+                    </div>
+                </form> : <table>
                     <thead>
                         <tr>
                             <th>ID</th>
@@ -77,11 +260,34 @@ export default class Order extends Component {
                         <td>{item.id}</td>
                         <td>{item.type}</td>
                         <td>{item.title}</td>
-                        <td>{item.price}</td>
+                        <td>{item.price} {this.state.order.currency}</td>
                         <td>{item.quantity}</td>
                         <td>{item.data}</td>
                     </tr>)}
                     </tbody>
+                </table>}
+                <h2>Timeline</h2>
+                <table className="timeline">
+                    <thead>
+                        <tr>
+                            <th>Timestamp</th>
+                            <th>Action</th>
+                            <th>Actor</th>
+                            <th>IP address</th>
+                            <th>Old values</th>
+                            <th>New values</th>
+                        </tr>
+                    </thead>
+                    <tbody>
+                    {this.state.events.map(event => <tr key={event.id}>
+                        <td>{event.timestamp}</td>
+                        <td>{event.action}</td>
+                        <td>{event.actor_type}{event.actor ? `: ${event.actor}` : null}</td>
+                        <td>{event.ip_address}</td>
+                        <td><code>{event.old_values ? JSON.stringify(event.old_values) : null}</code></td>
+                        <td><code>{event.new_values ? JSON.stringify(event.new_values) : null}</code></td>
+                    </tr>)}
+                    </tbody>
                 </table>
             </React.Fragment>
                 }
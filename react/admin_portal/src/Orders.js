@@ -11,12 +11,26 @@ export default class Orders extends Component {
         this.updateOrders = this.updateOrders.bind(this);
         this.nextList = this.nextList.bind(this);
         this.prevList = this.prevList.bind(this);
+        this.handleFilterChange = this.handleFilterChange.bind(this);
+        this.applyFilters = this.applyFilters.bind(this);
 
         this.state = {
             loading: true,
-            offset: 0,
-            limit: 5,
-            orders: []
+            cursors: [null],
+            limit: 25,
+            filters: {
+                search: "",
+                state: "",
+                environment: "",
+                customer_id: "",
+                payment_method: "",
+                item_type: "",
+                from: "",
+                to: "",
+            },
+            orders: [],
+            total: 0,
+            nextCursor: null,
         };
     }
 
@@ -26,18 +40,29 @@ export default class Orders extends Component {
 
     nextList() {
         this.setState({
-            offset: this.state.offset + this.state.limit
+            cursors: [...this.state.cursors, this.state.nextCursor]
         }, this.updateOrders);
-
     }
 
     prevList() {
-        let nextVal = this.state.offset - this.state.limit;
-        if (nextVal < 0) {
-            nextVal = 0;
-        }
         this.setState({
-            offset: nextVal
+            cursors: this.state.cursors.slice(0, Math.max(this.state.cursors.length - 1, 1))
+        }, this.updateOrders);
+    }
+
+    handleFilterChange(event) {
+        this.setState({
+            filters: {
+                ...this.state.filters,
+                [event.target.name]: event.target.value
+            }
+        });
+    }
+
+    applyFilters(event) {
+        event.preventDefault();
+        this.setState({
+            cursors: [null]
         }, this.updateOrders);
     }
 
@@ -45,7 +70,18 @@ export default class Orders extends Component {
         this.setState({
             loading: true,
         })
-        fetch(`${API_ROOT}payments/?offset=${this.state.offset}&limit=${this.state.limit}`, {
+        const params = new URLSearchParams();
+        params.set("limit", this.state.limit);
+        const cursor = this.state.cursors[this.state.cursors.length - 1];
+        if (cursor) {
+            params.set("cursor", cursor);
+        }
+        Object.entries(this.state.filters).forEach(([key, value]) => {
+            if (value) {
+                params.set(key, (key === "from" || key === "to") ? new Date(value).toISOString() : value);
+            }
+        });
+        fetch(`${API_ROOT}payments/?${params.toString()}`, {
             credentials: 'include',
         })
             .then(resp => {
@@ -58,7 +94,9 @@ export default class Orders extends Component {
             .then(resp => {
                 this.setState({
                     loading: false,
-                    orders: resp
+                    orders: resp.payments,
+                    total: resp.total,
+                    nextCursor: resp.next_cursor,
                 })
             })
     }
@@ -66,6 +104,31 @@ export default class Orders extends Component {
     render() {
         return <React.Fragment>
             <h2>Orders</h2>
+            <form className="filters" onSubmit={this.applyFilters}>
+                <input type="search" name="search" placeholder="Search items" value={this.state.filters.search}
+                       onChange={this.handleFilterChange}/>
+                <select name="state" value={this.state.filters.state} onChange={this.handleFilterChange}>
+                    <option value="">Any state</option>
+                    {["OPEN", "AUTHORISED", "PAID", "COMPLETE", "REFUNDED", "PARTIALLY_REFUNDED", "CHARGED_BACK",
+                        "CANCELLED", "EXPIRED"].map(state => <option key={state} value={state}>{state}</option>)}
+                </select>
+                <select name="environment" value={this.state.filters.environment} onChange={this.handleFilterChange}>
+                    <option value="">Any environment</option>
+                    <option value="LIVE">LIVE</option>
+                    <option value="TEST">TEST</option>
+                </select>
+                <input type="text" name="customer_id" placeholder="Customer ID" value={this.state.filters.customer_id}
+                       onChange={this.handleFilterChange}/>
+                <input type="text" name="payment_method" placeholder="Payment method"
+                       value={this.state.filters.payment_method} onChange={this.handleFilterChange}/>
+                <input type="text" name="item_type" placeholder="Item type" value={this.state.filters.item_type}
+                       onChange={this.handleFilterChange}/>
+                <input type="datetime-local" name="from" value={this.state.filters.from}
+                       onChange={this.handleFilterChange}/>
+                <input type="datetime-local" name="to" value={this.state.filters.to}
+                       onChange={this.handleFilterChange}/>
+                <button type="submit">Filter</button>
+            </form>
             {this.state.loading ? <div className="loading">
                 <SVG src={loader} className="loader"/>
             </div> : <React.Fragment>
@@ -91,8 +154,8 @@ export default class Orders extends Component {
                                 <td>{order.payment_method}</td>
                                 <td>
                                     <a target="_blank"
-                                       href={`https://account.cardifftec.uk/auth/admin/wwfypc/console/#/realms/wwfypc/users/${order.customer.id}`}>
-                                        {order.customer.id}
+                                       href={`https://account.cardifftec.uk/auth/admin/wwfypc/console/#/realms/wwfypc/users/${order.customer_id}`}>
+                                        {order.customer_id}
                                     </a>
                                 </td>
                                 <td>
@@ -104,9 +167,10 @@ export default class Orders extends Component {
                         })}
                     </tbody>
                 </table>
+                <p>{this.state.total} orders</p>
                 <div className="buttons sideways">
-                    <button disabled={this.state.offset === 0} onClick={this.prevList}>Previous</button>
-                    <button disabled={this.state.orders.length !== this.state.limit} onClick={this.nextList}>Next</button>
+                    <button disabled={this.state.cursors.length === 1} onClick={this.prevList}>Previous</button>
+                    <button disabled={!this.state.nextCursor} onClick={this.nextList}>Next</button>
                 </div>
             </React.Fragment>
                 }
@@ -13,6 +13,7 @@ import {
 } from "react-router-dom";
 import Orders from "./Orders";
 import Order from "./Order";
+import Jobs from "./Jobs";
 
 export const API_ROOT = process.env.BASE_URL ? process.env.BASE_URL :
     process.env.NODE_ENV  === 'production' ? 'https://payments.cardifftec.uk/' : 'https://wwfypc-payments.eu.ngrok.io/';
@@ -137,12 +138,17 @@ class Admin extends Component {
                     <header>
                         <h2>Cardifftec Payments</h2>
                         <div className="buttons">
+                            <button><Link to="/">Orders</Link></button>
+                            <button><Link to="/jobs/">Failed jobs</Link></button>
                             <button onClick={this.openLogoutPopup}>Logout</button>
                         </div>
                     </header>
                     <main>
                         <Switch>
                           <Route path="/order/:id/" render={(props) => <Order {...props}/> }/>
+                          <Route path="/jobs/">
+                            <Jobs/>
+                          </Route>
                           <Route path="/">
                             <Orders/>
                           </Route>
@@ -64,15 +64,15 @@ export default class StripeWrapper extends Component {
 
                     const paymentRequest = stripe.paymentRequest({
                         country: 'GB',
-                        currency: 'gbp',
+                        currency: resp.currency.toLowerCase(),
                         total: {
                             label: 'Total',
-                            amount: (resp.items.reduce((prev, item) => prev + item.price, 0.0) * 100),
+                            amount: Math.round(resp.outstanding * Math.pow(10, resp.currency_exponent)),
                         },
                         displayItems: resp.items.map(item => {
                             return {
                                 label: item.title,
-                                amount: item.price*100,
+                                amount: Math.round(item.price * Math.pow(10, resp.currency_exponent)),
                             }
                         }),
                         requestPayerName: resp.customer === null,
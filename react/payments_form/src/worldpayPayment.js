@@ -36,6 +36,7 @@ export default class WorldpayPayment extends Component {
             canUsePaymentRequests: null,
             isApplePayReady: null,
             applePaySession: null,
+            idempotencyKey: null,
         };
 
         this.handleError = this.handleError.bind(this);
@@ -160,7 +161,7 @@ export default class WorldpayPayment extends Component {
     }
 
     paymentTotal() {
-        return this.state.payment.items.reduce((prev, item) => prev + item.price, 0.0);
+        return this.state.payment.outstanding;
     }
 
     paymentDetails() {
@@ -171,7 +172,7 @@ export default class WorldpayPayment extends Component {
             total: {
                 label: 'Total',
                 amount: {
-                    currency: 'GBP',
+                    currency: this.state.payment.currency,
                     value: total
                 }
             },
@@ -179,7 +180,7 @@ export default class WorldpayPayment extends Component {
                 return {
                     label: item.title,
                     amount: {
-                        currency: 'GBP',
+                        currency: this.state.payment.currency,
                         value: item.price,
                     }
                 }
@@ -248,7 +249,7 @@ export default class WorldpayPayment extends Component {
     applePaymentRequest() {
         const paymentDataRequest = {
             countryCode: 'GB',
-            currencyCode: 'GBP',
+            currencyCode: this.state.payment.currency,
             supportedNetworks: allowedAppleCardNetworks,
             merchantCapabilities: ['supports3DS', 'supportsEMV', 'supportsCredit', 'supportsDebit'],
             requiredBillingContactFields: ["postalAddress", "email", "phone", "name"],
@@ -401,18 +402,29 @@ export default class WorldpayPayment extends Component {
             }
         }
 
+        const idempotencyKey = this.state.idempotencyKey || uuid.v4();
+        this.setState({
+            idempotencyKey: idempotencyKey
+        });
+
         fetch(`${API_ROOT}payment/worldpay/${this.state.payment.id}/`, {
             method: "POST",
             credentials: 'include',
             body: JSON.stringify(data),
             headers: {
-                "Content-Type": "application/json"
+                "Content-Type": "application/json",
+                "Idempotency-Key": idempotencyKey
             }
         })
             .then(resp => {
                 if (resp.ok) {
                     return resp.json();
                 } else {
+                    if (resp.status >= 400 && resp.status < 500 && resp.status !== 409) {
+                        this.setState({
+                            idempotencyKey: null
+                        });
+                    }
                     throw new Error('Something went wrong');
                 }
             })
@@ -436,6 +448,7 @@ export default class WorldpayPayment extends Component {
                     res.complete('success')
                         .then(() => {
                             this.setState({
+                                idempotencyKey: null,
                                 accountData: {
                                     resp: resp,
                                     data: data,
@@ -444,6 +457,9 @@ export default class WorldpayPayment extends Component {
                         })
                         .catch(err => this.handleError(err))
                 } else if (resp.state === "FAILED") {
+                    this.setState({
+                        idempotencyKey: null
+                    });
                     res.complete('fail')
                         .then(() => {
                             this.handleError(null, "Payment failed")
@@ -479,6 +495,9 @@ export default class WorldpayPayment extends Component {
                 if (event.data.threeds_approved) {
                     this.onComplete(this.state.email);
                 } else {
+                    this.setState({
+                        idempotencyKey: null
+                    });
                     this.handleError(null, "Payment failed");
                 }
             }
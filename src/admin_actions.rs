@@ -0,0 +1,74 @@
+use diesel::prelude::*;
+use diesel::pg::PgConnection;
+use uuid::Uuid;
+
+use crate::{models, schema};
+
+pub const TRANSITION: &str = "transition";
+pub const EDIT_ITEMS: &str = "edit_items";
+
+#[derive(Debug)]
+pub enum AdminActionError {
+    InvalidTransition(models::PaymentState, models::PaymentState),
+    PaymentNotOpen,
+    PartiallyPaid,
+    HasAdjustments,
+    Transition(crate::state_machine::TransitionError),
+    Database(diesel::result::Error),
+}
+
+impl std::fmt::Display for AdminActionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AdminActionError::InvalidTransition(from, to) => write!(f, "payment cannot move from {:?} to {:?}", from, to),
+            AdminActionError::PaymentNotOpen => write!(f, "payment is not open"),
+            AdminActionError::PartiallyPaid => write!(f, "payment has been partially paid"),
+            AdminActionError::HasAdjustments => write!(f, "payment has discounts or store credit applied"),
+            AdminActionError::Transition(e) => e.fmt(f),
+            AdminActionError::Database(e) => e.fmt(f),
+        }
+    }
+}
+
+impl From<diesel::result::Error> for AdminActionError {
+    fn from(e: diesel::result::Error) -> Self {
+        AdminActionError::Database(e)
+    }
+}
+
+impl From<crate::state_machine::TransitionError> for AdminActionError {
+    fn from(e: crate::state_machine::TransitionError) -> Self {
+        match e {
+            crate::state_machine::TransitionError::Database(e) => AdminActionError::Database(e),
+            e => AdminActionError::Transition(e)
+        }
+    }
+}
+
+pub fn allowed_transition(from: models::PaymentState, to: models::PaymentState) -> bool {
+    use models::PaymentState::*;
+
+    match (from, to) {
+        (OPEN, CANCELLED) | (_, COMPLETE) => crate::state_machine::allowed(from, to),
+        _ => false
+    }
+}
+
+pub fn record(
+    conn: &PgConnection, payment_id: &Uuid, actor: &Uuid, action: &str, from_state: Option<models::PaymentState>,
+    to_state: Option<models::PaymentState>, details: &serde_json::Value, reason: Option<&str>,
+) -> Result<(), diesel::result::Error> {
+    diesel::insert_into(schema::payment_admin_actions::table)
+        .values(&models::NewPaymentAdminAction {
+            payment_id,
+            actor,
+            action,
+            from_state,
+            to_state,
+            details,
+            reason,
+        })
+        .execute(conn)?;
+
+    Ok(())
+}
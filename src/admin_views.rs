@@ -1,4 +1,6 @@
-use actix_web::{HttpResponse};
+use actix_web::{HttpRequest, HttpResponse, web};
+use chrono::prelude::*;
+use crate::db;
 
 pub async fn render_admin() -> actix_web::Result<impl actix_web::Responder> {
     let context = tera::Context::new();
@@ -7,4 +9,271 @@ pub async fn render_admin() -> actix_web::Result<impl actix_web::Responder> {
         Ok(r) => Ok(HttpResponse::Ok().body(r)),
         Err(e) => Err(actix_web::error::ErrorInternalServerError(e))
     }
-}
\ No newline at end of file
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct JobResponseData {
+    id: uuid::Uuid,
+    #[serde(rename = "type")]
+    job_type: String,
+    data: serde_json::Value,
+    state: crate::models::JobState,
+    attempts: i32,
+    max_attempts: i32,
+    last_error: Option<String>,
+    created: DateTime<Utc>,
+    updated: DateTime<Utc>,
+}
+
+impl From<crate::models::Job> for JobResponseData {
+    fn from(job: crate::models::Job) -> Self {
+        JobResponseData {
+            id: job.id,
+            job_type: job.job_type,
+            data: job.payload,
+            state: job.state,
+            attempts: job.attempts,
+            max_attempts: job.max_attempts,
+            last_error: job.last_error,
+            created: DateTime::<Utc>::from_utc(job.created, Utc),
+            updated: DateTime::<Utc>::from_utc(job.updated, Utc),
+        }
+    }
+}
+
+pub async fn get_failed_jobs(data: web::Data<crate::config::AppState>, session: actix_session::Session) -> actix_web::Result<impl actix_web::Responder> {
+    let (_token_introspect, oauth_token) = match crate::util::user_token_from_session(&session, &data.oauth).await? {
+        Some(u) => u,
+        None => return Err(actix_web::error::ErrorForbidden(""))
+    };
+
+    data.oauth.verify_token(&oauth_token.access_token, "manage-jobs").await?;
+
+    let jobs = match match data.db.send(db::GetJobs::new(crate::models::JobState::DEAD)).await {
+        Ok(j) => j,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Ok(j) => j,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    };
+
+    Ok(HttpResponse::Ok().json(jobs.into_iter().map(JobResponseData::from).collect::<Vec<_>>()))
+}
+
+pub async fn retry_job(data: web::Data<crate::config::AppState>, session: actix_session::Session, info: web::Path<uuid::Uuid>) -> actix_web::Result<impl actix_web::Responder> {
+    let (_token_introspect, oauth_token) = match crate::util::user_token_from_session(&session, &data.oauth).await? {
+        Some(u) => u,
+        None => return Err(actix_web::error::ErrorForbidden(""))
+    };
+
+    data.oauth.verify_token(&oauth_token.access_token, "manage-jobs").await?;
+
+    let job = match match data.db.send(db::RetryJob::new(&info.into_inner())).await {
+        Ok(j) => j,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Ok(j) => j,
+        Err(e) => return match e {
+            diesel::result::Error::NotFound => Err(actix_web::error::ErrorNotFound(e)),
+            _ => Err(actix_web::error::ErrorInternalServerError(e))
+        }
+    };
+
+    Ok(HttpResponse::Ok().json(JobResponseData::from(job)))
+}
+
+fn admin_action_error(e: crate::admin_actions::AdminActionError) -> actix_web::Error {
+    match e {
+        crate::admin_actions::AdminActionError::Database(e) => match e {
+            diesel::result::Error::NotFound => actix_web::error::ErrorNotFound(e),
+            e => actix_web::error::ErrorInternalServerError(e)
+        },
+        crate::admin_actions::AdminActionError::Transition(e) => e.into(),
+        e => actix_web::error::ErrorBadRequest(e)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct PaymentStateData {
+    state: crate::models::PaymentState,
+    reason: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct PaymentStateResponseData {
+    state: crate::models::PaymentState,
+}
+
+pub async fn update_payment_state(req: HttpRequest, data: web::Data<crate::config::AppState>, session: actix_session::Session, info: web::Path<uuid::Uuid>, state_data: web::Json<PaymentStateData>) -> actix_web::Result<impl actix_web::Responder> {
+    let admin_id = crate::util::staff_id_from_session(&session, &data.oauth, "manage-payments").await?;
+    let actor = crate::audit::Actor::user(&req, &admin_id);
+
+    let payment = match match data.db.send(db::AdminTransitionPayment::new(
+        &info.into_inner(), &admin_id, state_data.state,
+        state_data.reason.as_deref().map(str::trim).filter(|r| !r.is_empty()), &actor,
+    )).await {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Ok(r) => r,
+        Err(e) => return Err(admin_action_error(e))
+    };
+
+    Ok(HttpResponse::Ok().json(PaymentStateResponseData {
+        state: payment.state,
+    }))
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct PaymentItemData {
+    item_type: String,
+    item_data: serde_json::Value,
+    title: String,
+    quantity: i32,
+    price: rust_decimal::Decimal,
+    #[serde(default)]
+    tax_category: crate::models::TaxCategory,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct PaymentItemsData {
+    items: Vec<PaymentItemData>,
+    reason: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct PaymentItemResponseData {
+    id: uuid::Uuid,
+    #[serde(rename = "type")]
+    item_type: String,
+    #[serde(rename = "data")]
+    item_data: serde_json::Value,
+    title: String,
+    price: f64,
+    quantity: i32,
+    tax_category: crate::models::TaxCategory,
+}
+
+pub async fn update_payment_items(req: HttpRequest, data: web::Data<crate::config::AppState>, session: actix_session::Session, info: web::Path<uuid::Uuid>, items_data: web::Json<PaymentItemsData>) -> actix_web::Result<impl actix_web::Responder> {
+    let admin_id = crate::util::staff_id_from_session(&session, &data.oauth, "manage-payments").await?;
+    let actor = crate::audit::Actor::user(&req, &admin_id);
+
+    let payment = match match data.db.send(db::GetPayment::new(&info.into_inner())).await {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Ok(r) => r,
+        Err(e) => return match e {
+            diesel::result::Error::NotFound => Err(actix_web::error::ErrorNotFound(e)),
+            _ => Err(actix_web::error::ErrorInternalServerError(e))
+        }
+    };
+    if payment.is_expired(&Utc::now().naive_utc()) {
+        return Err(actix_web::error::ErrorGone("payment has expired"));
+    }
+    let currency = crate::currency::Currency::for_payment(&payment);
+
+    if items_data.items.is_empty() {
+        return Err(actix_web::error::ErrorBadRequest("at least one item is required"));
+    }
+    let mut items: Vec<db::CreatePaymentItem> = vec![];
+    for i in items_data.items.iter() {
+        if i.item_type == crate::discounts::ITEM_TYPE {
+            return Err(actix_web::error::ErrorBadRequest("discounts cannot be edited"));
+        }
+        if i.quantity <= 0 {
+            return Err(actix_web::error::ErrorBadRequest("invalid quantity"));
+        }
+        let price = match currency.to_minor(&i.price) {
+            Some(p) if p >= 0 => p,
+            _ => return Err(actix_web::error::ErrorBadRequest("invalid price"))
+        };
+        items.push(db::CreatePaymentItem::new(
+            &uuid::Uuid::new_v4(),
+            &i.item_type,
+            &i.item_data,
+            &i.title,
+            i.quantity,
+            price,
+            i.tax_category,
+        ));
+    }
+
+    let items = match match data.db.send(db::AdminReplacePaymentItems::new(
+        &payment.id, &admin_id, &items,
+        items_data.reason.as_deref().map(str::trim).filter(|r| !r.is_empty()), &actor,
+    )).await {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Ok(r) => r,
+        Err(e) => return Err(admin_action_error(e))
+    };
+
+    Ok(HttpResponse::Ok().json(items.into_iter().map(|i| PaymentItemResponseData {
+        id: i.id,
+        item_type: i.item_type,
+        item_data: i.item_data,
+        title: i.title,
+        quantity: i.quantity,
+        price: currency.to_major(i.price),
+        tax_category: i.tax_category,
+    }).collect::<Vec<_>>()))
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct PaymentEventResponseData {
+    id: i64,
+    actor_type: crate::models::PaymentEventActorType,
+    actor: Option<String>,
+    ip_address: Option<String>,
+    action: String,
+    old_values: Option<serde_json::Value>,
+    new_values: Option<serde_json::Value>,
+    timestamp: DateTime<Utc>,
+}
+
+impl From<crate::models::PaymentAuditEvent> for PaymentEventResponseData {
+    fn from(event: crate::models::PaymentAuditEvent) -> Self {
+        PaymentEventResponseData {
+            id: event.id,
+            actor_type: event.actor_type,
+            actor: event.actor,
+            ip_address: event.ip_address,
+            action: event.action,
+            old_values: event.old_values,
+            new_values: event.new_values,
+            timestamp: DateTime::<Utc>::from_utc(event.timestamp, Utc),
+        }
+    }
+}
+
+pub async fn get_payment_events(data: web::Data<crate::config::AppState>, session: actix_session::Session, info: web::Path<uuid::Uuid>) -> actix_web::Result<impl actix_web::Responder> {
+    let (_token_introspect, oauth_token) = match crate::util::user_token_from_session(&session, &data.oauth).await? {
+        Some(u) => u,
+        None => return Err(actix_web::error::ErrorForbidden(""))
+    };
+
+    data.oauth.verify_token(&oauth_token.access_token, "view-payments").await?;
+
+    let payment = match match data.db.send(db::GetPayment::new(&info.into_inner())).await {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Ok(r) => r,
+        Err(e) => return match e {
+            diesel::result::Error::NotFound => Err(actix_web::error::ErrorNotFound(e)),
+            _ => Err(actix_web::error::ErrorInternalServerError(e))
+        }
+    };
+
+    let events = match match data.db.send(db::GetPaymentAuditEvents::new(&payment)).await {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    };
+
+    Ok(HttpResponse::Ok().json(events.into_iter().map(PaymentEventResponseData::from).collect::<Vec<_>>()))
+}
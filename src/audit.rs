@@ -0,0 +1,102 @@
+use diesel::prelude::*;
+use diesel::pg::PgConnection;
+use uuid::Uuid;
+
+use crate::{models, schema};
+
+pub const CREATED: &str = "created";
+pub const VIEWED: &str = "viewed";
+pub const STATE_CHANGED: &str = "state_changed";
+pub const CAPTURED: &str = "captured";
+pub const REFUNDED: &str = "refunded";
+pub const TENDER_COMPLETED: &str = "tender_completed";
+pub const ITEMS_EDITED: &str = "items_edited";
+pub const DISCOUNT_APPLIED: &str = "discount_applied";
+pub const STORE_CREDIT_APPLIED: &str = "store_credit_applied";
+pub const STORE_CREDIT_REMOVED: &str = "store_credit_removed";
+pub const LINK_CREATED: &str = "link_created";
+pub const LINK_USED: &str = "link_used";
+
+// Repeat views by the same actor, such as the payment form polling, are only recorded once per window
+pub const VIEW_DEDUPE_MINUTES: i64 = 15;
+
+#[derive(Debug, Clone)]
+pub struct Actor {
+    pub actor_type: models::PaymentEventActorType,
+    pub id: Option<String>,
+    pub ip_address: Option<String>,
+}
+
+fn ip_address(req: &actix_web::HttpRequest) -> Option<String> {
+    req.peer_addr().map(|a| a.ip().to_string())
+}
+
+impl Actor {
+    pub fn system() -> Self {
+        Self {
+            actor_type: models::PaymentEventActorType::SYSTEM,
+            id: None,
+            ip_address: None,
+        }
+    }
+
+    pub fn client(req: &actix_web::HttpRequest, introspect: &crate::oauth::OAuthTokenIntrospect) -> Self {
+        Self {
+            actor_type: models::PaymentEventActorType::CLIENT,
+            id: introspect.client_id.clone()
+                .or_else(|| introspect.azp.clone())
+                .or_else(|| introspect.sub.clone()),
+            ip_address: ip_address(req),
+        }
+    }
+
+    pub fn user(req: &actix_web::HttpRequest, user_id: &Uuid) -> Self {
+        Self {
+            actor_type: models::PaymentEventActorType::USER,
+            id: Some(user_id.to_string()),
+            ip_address: ip_address(req),
+        }
+    }
+
+    pub fn anonymous(req: &actix_web::HttpRequest) -> Self {
+        Self {
+            actor_type: models::PaymentEventActorType::ANONYMOUS,
+            id: None,
+            ip_address: ip_address(req),
+        }
+    }
+
+    pub fn gateway(req: &actix_web::HttpRequest, gateway: &str) -> Self {
+        Self {
+            actor_type: models::PaymentEventActorType::GATEWAY,
+            id: Some(gateway.to_owned()),
+            ip_address: ip_address(req),
+        }
+    }
+
+    pub async fn from_session(req: &actix_web::HttpRequest, session: &actix_session::Session, oauth_client: &crate::oauth::OAuthClient) -> actix_web::Result<Self> {
+        Ok(match crate::util::user_id_from_session(session, oauth_client).await? {
+            Some(u) => Self::user(req, &u),
+            None => Self::anonymous(req)
+        })
+    }
+}
+
+pub fn record(
+    conn: &PgConnection, payment_id: &Uuid, actor: &Actor, action: &str, old_values: Option<&serde_json::Value>,
+    new_values: Option<&serde_json::Value>,
+) -> Result<(), diesel::result::Error> {
+    diesel::insert_into(schema::payment_events::table)
+        .values(&models::NewPaymentAuditEvent {
+            payment_id,
+            actor_type: actor.actor_type,
+            actor: actor.id.as_deref(),
+            ip_address: actor.ip_address.as_deref(),
+            action,
+            old_values,
+            new_values,
+        })
+        .execute(conn)?;
+
+    Ok(())
+}
@@ -0,0 +1,80 @@
+use actix_web::{HttpResponse, web};
+use crate::db;
+
+#[derive(Clone, Debug, Serialize)]
+struct CardResponseData {
+    id: uuid::Uuid,
+    last_four: String,
+    brand: String,
+    exp_month: i32,
+    exp_year: i32,
+    name: String,
+    environment: crate::models::PaymentEnvironment,
+}
+
+pub async fn get_cards(data: web::Data<crate::config::AppState>, session: actix_session::Session) -> actix_web::Result<impl actix_web::Responder> {
+    let user_id = match crate::util::user_id_from_session(&session, &data.oauth).await? {
+        Some(u) => u,
+        None => return Err(actix_web::error::ErrorUnauthorized(""))
+    };
+
+    let cards = match match data.db.send(db::GetCards::new(&user_id)).await {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    };
+
+    let response_data = cards.into_iter()
+        .map(|card| CardResponseData {
+            id: card.id,
+            last_four: card.last_four,
+            brand: card.brand,
+            exp_month: card.exp_month,
+            exp_year: card.exp_year,
+            name: card.name_on_card,
+            environment: card.environment,
+        })
+        .collect::<Vec<_>>();
+
+    Ok(HttpResponse::Ok().json(response_data))
+}
+
+pub async fn delete_card(data: web::Data<crate::config::AppState>, info: web::Path<uuid::Uuid>, session: actix_session::Session) -> actix_web::Result<impl actix_web::Responder> {
+    let user_id = match crate::util::user_id_from_session(&session, &data.oauth).await? {
+        Some(u) => u,
+        None => return Err(actix_web::error::ErrorUnauthorized(""))
+    };
+
+    let card = match match data.db.send(db::GetCard::new(&info.into_inner())).await {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Ok(r) => r,
+        Err(e) => return match e {
+            diesel::result::Error::NotFound => Err(actix_web::error::ErrorNotFound(e)),
+            _ => Err(actix_web::error::ErrorInternalServerError(e))
+        }
+    };
+
+    if card.customer_id != user_id {
+        return Err(actix_web::error::ErrorNotFound(""));
+    }
+
+    if let Some(gateway) = crate::gateway::get(&data, "worldpay", card.environment) {
+        if let Err(e) = gateway.delete_token(card.environment, &card.token).await {
+            warn!("Unable to delete {} token for card {}: {}", gateway.name(), card.id, e);
+        }
+    }
+
+    match match data.db.send(db::DeleteCard::new(&card)).await {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Ok(_) => {}
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    };
+
+    Ok(HttpResponse::NoContent().finish())
+}
\ No newline at end of file
@@ -64,6 +64,18 @@ pub fn worldpay_config() -> WorldpayConfig {
     WorldpayConfig {
         test_key: env::var("WORLDPAY_TEST_KEY").unwrap(),
         live_key: env::var("WORLDPAY_LIVE_KEY").unwrap(),
+        test_client_key: env::var("WORLDPAY_TEST_CLIENT_KEY").expect("WORLDPAY_TEST_CLIENT_KEY must be set"),
+        live_client_key: env::var("WORLDPAY_LIVE_CLIENT_KEY").expect("WORLDPAY_LIVE_CLIENT_KEY must be set"),
+        webhook_secret: env::var("WORLDPAY_WEBHOOK_SECRET").unwrap(),
+    }
+}
+
+pub fn stripe_config() -> StripeConfig {
+    dotenv().ok();
+
+    StripeConfig {
+        test_key: env::var("STRIPE_TEST_KEY").unwrap(),
+        live_key: env::var("STRIPE_LIVE_KEY").unwrap(),
     }
 }
 
@@ -90,14 +102,98 @@ pub fn mail_client() -> lettre::smtp::SmtpClient {
         .smtp_utf8(true)
 }
 
-pub fn amqp_client() -> amqp::Channel {
+fn notification_recipient_list(value: &str) -> Vec<String> {
+    value.split(',')
+        .map(|r| r.trim())
+        .filter(|r| !r.is_empty())
+        .map(|r| r.to_string())
+        .collect()
+}
+
+fn notification_recipients(environment: &str, default: &str) -> NotificationRecipients {
+    let prefix = format!("NOTIFICATION_{}_RECIPIENTS", environment);
+    let item_prefix = format!("{}_", prefix);
+
+    NotificationRecipients {
+        default: notification_recipient_list(&env::var(&prefix).unwrap_or(default.to_string())),
+        item_types: env::vars()
+            .filter(|(k, _)| k.starts_with(&item_prefix))
+            .map(|(k, v)| (k[item_prefix.len()..].to_lowercase(), notification_recipient_list(&v)))
+            .collect(),
+    }
+}
+
+pub fn notification_config() -> NotificationConfig {
+    dotenv().ok();
+
+    NotificationConfig {
+        from: env::var("NOTIFICATION_FROM")
+            .unwrap_or("noreply@noreply.wewillfixyourpc.co.uk".to_string()),
+        reply_to: env::var("NOTIFICATION_REPLY_TO").ok(),
+        subject: env::var("NOTIFICATION_SUBJECT")
+            .unwrap_or("New order notification".to_string()),
+        receipt_subject: env::var("RECEIPT_SUBJECT")
+            .unwrap_or("Your We Will Fix Your PC receipt".to_string()),
+        live: notification_recipients("LIVE", "q@misell.cymru"),
+        test: notification_recipients("TEST", ""),
+    }
+}
+
+pub fn invoice_config() -> InvoiceConfig {
+    dotenv().ok();
+
+    InvoiceConfig {
+        company_name: env::var("INVOICE_COMPANY_NAME")
+            .unwrap_or("We Will Fix Your PC".to_string()),
+        company_address: env::var("INVOICE_COMPANY_ADDRESS")
+            .unwrap_or("".to_string())
+            .split(',')
+            .map(|l| l.trim().to_string())
+            .filter(|l| !l.is_empty())
+            .collect(),
+        company_email: env::var("INVOICE_COMPANY_EMAIL").ok(),
+        vat_number: env::var("INVOICE_VAT_NUMBER").ok(),
+    }
+}
+
+pub fn amqp_url() -> String {
+    dotenv().ok();
+
+    env::var("AMPQ_SERVER")
+        .unwrap_or("amqp://localhost//".to_string())
+}
+
+pub fn webhook_signing_key() -> Vec<u8> {
     dotenv().ok();
 
-    let server = env::var("AMPQ_SERVER")
-        .unwrap_or("amqp://localhost//".to_string());
-    let mut session = amqp::Session::open_url(&server).unwrap();
-    let channel = session.open_channel(1).unwrap();
-    channel
+    env::var("WEBHOOK_SIGNING_KEY")
+        .expect("WEBHOOK_SIGNING_KEY must be set")
+        .into_bytes()
+}
+
+pub fn payment_link_key() -> Vec<u8> {
+    dotenv().ok();
+
+    env::var("PAYMENT_LINK_KEY")
+        .expect("PAYMENT_LINK_KEY must be set")
+        .into_bytes()
+}
+
+pub fn idempotency_key_secret() -> Vec<u8> {
+    dotenv().ok();
+
+    env::var("IDEMPOTENCY_KEY_SECRET")
+        .expect("IDEMPOTENCY_KEY_SECRET must be set")
+        .into_bytes()
+}
+
+pub fn mock_gateway() -> bool {
+    dotenv().ok();
+
+    match env::var("MOCK_GATEWAY") {
+        Ok(v) => v == "true" || v == "1",
+        Err(_) => false
+    }
 }
 
 pub fn apple_pay_identity() -> reqwest::Client {
@@ -122,6 +218,39 @@ pub fn apple_pay_identity() -> reqwest::Client {
 pub struct WorldpayConfig {
     pub test_key: String,
     pub live_key: String,
+    pub test_client_key: String,
+    pub live_client_key: String,
+    pub webhook_secret: String,
+}
+
+#[derive(Clone)]
+pub struct StripeConfig {
+    pub test_key: String,
+    pub live_key: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct NotificationRecipients {
+    pub default: Vec<String>,
+    pub item_types: std::collections::HashMap<String, Vec<String>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct NotificationConfig {
+    pub from: String,
+    pub reply_to: Option<String>,
+    pub subject: String,
+    pub receipt_subject: String,
+    pub live: NotificationRecipients,
+    pub test: NotificationRecipients,
+}
+
+#[derive(Clone, Debug)]
+pub struct InvoiceConfig {
+    pub company_name: String,
+    pub company_address: Vec<String>,
+    pub company_email: Option<String>,
+    pub vat_number: Option<String>,
 }
 
 #[derive(Clone)]
@@ -129,6 +258,11 @@ pub struct AppState {
     pub oauth: crate::oauth::OAuthClient,
     pub keycloak: crate::keycloak::KeycloakClient,
     pub worldpay: WorldpayConfig,
+    pub stripe: StripeConfig,
+    pub mock_gateway: bool,
+    pub invoice: InvoiceConfig,
+    pub payment_link_key: Vec<u8>,
+    pub idempotency_key_secret: Vec<u8>,
     pub apple_pay_client: reqwest::Client,
     pub db: Addr<crate::db::DbExecutor>,
     pub jobs_state: crate::jobs::JobsState,
@@ -0,0 +1,81 @@
+use rust_decimal::prelude::*;
+
+use crate::models;
+
+const CURRENCIES: &[&str] = &[
+    "AED", "AFN", "ALL", "AMD", "ANG", "AOA", "ARS", "AUD", "AWG", "AZN", "BAM", "BBD", "BDT", "BGN",
+    "BHD", "BIF", "BMD", "BND", "BOB", "BOV", "BRL", "BSD", "BTN", "BWP", "BYN", "BZD", "CAD", "CDF",
+    "CHE", "CHF", "CHW", "CLF", "CLP", "CNY", "COP", "COU", "CRC", "CUC", "CUP", "CVE", "CZK", "DJF",
+    "DKK", "DOP", "DZD", "EGP", "ERN", "ETB", "EUR", "FJD", "FKP", "GBP", "GEL", "GHS", "GIP", "GMD",
+    "GNF", "GTQ", "GYD", "HKD", "HNL", "HRK", "HTG", "HUF", "IDR", "ILS", "INR", "IQD", "IRR", "ISK",
+    "JMD", "JOD", "JPY", "KES", "KGS", "KHR", "KMF", "KPW", "KRW", "KWD", "KYD", "KZT", "LAK", "LBP",
+    "LKR", "LRD", "LSL", "LYD", "MAD", "MDL", "MGA", "MKD", "MMK", "MNT", "MOP", "MRU", "MUR", "MVR",
+    "MWK", "MXN", "MXV", "MYR", "MZN", "NAD", "NGN", "NIO", "NOK", "NPR", "NZD", "OMR", "PAB", "PEN",
+    "PGK", "PHP", "PKR", "PLN", "PYG", "QAR", "RON", "RSD", "RUB", "RWF", "SAR", "SBD", "SCR", "SDG",
+    "SEK", "SGD", "SHP", "SLL", "SOS", "SRD", "SSP", "STN", "SVC", "SYP", "SZL", "THB", "TJS", "TMT",
+    "TND", "TOP", "TRY", "TTD", "TWD", "TZS", "UAH", "UGX", "USD", "USN", "UYI", "UYU", "UYW", "UZS",
+    "VES", "VND", "VUV", "WST", "XAF", "XCD", "XOF", "XPF", "YER", "ZAR", "ZMW", "ZWL",
+];
+
+pub const DEFAULT: &str = "GBP";
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Currency {
+    pub code: &'static str,
+    pub exponent: u32,
+}
+
+impl Currency {
+    pub fn lookup(code: &str) -> Option<Self> {
+        let code = *CURRENCIES.iter().find(|c| **c == code)?;
+        let exponent = match code {
+            "BIF" | "CLP" | "DJF" | "GNF" | "ISK" | "JPY" | "KMF" | "KRW" | "PYG" | "RWF" | "UGX" | "UYI" |
+            "VND" | "VUV" | "XAF" | "XOF" | "XPF" => 0,
+            "BHD" | "IQD" | "JOD" | "KWD" | "LYD" | "OMR" | "TND" => 3,
+            "CLF" | "UYW" => 4,
+            _ => 2
+        };
+
+        Some(Self {
+            code,
+            exponent,
+        })
+    }
+
+    pub fn for_payment(payment: &models::Payment) -> Self {
+        match Self::lookup(&payment.currency) {
+            Some(c) => c,
+            None => {
+                warn!("Payment {} has unknown currency {}", payment.id, payment.currency);
+                Self::lookup(DEFAULT).unwrap()
+            }
+        }
+    }
+
+    pub fn to_minor(&self, amount: &Decimal) -> Option<i64> {
+        let amount = amount.normalize();
+        if amount.scale() > self.exponent {
+            return None;
+        }
+        (amount * Decimal::new(10i64.pow(self.exponent), 0)).to_i64()
+    }
+
+    pub fn to_major(&self, amount: i64) -> f64 {
+        (amount as f64) / 10f64.powi(self.exponent as i32)
+    }
+
+    pub fn format(&self, amount: i64) -> String {
+        if self.exponent == 0 {
+            return amount.to_string();
+        }
+        let divisor = 10i64.pow(self.exponent);
+        format!(
+            "{}{}.{:0width$}", if amount < 0 { "-" } else { "" },
+            amount.abs() / divisor, amount.abs() % divisor, width = self.exponent as usize
+        )
+    }
+
+    pub fn format_with_code(&self, amount: i64) -> String {
+        format!("{} {}", self.format(amount), self.code)
+    }
+}
@@ -3,9 +3,7 @@ use diesel::prelude::*;
 use diesel::pg::PgConnection;
 use uuid::Uuid;
 use chrono::prelude::*;
-use rust_decimal::prelude::*;
 use crate::{models, schema};
-use diesel::data_types::PgMoney as Pence;
 
 pub struct DbExecutor(PgConnection);
 
@@ -19,6 +17,72 @@ impl Actor for DbExecutor {
     type Context = SyncContext<Self>;
 }
 
+fn record_payment_event(conn: &PgConnection, payment: &models::Payment, event: crate::events::PaymentEvent) -> Result<(), diesel::result::Error> {
+    let event_id = Uuid::new_v4();
+    let new_event = models::NewOutboxEvent {
+        id: &event_id,
+        payment_id: &payment.id,
+        routing_key: &crate::events::routing_key(event, payment.environment),
+        payload: &crate::events::payload(&event_id, event, payment),
+    };
+
+    diesel::insert_into(schema::event_outbox::table)
+        .values(&new_event)
+        .execute(conn)?;
+
+    Ok(())
+}
+
+fn record_job(conn: &PgConnection, job: &crate::jobs::Job) -> Result<(), diesel::result::Error> {
+    let new_job = models::NewJob {
+        id: &Uuid::new_v4(),
+        job_type: job.job_type(),
+        payload: &serde_json::to_value(job)
+            .map_err(|e| diesel::result::Error::SerializationError(Box::new(e)))?,
+        max_attempts: crate::jobs::MAX_ATTEMPTS,
+    };
+
+    diesel::insert_into(schema::jobs::table)
+        .values(&new_job)
+        .execute(conn)?;
+
+    Ok(())
+}
+
+fn record_state_change(conn: &PgConnection, previous_state: models::PaymentState, payment: &models::Payment) -> Result<(), diesel::result::Error> {
+    if previous_state != payment.state {
+        if let Some(event) = crate::events::PaymentEvent::for_state(payment.state) {
+            record_payment_event(conn, payment, event)?;
+        }
+        if let Some(event_type) = crate::webhooks::EventType::for_state(payment.state) {
+            crate::webhooks::record_event(conn, payment, event_type)?;
+        }
+        if previous_state == models::PaymentState::OPEN &&
+            (payment.state == models::PaymentState::AUTHORISED || payment.state == models::PaymentState::PAID) {
+            record_job(conn, &crate::jobs::Job::SendPaymentNotification {
+                payment_id: payment.id
+            })?;
+        }
+        if payment.state == models::PaymentState::PAID {
+            record_job(conn, &crate::jobs::Job::SendPaymentReceipt {
+                payment_id: payment.id
+            })?;
+        }
+        if payment.state == models::PaymentState::CANCELLED || payment.state == models::PaymentState::EXPIRED {
+            if payment.store_credit_amount > 0 {
+                crate::store_credit::record(
+                    conn, &payment.customer_id, &payment.currency, payment.store_credit_amount,
+                    models::StoreCreditEntryType::REVERSAL, Some(&payment.id), None, None,
+                )?;
+            }
+            diesel::delete(schema::discount_code_uses::table.filter(schema::discount_code_uses::payment_id.eq(&payment.id)))
+                .execute(conn)?;
+        }
+    }
+
+    Ok(())
+}
+
 pub struct GetPayment {
     id: Uuid,
 }
@@ -46,39 +110,129 @@ impl Handler<GetPayment> for DbExecutor {
     }
 }
 
+#[derive(Debug, Clone, Default)]
+pub struct PaymentFilter {
+    pub state: Option<models::PaymentState>,
+    pub environment: Option<models::PaymentEnvironment>,
+    pub customer_id: Option<Uuid>,
+    pub from: Option<NaiveDateTime>,
+    pub to: Option<NaiveDateTime>,
+    pub payment_method: Option<String>,
+    pub item_type: Option<String>,
+    pub search: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PaymentCursor {
+    pub time: NaiveDateTime,
+    pub id: Uuid,
+}
+
+impl PaymentCursor {
+    pub fn for_payment(payment: &models::Payment) -> Self {
+        Self {
+            time: payment.time,
+            id: payment.id,
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        format!("{}.{}", self.time.timestamp_nanos(), self.id)
+    }
+
+    pub fn decode(cursor: &str) -> Option<Self> {
+        let mut parts = cursor.splitn(2, '.');
+        let nanos = parts.next()?.parse::<i64>().ok()?;
+        let id = Uuid::parse_str(parts.next()?).ok()?;
+        Some(Self {
+            time: NaiveDateTime::from_timestamp(nanos.div_euclid(1_000_000_000), nanos.rem_euclid(1_000_000_000) as u32),
+            id,
+        })
+    }
+}
+
+fn like_pattern(s: &str) -> String {
+    format!("%{}%", s.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_"))
+}
+
+fn filtered_payments(filter: &PaymentFilter) -> schema::payments::BoxedQuery<'static, diesel::pg::Pg> {
+    use schema::payments::dsl::*;
+
+    let mut query = payments.into_boxed();
+    if let Some(f) = filter.state {
+        query = query.filter(state.eq(f));
+    }
+    if let Some(f) = filter.environment {
+        query = query.filter(environment.eq(f));
+    }
+    if let Some(f) = filter.customer_id {
+        query = query.filter(customer_id.eq(f));
+    }
+    if let Some(f) = filter.from {
+        query = query.filter(time.ge(f));
+    }
+    if let Some(f) = filter.to {
+        query = query.filter(time.lt(f));
+    }
+    if let Some(f) = &filter.payment_method {
+        query = query.filter(payment_method.ilike(like_pattern(f)));
+    }
+    if let Some(f) = &filter.item_type {
+        query = query.filter(id.eq_any(
+            schema::payment_items::table
+                .select(schema::payment_items::payment_id)
+                .filter(schema::payment_items::item_type.eq(f.to_owned()))
+        ));
+    }
+    if let Some(f) = &filter.search {
+        query = query.filter(id.eq_any(
+            schema::payment_items::table
+                .select(schema::payment_items::payment_id)
+                .filter(schema::payment_items::title.ilike(like_pattern(f)))
+        ));
+    }
+    query
+}
+
 pub struct GetPayments {
-    offset: Option<i64>,
-    limit: Option<i64>,
+    filter: PaymentFilter,
+    cursor: Option<PaymentCursor>,
+    limit: i64,
 }
 
 impl GetPayments {
-    pub fn new<I: Into<Option<i64>>>(offset: I, limit: I) -> Self {
+    pub fn new(filter: &PaymentFilter, cursor: Option<&PaymentCursor>, limit: i64) -> Self {
         Self {
-            offset: offset.into(),
-            limit: limit.into()
+            filter: filter.to_owned(),
+            cursor: cursor.map(|c| c.to_owned()),
+            limit,
         }
     }
 }
 
 impl Message for GetPayments {
-    type Result = Result<Vec<models::Payment>, diesel::result::Error>;
+    type Result = Result<(Vec<models::Payment>, i64), diesel::result::Error>;
 }
 
 impl Handler<GetPayments> for DbExecutor {
-    type Result = Result<Vec<models::Payment>, diesel::result::Error>;
+    type Result = Result<(Vec<models::Payment>, i64), diesel::result::Error>;
 
     fn handle(&mut self, msg: GetPayments, _: &mut Self::Context) -> Self::Result {
         use schema::payments::dsl::*;
 
-        let mut dsl = payments.order(time.desc()).offset(0);
-        if let Some(offset) = msg.offset {
-            dsl = dsl.offset(offset);
-        }
-        let mut dsl = dsl.limit(100);
-        if let Some(limit) = msg.limit {
-            dsl = dsl.limit(limit);
+        let total = filtered_payments(&msg.filter)
+            .count()
+            .get_result::<i64>(&self.0)?;
+
+        let mut query = filtered_payments(&msg.filter);
+        if let Some(cursor) = msg.cursor {
+            query = query.filter(time.lt(cursor.time).or(time.eq(cursor.time).and(id.lt(cursor.id))));
         }
-        dsl.load::<models::Payment>(&self.0)
+        let results = query.order((time.desc(), id.desc()))
+            .limit(msg.limit)
+            .load::<models::Payment>(&self.0)?;
+
+        Ok((results, total))
     }
 }
 
@@ -114,7 +268,12 @@ pub struct CreatePayment {
     state: models::PaymentState,
     environment: models::PaymentEnvironment,
     customer_id: Uuid,
+    authorise_only: bool,
+    callback_url: Option<String>,
+    currency: String,
+    expires_at: Option<NaiveDateTime>,
     items: Vec<CreatePaymentItem>,
+    actor: crate::audit::Actor,
 }
 
 #[derive(Debug, Clone)]
@@ -124,31 +283,49 @@ pub struct CreatePaymentItem {
     item_data: serde_json::Value,
     title: String,
     quantity: i32,
-    price: rust_decimal::Decimal,
+    price: i64,
+    tax_category: models::TaxCategory,
 }
 
 impl CreatePayment {
-    pub fn new(id: &Uuid, time: &NaiveDateTime, state: models::PaymentState, environment: models::PaymentEnvironment, customer_id: &Uuid, items: &[CreatePaymentItem]) -> Self {
+    pub fn new(id: &Uuid, time: &NaiveDateTime, state: models::PaymentState, environment: models::PaymentEnvironment, customer_id: &Uuid, authorise_only: bool, callback_url: Option<&str>, currency: &crate::currency::Currency, expires_at: Option<&NaiveDateTime>, items: &[CreatePaymentItem], actor: &crate::audit::Actor) -> Self {
         Self {
             id: id.to_owned(),
             time: time.to_owned(),
             state,
             environment,
             customer_id: customer_id.to_owned(),
+            authorise_only,
+            callback_url: callback_url.map(|s| s.to_owned()),
+            currency: currency.code.to_owned(),
+            expires_at: expires_at.map(|e| e.to_owned()),
             items: items.to_vec(),
+            actor: actor.to_owned(),
         }
     }
 }
 
 impl CreatePaymentItem {
-    pub fn new(id: &Uuid, item_type: &str, item_data: &serde_json::Value, title: &str, quantity: i32, price: &rust_decimal::Decimal) -> Self {
+    fn audit_values(&self) -> serde_json::Value {
+        serde_json::json!({
+            "id": self.id,
+            "type": self.item_type,
+            "title": self.title,
+            "quantity": self.quantity,
+            "price": self.price,
+            "tax_category": self.tax_category,
+        })
+    }
+
+    pub fn new(id: &Uuid, item_type: &str, item_data: &serde_json::Value, title: &str, quantity: i32, price: i64, tax_category: models::TaxCategory) -> Self {
         Self {
             id: id.to_owned(),
             item_type: item_type.to_owned(),
             item_data: item_data.to_owned(),
             title: title.to_owned(),
             quantity,
-            price: price.to_owned(),
+            price,
+            tax_category,
         }
     }
 }
@@ -168,67 +345,163 @@ impl Handler<CreatePayment> for DbExecutor {
                 state: msg.state,
                 environment: msg.environment,
                 customer_id: &msg.customer_id,
+                authorise_only: msg.authorise_only,
+                callback_url: msg.callback_url.as_deref(),
+                currency: &msg.currency,
+                expires_at: msg.expires_at.as_ref(),
             };
 
             let payment = diesel::insert_into(schema::payments::table)
                 .values(&new_payment)
                 .get_result(&self.0)?;
-
-            for item in msg.items.iter() {
-                let new_payment_item = models::NewPaymentItem {
-                    id: &item.id,
-                    payment_id: &msg.id,
-                    item_type: &item.item_type,
-                    item_data: &item.item_data,
-                    title: &item.title,
-                    quantity: item.quantity,
-                    price: &Pence((item.price * rust_decimal::Decimal::new(100, 0)).to_i64().unwrap()),
-                };
-
-                diesel::insert_into(schema::payment_items::table)
-                    .values(&new_payment_item)
-                    .execute(&self.0)?;
-            }
+            record_payment_event(&self.0, &payment, crate::events::PaymentEvent::Created)?;
+            insert_payment_items(&self.0, &msg.id, &msg.items)?;
+            crate::audit::record(&self.0, &msg.id, &msg.actor, crate::audit::CREATED, None, Some(&serde_json::json!({
+                "state": msg.state,
+                "environment": msg.environment,
+                "customer_id": msg.customer_id,
+                "authorise_only": msg.authorise_only,
+                "callback_url": msg.callback_url,
+                "currency": msg.currency,
+                "expires_at": msg.expires_at,
+                "items": msg.items.iter().map(CreatePaymentItem::audit_values).collect::<Vec<_>>(),
+            })))?;
 
             Ok(payment)
         })
     }
 }
 
+fn insert_payment_items(conn: &PgConnection, payment_id: &Uuid, items: &[CreatePaymentItem]) -> Result<(), diesel::result::Error> {
+    for item in items {
+        let tax_rate = crate::tax::rate(item.tax_category);
+        let (net_amount, tax_amount) = crate::tax::split_gross(item.price * item.quantity as i64, tax_rate);
+        let new_payment_item = models::NewPaymentItem {
+            id: &item.id,
+            payment_id,
+            item_type: &item.item_type,
+            item_data: &item.item_data,
+            title: &item.title,
+            quantity: item.quantity,
+            price: item.price,
+            tax_category: item.tax_category,
+            tax_rate,
+            net_amount,
+            tax_amount,
+        };
+
+        diesel::insert_into(schema::payment_items::table)
+            .values(&new_payment_item)
+            .execute(conn)?;
+    }
+
+    Ok(())
+}
+
 #[derive(Debug, Clone)]
 pub struct UpdatePaymentState {
     id: Uuid,
+    expected: models::PaymentState,
     state: models::PaymentState,
     payment_method: Option<String>,
+    order_code: Option<String>,
+    gateway: Option<String>,
+    actor: crate::audit::Actor,
 }
 
 impl UpdatePaymentState {
-    pub fn new(id: &Uuid, state: models::PaymentState, payment_method: Option<&str>) -> Self {
+    pub fn new(id: &Uuid, expected: models::PaymentState, state: models::PaymentState, payment_method: Option<&str>, order_code: Option<&str>, gateway: Option<&str>, actor: &crate::audit::Actor) -> Self {
         Self {
             id: id.to_owned(),
+            expected,
             state,
             payment_method: match payment_method {
                 Some(s) => Some(s.to_owned()),
                 None => None
-            }
+            },
+            order_code: match order_code {
+                Some(s) => Some(s.to_owned()),
+                None => None
+            },
+            gateway: match gateway {
+                Some(s) => Some(s.to_owned()),
+                None => None
+            },
+            actor: actor.to_owned(),
         }
     }
 }
 
 impl Message for UpdatePaymentState {
-    type Result = Result<(), diesel::result::Error>;
+    type Result = Result<(), crate::state_machine::TransitionError>;
 }
 
 impl Handler<UpdatePaymentState> for DbExecutor {
-    type Result = Result<(), diesel::result::Error>;
+    type Result = Result<(), crate::state_machine::TransitionError>;
 
     fn handle(&mut self, msg: UpdatePaymentState, _: &mut Self::Context) -> Self::Result {
-       let q = diesel::update(schema::payments::table.find(msg.id));
+        let changeset = models::PaymentStateChangeset {
+            state: msg.state,
+            payment_method: msg.payment_method.as_deref(),
+            order_code: msg.order_code.as_deref(),
+            gateway: msg.gateway.as_deref(),
+        };
+
+        self.0.transaction(|| {
+            let previous = schema::payments::table.find(msg.id)
+                .for_update()
+                .first::<models::Payment>(&self.0)?;
+            if previous.state != msg.expected || previous.state == msg.state {
+                return Err(crate::state_machine::TransitionError::Conflict(msg.expected));
+            }
+            let updated = crate::state_machine::transition(&self.0, &previous, msg.state, &msg.actor)?;
+            let payment = diesel::update(&updated)
+                .set(&changeset)
+                .get_result::<models::Payment>(&self.0)?;
+            record_state_change(&self.0, previous.state, &payment)?;
+
+            Ok(())
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct UpdatePaymentGateway {
+    id: Uuid,
+    expected: models::PaymentState,
+    order_code: Option<String>,
+    gateway: String,
+}
+
+impl UpdatePaymentGateway {
+    pub fn new(id: &Uuid, expected: models::PaymentState, order_code: Option<&str>, gateway: &str) -> Self {
+        Self {
+            id: id.to_owned(),
+            expected,
+            order_code: order_code.map(|s| s.to_owned()),
+            gateway: gateway.to_owned(),
+        }
+    }
+}
+
+impl Message for UpdatePaymentGateway {
+    type Result = Result<(), crate::state_machine::TransitionError>;
+}
+
+impl Handler<UpdatePaymentGateway> for DbExecutor {
+    type Result = Result<(), crate::state_machine::TransitionError>;
 
-        if let Some(pm) = msg.payment_method {
-            q.set((schema::payments::state.eq(msg.state), schema::payments::payment_method.eq(pm))).execute(&self.0)?;
-        } else {
-            q.set(schema::payments::state.eq(msg.state)).execute(&self.0)?;
+    fn handle(&mut self, msg: UpdatePaymentGateway, _: &mut Self::Context) -> Self::Result {
+        use schema::payments::dsl::*;
+
+        let updated = diesel::update(payments.find(msg.id).filter(state.eq(msg.expected)))
+            .set(&models::PaymentGatewayChangeset {
+                order_code: msg.order_code.as_deref(),
+                gateway: Some(&msg.gateway),
+            })
+            .execute(&self.0)?;
+        if updated == 0 {
+            return Err(crate::state_machine::TransitionError::Conflict(msg.expected));
         }
 
         Ok(())
@@ -236,172 +509,2398 @@ impl Handler<UpdatePaymentState> for DbExecutor {
 }
 
 #[derive(Debug, Clone)]
-pub struct CreateThreedsData {
-    payment_id: Uuid,
-    one_time_3ds_token: String,
-    redirect_url: String,
-    order_id: String,
+pub struct AuthorisePayment {
+    id: Uuid,
+    payment_method: Option<String>,
+    order_code: String,
+    gateway: String,
+    authorised_until: NaiveDateTime,
+    actor: crate::audit::Actor,
 }
 
-impl CreateThreedsData {
-    pub fn new(payment_id: &Uuid, one_time_3ds_token: &str, redirect_url: &str, order_id: &str) -> Self {
+impl AuthorisePayment {
+    pub fn new(id: &Uuid, payment_method: Option<&str>, order_code: &str, gateway: &str, authorised_until: &NaiveDateTime, actor: &crate::audit::Actor) -> Self {
         Self {
-            payment_id: payment_id.to_owned(),
-            one_time_3ds_token: one_time_3ds_token.to_owned(),
-            redirect_url: redirect_url.to_owned(),
-            order_id: order_id.to_owned()
+            id: id.to_owned(),
+            payment_method: payment_method.map(|s| s.to_owned()),
+            order_code: order_code.to_owned(),
+            gateway: gateway.to_owned(),
+            authorised_until: authorised_until.to_owned(),
+            actor: actor.to_owned(),
         }
     }
 }
 
-impl Message for CreateThreedsData {
-    type Result = Result<models::ThreedsData, diesel::result::Error>;
+impl Message for AuthorisePayment {
+    type Result = Result<(), crate::state_machine::TransitionError>;
 }
 
-impl Handler<CreateThreedsData> for DbExecutor {
-    type Result = Result<models::ThreedsData, diesel::result::Error>;
+impl Handler<AuthorisePayment> for DbExecutor {
+    type Result = Result<(), crate::state_machine::TransitionError>;
 
-    fn handle(&mut self, msg: CreateThreedsData, _: &mut Self::Context) -> Self::Result {
-        let new_data = models::NewThreedsData {
-            payment_id: &msg.payment_id,
-            one_time_3ds_token: &msg.one_time_3ds_token,
-            redirect_url: &msg.redirect_url,
-            order_id: &msg.order_id
-        };
+    fn handle(&mut self, msg: AuthorisePayment, _: &mut Self::Context) -> Self::Result {
+        use schema::payments::dsl::*;
 
-        diesel::insert_into(schema::threeds_datas::table)
-            .values(&new_data)
-            .get_result(&self.0)
+        self.0.transaction(|| {
+            let previous = payments.find(msg.id)
+                .for_update()
+                .first::<models::Payment>(&self.0)?;
+            let payment = crate::state_machine::transition(&self.0, &previous, models::PaymentState::AUTHORISED, &msg.actor)?;
+            let payment = diesel::update(&payment)
+                .set((
+                    payment_method.eq(&msg.payment_method),
+                    order_code.eq(&msg.order_code),
+                    gateway.eq(&msg.gateway),
+                    authorised_until.eq(msg.authorised_until),
+                ))
+                .get_result::<models::Payment>(&self.0)?;
+            record_state_change(&self.0, previous.state, &payment)?;
+
+            Ok(())
+        })
     }
 }
 
-pub struct GetThreedsData {
-    payment: models::Payment,
+#[derive(Debug, Clone)]
+pub struct CapturePayment {
+    id: Uuid,
+    amount: i64,
+    actor: crate::audit::Actor,
 }
 
-impl GetThreedsData {
-    pub fn new(payment: &models::Payment) -> Self {
+impl CapturePayment {
+    pub fn new(id: &Uuid, amount: i64, actor: &crate::audit::Actor) -> Self {
         Self {
-            payment: payment.to_owned()
+            id: id.to_owned(),
+            amount,
+            actor: actor.to_owned(),
         }
     }
 }
 
-impl Message for GetThreedsData {
-    type Result = Result<models::ThreedsData, diesel::result::Error>;
+impl Message for CapturePayment {
+    type Result = Result<(), crate::state_machine::TransitionError>;
 }
 
-impl Handler<GetThreedsData> for DbExecutor {
-    type Result = Result<models::ThreedsData, diesel::result::Error>;
+impl Handler<CapturePayment> for DbExecutor {
+    type Result = Result<(), crate::state_machine::TransitionError>;
 
-    fn handle(&mut self, msg: GetThreedsData, _: &mut Self::Context) -> Self::Result {
-        models::ThreedsData::belonging_to(&msg.payment)
-            .order_by(schema::threeds_datas::timestamp.desc())
-            .first::<models::ThreedsData>(&self.0)
+    fn handle(&mut self, msg: CapturePayment, _: &mut Self::Context) -> Self::Result {
+        use schema::payments::dsl::*;
+
+        self.0.transaction(|| {
+            let previous = payments.find(msg.id)
+                .for_update()
+                .first::<models::Payment>(&self.0)?;
+            if previous.state != models::PaymentState::AUTHORISED {
+                return Err(crate::state_machine::TransitionError::Invalid(previous.state, models::PaymentState::PAID));
+            }
+            let payment = crate::state_machine::transition(&self.0, &previous, models::PaymentState::PAID, &msg.actor)?;
+            let payment = diesel::update(&payment)
+                .set((
+                    captured_amount.eq(msg.amount),
+                    authorised_until.eq(None::<NaiveDateTime>),
+                ))
+                .get_result::<models::Payment>(&self.0)?;
+            record_state_change(&self.0, previous.state, &payment)?;
+            crate::audit::record(
+                &self.0, &payment.id, &msg.actor, crate::audit::CAPTURED,
+                Some(&serde_json::json!({"captured_amount": previous.captured_amount})),
+                Some(&serde_json::json!({"captured_amount": payment.captured_amount})),
+            )?;
+
+            Ok(())
+        })
     }
 }
 
-pub struct DeleteThreedsData {
-    payment: models::Payment,
+pub struct GetExpiredAuthorisations {
+    now: NaiveDateTime,
 }
 
-impl DeleteThreedsData {
-    pub fn new(payment: &models::Payment) -> Self {
+impl GetExpiredAuthorisations {
+    pub fn new(now: &NaiveDateTime) -> Self {
         Self {
-            payment: payment.to_owned()
+            now: now.to_owned()
         }
     }
 }
 
-impl Message for DeleteThreedsData {
-    type Result = Result<(), diesel::result::Error>;
+impl Message for GetExpiredAuthorisations {
+    type Result = Result<Vec<models::Payment>, diesel::result::Error>;
 }
 
-impl Handler<DeleteThreedsData> for DbExecutor {
-    type Result = Result<(), diesel::result::Error>;
+impl Handler<GetExpiredAuthorisations> for DbExecutor {
+    type Result = Result<Vec<models::Payment>, diesel::result::Error>;
 
-    fn handle(&mut self, msg: DeleteThreedsData, _: &mut Self::Context) -> Self::Result {
-        diesel::delete(models::ThreedsData::belonging_to(&msg.payment))
-            .execute(&self.0)?;
+    fn handle(&mut self, msg: GetExpiredAuthorisations, _: &mut Self::Context) -> Self::Result {
+        use schema::payments::dsl::*;
 
-        Ok(())
+        payments.filter(state.eq(models::PaymentState::AUTHORISED))
+            .filter(authorised_until.lt(msg.now))
+            .load::<models::Payment>(&self.0)
+    }
+}
+
+pub struct GetExpiredPayments {
+    now: NaiveDateTime,
+}
+
+impl GetExpiredPayments {
+    pub fn new(now: &NaiveDateTime) -> Self {
+        Self {
+            now: now.to_owned()
+        }
+    }
+}
+
+impl Message for GetExpiredPayments {
+    type Result = Result<Vec<models::Payment>, diesel::result::Error>;
+}
+
+impl Handler<GetExpiredPayments> for DbExecutor {
+    type Result = Result<Vec<models::Payment>, diesel::result::Error>;
+
+    fn handle(&mut self, msg: GetExpiredPayments, _: &mut Self::Context) -> Self::Result {
+        use schema::payments::dsl::*;
+
+        payments.filter(state.eq(models::PaymentState::OPEN))
+            .filter(expires_at.lt(msg.now))
+            .load::<models::Payment>(&self.0)
     }
 }
 
 #[derive(Debug, Clone)]
-pub struct CreateCard {
+pub struct ExpirePayment {
     id: Uuid,
-    customer_id: Uuid,
-    pan: String,
-    exp_month: u32,
-    exp_year: u32,
-    name_on_card: String,
+    now: NaiveDateTime,
+    actor: crate::audit::Actor,
 }
 
-impl CreateCard {
-    pub fn new(id: &Uuid, customer_id: &Uuid, pan: &str, exp_month: u32, exp_year: u32, name_on_card: &str) -> Self {
+impl ExpirePayment {
+    pub fn new(id: &Uuid, now: &NaiveDateTime, actor: &crate::audit::Actor) -> Self {
         Self {
             id: id.to_owned(),
-            customer_id: customer_id.to_owned(),
-            pan: pan.to_string(),
-            exp_month,
-            exp_year,
-            name_on_card: name_on_card.to_string()
+            now: now.to_owned(),
+            actor: actor.to_owned(),
         }
     }
 }
 
-impl Message for CreateCard {
-    type Result = Result<models::Card, diesel::result::Error>;
+impl Message for ExpirePayment {
+    type Result = Result<Option<models::Payment>, crate::state_machine::TransitionError>;
 }
 
-impl Handler<CreateCard> for DbExecutor {
-    type Result = Result<models::Card, diesel::result::Error>;
+impl Handler<ExpirePayment> for DbExecutor {
+    type Result = Result<Option<models::Payment>, crate::state_machine::TransitionError>;
 
-    fn handle(&mut self, msg: CreateCard, _: &mut Self::Context) -> Self::Result {
-        use schema::cards::dsl::*;
-        let existing_card = cards.filter(pan.eq(&msg.pan))
-            .first::<models::Card>(&self.0);
+    fn handle(&mut self, msg: ExpirePayment, _: &mut Self::Context) -> Self::Result {
+        use schema::payments::dsl::*;
 
-        match existing_card {
-            Ok(card) => Ok(card),
-            Err(diesel::result::Error::NotFound) => {
-                let new_data = models::NewCard {
-                    id: &msg.id,
-                    customer_id: &msg.customer_id,
-                    pan: &msg.pan,
-                    exp_month: msg.exp_month as i32,
-                    exp_year: msg.exp_year as i32,
-                    name_on_card: &msg.name_on_card
-                };
+        let conn = &self.0;
+        conn.transaction(|| {
+            let payment = payments.find(msg.id)
+                .for_update()
+                .first::<models::Payment>(conn)?;
+            if payment.state != models::PaymentState::OPEN || !payment.is_expired(&msg.now) {
+                return Ok(None);
+            }
 
-                diesel::insert_into(schema::cards::table)
-                    .values(&new_data)
-                    .get_result(&self.0)
+            let tenders = models::PaymentTender::belonging_to(&payment)
+                .load::<models::PaymentTender>(conn)?;
+            if crate::tenders::paid(&tenders) > 0 {
+                warn!("Not expiring payment {} as it has been partially paid", payment.id);
+                return Ok(None);
             }
-            Err(e) => Err(e),
+
+            let updated = crate::state_machine::transition(conn, &payment, models::PaymentState::EXPIRED, &msg.actor)?;
+            record_state_change(conn, payment.state, &updated)?;
+
+            Ok(Some(updated))
+        })
+    }
+}
+
+pub struct CreatePaymentLink {
+    payment_id: Uuid,
+    expires_at: NaiveDateTime,
+    actor: crate::audit::Actor,
+}
+
+impl CreatePaymentLink {
+    pub fn new(payment_id: &Uuid, expires_at: &NaiveDateTime, actor: &crate::audit::Actor) -> Self {
+        Self {
+            payment_id: payment_id.to_owned(),
+            expires_at: expires_at.to_owned(),
+            actor: actor.to_owned(),
         }
     }
 }
 
+impl Message for CreatePaymentLink {
+    type Result = Result<models::PaymentLink, diesel::result::Error>;
+}
+
+impl Handler<CreatePaymentLink> for DbExecutor {
+    type Result = Result<models::PaymentLink, diesel::result::Error>;
 
-pub struct GetPaymentTokens {
+    fn handle(&mut self, msg: CreatePaymentLink, _: &mut Self::Context) -> Self::Result {
+        self.0.transaction(|| {
+            let link = diesel::insert_into(schema::payment_links::table)
+                .values(&models::NewPaymentLink {
+                    id: &Uuid::new_v4(),
+                    payment_id: &msg.payment_id,
+                    expires_at: &msg.expires_at,
+                })
+                .get_result::<models::PaymentLink>(&self.0)?;
+            crate::audit::record(&self.0, &msg.payment_id, &msg.actor, crate::audit::LINK_CREATED, None, Some(&serde_json::json!({
+                "link_id": link.id,
+                "expires_at": link.expires_at,
+            })))?;
+
+            Ok(link)
+        })
+    }
 }
 
-impl GetPaymentTokens {
-    pub fn new() -> Self {
-        Self {}
+pub struct GetPaymentLink {
+    id: Uuid,
+}
+
+impl GetPaymentLink {
+    pub fn new(id: &Uuid) -> Self {
+        Self {
+            id: id.to_owned()
+        }
     }
 }
 
-impl Message for GetPaymentTokens {
-    type Result = Result<Vec<models::PaymentToken>, diesel::result::Error>;
+impl Message for GetPaymentLink {
+    type Result = Result<models::PaymentLink, diesel::result::Error>;
 }
 
-impl Handler<GetPaymentTokens> for DbExecutor {
-    type Result = Result<Vec<models::PaymentToken>, diesel::result::Error>;
+impl Handler<GetPaymentLink> for DbExecutor {
+    type Result = Result<models::PaymentLink, diesel::result::Error>;
 
-    fn handle(&mut self, _msg: GetPaymentTokens, _: &mut Self::Context) -> Self::Result {
-        schema::payment_tokens::table.load::<models::PaymentToken>(&self.0)
+    fn handle(&mut self, msg: GetPaymentLink, _: &mut Self::Context) -> Self::Result {
+        schema::payment_links::table.find(msg.id)
+            .first::<models::PaymentLink>(&self.0)
+    }
+}
+
+pub struct UsePaymentLink {
+    id: Uuid,
+    now: NaiveDateTime,
+    actor: crate::audit::Actor,
+}
+
+impl UsePaymentLink {
+    pub fn new(id: &Uuid, now: &NaiveDateTime, actor: &crate::audit::Actor) -> Self {
+        Self {
+            id: id.to_owned(),
+            now: now.to_owned(),
+            actor: actor.to_owned(),
+        }
+    }
+}
+
+impl Message for UsePaymentLink {
+    type Result = Result<bool, diesel::result::Error>;
+}
+
+impl Handler<UsePaymentLink> for DbExecutor {
+    type Result = Result<bool, diesel::result::Error>;
+
+    fn handle(&mut self, msg: UsePaymentLink, _: &mut Self::Context) -> Self::Result {
+        use schema::payment_links::dsl::*;
+
+        self.0.transaction(|| {
+            let link = diesel::update(payment_links.find(msg.id))
+                .filter(used_at.is_null())
+                .filter(expires_at.gt(msg.now))
+                .set(used_at.eq(msg.now))
+                .get_result::<models::PaymentLink>(&self.0)
+                .optional()?;
+
+            match link {
+                Some(link) => {
+                    crate::audit::record(&self.0, &link.payment_id, &msg.actor, crate::audit::LINK_USED, None, Some(&serde_json::json!({
+                        "link_id": link.id,
+                    })))?;
+                    Ok(true)
+                }
+                None => Ok(false)
+            }
+        })
+    }
+}
+
+pub struct GetRefunds {
+    payment: models::Payment,
+}
+
+impl GetRefunds {
+    pub fn new(payment: &models::Payment) -> Self {
+        Self {
+            payment: payment.to_owned()
+        }
+    }
+}
+
+impl Message for GetRefunds {
+    type Result = Result<Vec<models::Refund>, diesel::result::Error>;
+}
+
+impl Handler<GetRefunds> for DbExecutor {
+    type Result = Result<Vec<models::Refund>, diesel::result::Error>;
+
+    fn handle(&mut self, msg: GetRefunds, _: &mut Self::Context) -> Self::Result {
+        models::Refund::belonging_to(&msg.payment)
+            .filter(schema::refunds::state.ne(models::RefundState::FAILED))
+            .order_by(schema::refunds::time.asc())
+            .load::<models::Refund>(&self.0)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CreateRefund {
+    id: Uuid,
+    time: NaiveDateTime,
+    payment_item_id: Option<Uuid>,
+    quantity: Option<i32>,
+    amount: i64,
+    order_code: String,
+}
+
+impl CreateRefund {
+    pub fn new(id: &Uuid, time: &NaiveDateTime, payment_item_id: Option<&Uuid>, quantity: Option<i32>, amount: i64, order_code: &str) -> Self {
+        Self {
+            id: id.to_owned(),
+            time: time.to_owned(),
+            payment_item_id: payment_item_id.map(|i| i.to_owned()),
+            quantity,
+            amount,
+            order_code: order_code.to_owned(),
+        }
+    }
+
+    pub fn amount(&self) -> i64 {
+        self.amount
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ReserveRefunds {
+    payment_id: Uuid,
+    refunded: i64,
+    refunds: Vec<CreateRefund>,
+}
+
+impl ReserveRefunds {
+    pub fn new(payment_id: &Uuid, refunded: i64, refunds: &[CreateRefund]) -> Self {
+        Self {
+            payment_id: payment_id.to_owned(),
+            refunded,
+            refunds: refunds.to_vec(),
+        }
+    }
+}
+
+impl Message for ReserveRefunds {
+    type Result = Result<Vec<models::Refund>, crate::state_machine::TransitionError>;
+}
+
+impl Handler<ReserveRefunds> for DbExecutor {
+    type Result = Result<Vec<models::Refund>, crate::state_machine::TransitionError>;
+
+    fn handle(&mut self, msg: ReserveRefunds, _: &mut Self::Context) -> Self::Result {
+        let conn = &self.0;
+        conn.transaction(|| {
+            let payment = schema::payments::table.find(msg.payment_id)
+                .for_update()
+                .first::<models::Payment>(conn)?;
+            match payment.state {
+                models::PaymentState::PAID | models::PaymentState::COMPLETE | models::PaymentState::PARTIALLY_REFUNDED => {}
+                s => return Err(crate::state_machine::TransitionError::Invalid(s, models::PaymentState::REFUNDED))
+            }
+
+            let refunded: i64 = models::Refund::belonging_to(&payment)
+                .filter(schema::refunds::state.ne(models::RefundState::FAILED))
+                .load::<models::Refund>(conn)?
+                .iter()
+                .map(|r| r.amount)
+                .sum();
+            if refunded != msg.refunded {
+                return Err(crate::state_machine::TransitionError::Conflict(payment.state));
+            }
+
+            let mut refunds = vec![];
+            for refund in msg.refunds.iter() {
+                refunds.push(diesel::insert_into(schema::refunds::table)
+                    .values(&models::NewRefund {
+                        id: &refund.id,
+                        payment_id: &msg.payment_id,
+                        time: &refund.time,
+                        payment_item_id: refund.payment_item_id.as_ref(),
+                        quantity: refund.quantity,
+                        amount: refund.amount,
+                        order_code: &refund.order_code,
+                        state: models::RefundState::PENDING,
+                    })
+                    .get_result(conn)?);
+            }
+
+            Ok(refunds)
+        })
+    }
+}
+
+pub struct FailRefunds {
+    ids: Vec<Uuid>,
+}
+
+impl FailRefunds {
+    pub fn new(ids: &[Uuid]) -> Self {
+        Self {
+            ids: ids.to_vec()
+        }
+    }
+}
+
+impl Message for FailRefunds {
+    type Result = Result<(), diesel::result::Error>;
+}
+
+impl Handler<FailRefunds> for DbExecutor {
+    type Result = Result<(), diesel::result::Error>;
+
+    fn handle(&mut self, msg: FailRefunds, _: &mut Self::Context) -> Self::Result {
+        use schema::refunds::dsl::*;
+
+        diesel::update(refunds.filter(id.eq_any(&msg.ids)).filter(state.eq(models::RefundState::PENDING)))
+            .set(state.eq(models::RefundState::FAILED))
+            .execute(&self.0)?;
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RefundPayment {
+    payment_id: Uuid,
+    refund_ids: Vec<Uuid>,
+    to_credit: i64,
+    actor: crate::audit::Actor,
+}
+
+impl RefundPayment {
+    pub fn new(payment_id: &Uuid, refund_ids: &[Uuid], to_credit: i64, actor: &crate::audit::Actor) -> Self {
+        Self {
+            payment_id: payment_id.to_owned(),
+            refund_ids: refund_ids.to_vec(),
+            to_credit,
+            actor: actor.to_owned(),
+        }
+    }
+}
+
+impl Message for RefundPayment {
+    type Result = Result<(models::Payment, Vec<models::Refund>), crate::state_machine::TransitionError>;
+}
+
+impl Handler<RefundPayment> for DbExecutor {
+    type Result = Result<(models::Payment, Vec<models::Refund>), crate::state_machine::TransitionError>;
+
+    fn handle(&mut self, msg: RefundPayment, _: &mut Self::Context) -> Self::Result {
+        let conn = &self.0;
+        conn.transaction(|| {
+            let previous = schema::payments::table.find(msg.payment_id)
+                .for_update()
+                .first::<models::Payment>(conn)?;
+
+            let refunds = diesel::update(
+                schema::refunds::table.filter(schema::refunds::id.eq_any(&msg.refund_ids))
+                    .filter(schema::refunds::state.eq(models::RefundState::PENDING))
+            )
+                .set(schema::refunds::state.eq(models::RefundState::SUCCEEDED))
+                .get_results::<models::Refund>(conn)?;
+
+            let total = match previous.captured_amount {
+                Some(a) => a + previous.store_credit_amount,
+                None => crate::tenders::total(&models::PaymentItem::belonging_to(&previous).load::<models::PaymentItem>(conn)?)
+            };
+            let refunded: i64 = models::Refund::belonging_to(&previous)
+                .filter(schema::refunds::state.eq(models::RefundState::SUCCEEDED))
+                .load::<models::Refund>(conn)?
+                .iter()
+                .map(|r| r.amount)
+                .sum();
+            let state = if refunded >= total {
+                models::PaymentState::REFUNDED
+            } else {
+                models::PaymentState::PARTIALLY_REFUNDED
+            };
+
+            let payment = crate::state_machine::transition(conn, &previous, state, &msg.actor)?;
+            if msg.to_credit > 0 {
+                crate::store_credit::record(
+                    conn, &payment.customer_id, &payment.currency, msg.to_credit,
+                    models::StoreCreditEntryType::REFUND, Some(&payment.id), None, None,
+                )?;
+            }
+            if previous.state == payment.state {
+                record_payment_event(conn, &payment, crate::events::PaymentEvent::Refunded)?;
+                crate::webhooks::record_event(conn, &payment, crate::webhooks::EventType::PaymentRefunded)?;
+            } else {
+                record_state_change(conn, previous.state, &payment)?;
+            }
+            crate::audit::record(conn, &payment.id, &msg.actor, crate::audit::REFUNDED, None, Some(&serde_json::json!({
+                "refunds": refunds.iter().map(|r: &models::Refund| serde_json::json!({
+                    "id": r.id,
+                    "payment_item_id": r.payment_item_id,
+                    "quantity": r.quantity,
+                    "amount": r.amount,
+                    "order_code": r.order_code,
+                })).collect::<Vec<_>>(),
+            })))?;
+
+            Ok((payment, refunds))
+        })
+    }
+}
+
+pub struct GetPaymentTenders {
+    payment: models::Payment,
+}
+
+impl GetPaymentTenders {
+    pub fn new(payment: &models::Payment) -> Self {
+        Self {
+            payment: payment.to_owned()
+        }
+    }
+}
+
+impl Message for GetPaymentTenders {
+    type Result = Result<Vec<models::PaymentTender>, diesel::result::Error>;
+}
+
+impl Handler<GetPaymentTenders> for DbExecutor {
+    type Result = Result<Vec<models::PaymentTender>, diesel::result::Error>;
+
+    fn handle(&mut self, msg: GetPaymentTenders, _: &mut Self::Context) -> Self::Result {
+        models::PaymentTender::belonging_to(&msg.payment)
+            .order_by(schema::payment_tenders::created.asc())
+            .load::<models::PaymentTender>(&self.0)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CreatePendingTender {
+    payment_id: Uuid,
+    gateway: String,
+    order_code: String,
+    amount: i64,
+}
+
+impl CreatePendingTender {
+    pub fn new(payment_id: &Uuid, gateway: &str, order_code: &str, amount: i64) -> Self {
+        Self {
+            payment_id: payment_id.to_owned(),
+            gateway: gateway.to_owned(),
+            order_code: order_code.to_owned(),
+            amount,
+        }
+    }
+}
+
+impl Message for CreatePendingTender {
+    type Result = Result<(), diesel::result::Error>;
+}
+
+impl Handler<CreatePendingTender> for DbExecutor {
+    type Result = Result<(), diesel::result::Error>;
+
+    fn handle(&mut self, msg: CreatePendingTender, _: &mut Self::Context) -> Self::Result {
+        let new_tender = models::NewPaymentTender {
+            id: &Uuid::new_v4(),
+            payment_id: &msg.payment_id,
+            gateway: &msg.gateway,
+            order_code: &msg.order_code,
+            payment_method: None,
+            amount: msg.amount,
+            state: models::PaymentTenderState::PENDING,
+        };
+
+        diesel::insert_into(schema::payment_tenders::table)
+            .values(&new_tender)
+            .on_conflict_do_nothing()
+            .execute(&self.0)?;
+
+        Ok(())
+    }
+}
+
+pub struct FailTender {
+    payment_id: Uuid,
+    gateway: String,
+    order_code: String,
+}
+
+impl FailTender {
+    pub fn new(payment_id: &Uuid, gateway: &str, order_code: &str) -> Self {
+        Self {
+            payment_id: payment_id.to_owned(),
+            gateway: gateway.to_owned(),
+            order_code: order_code.to_owned(),
+        }
+    }
+}
+
+impl Message for FailTender {
+    type Result = Result<(), diesel::result::Error>;
+}
+
+impl Handler<FailTender> for DbExecutor {
+    type Result = Result<(), diesel::result::Error>;
+
+    fn handle(&mut self, msg: FailTender, _: &mut Self::Context) -> Self::Result {
+        use schema::payment_tenders::dsl::*;
+
+        diesel::update(
+            payment_tenders.filter(payment_id.eq(msg.payment_id))
+                .filter(gateway.eq(&msg.gateway))
+                .filter(order_code.eq(&msg.order_code))
+                .filter(state.eq(models::PaymentTenderState::PENDING))
+        )
+            .set(state.eq(models::PaymentTenderState::FAILED))
+            .execute(&self.0)?;
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CompleteTender {
+    payment_id: Uuid,
+    gateway: String,
+    order_code: String,
+    payment_method: Option<String>,
+    amount: Option<i64>,
+    actor: crate::audit::Actor,
+}
+
+impl CompleteTender {
+    pub fn new(payment_id: &Uuid, gateway: &str, order_code: &str, payment_method: Option<&str>, amount: Option<i64>, actor: &crate::audit::Actor) -> Self {
+        Self {
+            payment_id: payment_id.to_owned(),
+            gateway: gateway.to_owned(),
+            order_code: order_code.to_owned(),
+            payment_method: payment_method.map(|s| s.to_owned()),
+            amount,
+            actor: actor.to_owned(),
+        }
+    }
+}
+
+impl Message for CompleteTender {
+    type Result = Result<models::Payment, crate::tenders::TenderError>;
+}
+
+impl Handler<CompleteTender> for DbExecutor {
+    type Result = Result<models::Payment, crate::tenders::TenderError>;
+
+    fn handle(&mut self, msg: CompleteTender, _: &mut Self::Context) -> Self::Result {
+        use schema::payment_tenders::dsl::*;
+
+        let conn = &self.0;
+        conn.transaction(|| {
+            let payment = schema::payments::table.find(msg.payment_id)
+                .for_update()
+                .first::<models::Payment>(conn)?;
+
+            let existing = payment_tenders.filter(gateway.eq(&msg.gateway))
+                .filter(order_code.eq(&msg.order_code))
+                .first::<models::PaymentTender>(conn)
+                .optional()?;
+            if let Some(tender) = &existing {
+                if tender.state == models::PaymentTenderState::SUCCEEDED {
+                    return Ok(payment);
+                }
+            }
+            if payment.state != models::PaymentState::OPEN {
+                return Err(crate::tenders::TenderError::PaymentNotOpen(payment.state));
+            }
+
+            match existing {
+                Some(tender) => {
+                    diesel::update(&tender)
+                        .set((
+                            state.eq(models::PaymentTenderState::SUCCEEDED),
+                            payment_method.eq(&msg.payment_method),
+                            amount.eq(msg.amount.unwrap_or(tender.amount)),
+                        ))
+                        .execute(conn)?;
+                    crate::audit::record(conn, &payment.id, &msg.actor, crate::audit::TENDER_COMPLETED, None, Some(&serde_json::json!({
+                        "gateway": msg.gateway,
+                        "order_code": msg.order_code,
+                        "payment_method": msg.payment_method,
+                        "amount": msg.amount.unwrap_or(tender.amount),
+                    })))?;
+                }
+                None => {
+                    let tender_amount = match msg.amount {
+                        Some(a) => a,
+                        None => return Err(crate::tenders::TenderError::UnknownTender)
+                    };
+                    diesel::insert_into(payment_tenders)
+                        .values(&models::NewPaymentTender {
+                            id: &Uuid::new_v4(),
+                            payment_id: &payment.id,
+                            gateway: &msg.gateway,
+                            order_code: &msg.order_code,
+                            payment_method: msg.payment_method.as_deref(),
+                            amount: tender_amount,
+                            state: models::PaymentTenderState::SUCCEEDED,
+                        })
+                        .execute(conn)?;
+                    crate::audit::record(conn, &payment.id, &msg.actor, crate::audit::TENDER_COMPLETED, None, Some(&serde_json::json!({
+                        "gateway": msg.gateway,
+                        "order_code": msg.order_code,
+                        "payment_method": msg.payment_method,
+                        "amount": tender_amount,
+                    })))?;
+                }
+            }
+
+            let items = models::PaymentItem::belonging_to(&payment)
+                .load::<models::PaymentItem>(conn)?;
+            let tenders = models::PaymentTender::belonging_to(&payment)
+                .order_by(created.asc())
+                .load::<models::PaymentTender>(conn)?;
+            if crate::tenders::outstanding(&payment, &items, &tenders) > 0 {
+                return Ok(payment);
+            }
+
+            let updated = crate::state_machine::transition(conn, &payment, models::PaymentState::PAID, &msg.actor)?;
+            let updated = diesel::update(&updated)
+                .set(&models::PaymentStateChangeset {
+                    state: models::PaymentState::PAID,
+                    payment_method: crate::tenders::payment_method(&payment, &tenders).as_deref(),
+                    order_code: Some(&msg.order_code),
+                    gateway: Some(&msg.gateway),
+                })
+                .get_result::<models::Payment>(conn)?;
+            record_state_change(conn, payment.state, &updated)?;
+
+            Ok(updated)
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CreateThreedsData {
+    payment_id: Uuid,
+    one_time_3ds_token: String,
+    redirect_url: String,
+    order_id: String,
+}
+
+impl CreateThreedsData {
+    pub fn new(payment_id: &Uuid, one_time_3ds_token: &str, redirect_url: &str, order_id: &str) -> Self {
+        Self {
+            payment_id: payment_id.to_owned(),
+            one_time_3ds_token: one_time_3ds_token.to_owned(),
+            redirect_url: redirect_url.to_owned(),
+            order_id: order_id.to_owned()
+        }
+    }
+}
+
+impl Message for CreateThreedsData {
+    type Result = Result<models::ThreedsData, diesel::result::Error>;
+}
+
+impl Handler<CreateThreedsData> for DbExecutor {
+    type Result = Result<models::ThreedsData, diesel::result::Error>;
+
+    fn handle(&mut self, msg: CreateThreedsData, _: &mut Self::Context) -> Self::Result {
+        let new_data = models::NewThreedsData {
+            payment_id: &msg.payment_id,
+            one_time_3ds_token: &msg.one_time_3ds_token,
+            redirect_url: &msg.redirect_url,
+            order_id: &msg.order_id
+        };
+
+        diesel::insert_into(schema::threeds_datas::table)
+            .values(&new_data)
+            .get_result(&self.0)
+    }
+}
+
+pub struct GetThreedsData {
+    payment: models::Payment,
+}
+
+impl GetThreedsData {
+    pub fn new(payment: &models::Payment) -> Self {
+        Self {
+            payment: payment.to_owned()
+        }
+    }
+}
+
+impl Message for GetThreedsData {
+    type Result = Result<models::ThreedsData, diesel::result::Error>;
+}
+
+impl Handler<GetThreedsData> for DbExecutor {
+    type Result = Result<models::ThreedsData, diesel::result::Error>;
+
+    fn handle(&mut self, msg: GetThreedsData, _: &mut Self::Context) -> Self::Result {
+        models::ThreedsData::belonging_to(&msg.payment)
+            .order_by(schema::threeds_datas::timestamp.desc())
+            .first::<models::ThreedsData>(&self.0)
+    }
+}
+
+pub struct DeleteThreedsData {
+    payment: models::Payment,
+}
+
+impl DeleteThreedsData {
+    pub fn new(payment: &models::Payment) -> Self {
+        Self {
+            payment: payment.to_owned()
+        }
+    }
+}
+
+impl Message for DeleteThreedsData {
+    type Result = Result<(), diesel::result::Error>;
+}
+
+impl Handler<DeleteThreedsData> for DbExecutor {
+    type Result = Result<(), diesel::result::Error>;
+
+    fn handle(&mut self, msg: DeleteThreedsData, _: &mut Self::Context) -> Self::Result {
+        diesel::delete(models::ThreedsData::belonging_to(&msg.payment))
+            .execute(&self.0)?;
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CreatePaymentAttempt {
+    payment_id: Uuid,
+    gateway: String,
+    order_code: Option<String>,
+    payment_status: Option<String>,
+    card_issuer: Option<String>,
+    masked_pan: Option<String>,
+    raw_response: serde_json::Value,
+}
+
+impl CreatePaymentAttempt {
+    pub fn new(payment_id: &Uuid, gateway: &str, order_code: Option<&str>, payment_status: Option<&str>, card_issuer: Option<&str>, masked_pan: Option<&str>, raw_response: &serde_json::Value) -> Self {
+        Self {
+            payment_id: payment_id.to_owned(),
+            gateway: gateway.to_owned(),
+            order_code: order_code.map(|s| s.to_owned()),
+            payment_status: payment_status.map(|s| s.to_owned()),
+            card_issuer: card_issuer.map(|s| s.to_owned()),
+            masked_pan: masked_pan.map(|s| s.to_owned()),
+            raw_response: raw_response.to_owned(),
+        }
+    }
+}
+
+impl Message for CreatePaymentAttempt {
+    type Result = Result<models::PaymentAttempt, diesel::result::Error>;
+}
+
+impl Handler<CreatePaymentAttempt> for DbExecutor {
+    type Result = Result<models::PaymentAttempt, diesel::result::Error>;
+
+    fn handle(&mut self, msg: CreatePaymentAttempt, _: &mut Self::Context) -> Self::Result {
+        let new_data = models::NewPaymentAttempt {
+            payment_id: &msg.payment_id,
+            gateway: &msg.gateway,
+            order_code: msg.order_code.as_deref(),
+            payment_status: msg.payment_status.as_deref(),
+            card_issuer: msg.card_issuer.as_deref(),
+            masked_pan: msg.masked_pan.as_deref(),
+            raw_response: &msg.raw_response,
+        };
+
+        diesel::insert_into(schema::payment_attempts::table)
+            .values(&new_data)
+            .get_result(&self.0)
+    }
+}
+
+pub struct GetPaymentByOrderCode {
+    order_code: String,
+}
+
+impl GetPaymentByOrderCode {
+    pub fn new(order_code: &str) -> Self {
+        Self {
+            order_code: order_code.to_owned()
+        }
+    }
+}
+
+impl Message for GetPaymentByOrderCode {
+    type Result = Result<models::Payment, diesel::result::Error>;
+}
+
+impl Handler<GetPaymentByOrderCode> for DbExecutor {
+    type Result = Result<models::Payment, diesel::result::Error>;
+
+    fn handle(&mut self, msg: GetPaymentByOrderCode, _: &mut Self::Context) -> Self::Result {
+        use schema::payments::dsl::*;
+
+        payments.filter(order_code.eq(&msg.order_code))
+            .first::<models::Payment>(&self.0)
+    }
+}
+
+pub struct RecordGatewayEvent {
+    gateway: String,
+    order_code: String,
+    event_status: String,
+    payment_id: Option<Uuid>,
+    raw_body: serde_json::Value,
+    state_change: Option<(models::PaymentState, models::PaymentState)>,
+    authorised_until: Option<NaiveDateTime>,
+    actor: crate::audit::Actor,
+}
+
+impl RecordGatewayEvent {
+    pub fn new(gateway: &str, order_code: &str, event_status: &str, payment_id: Option<&Uuid>, raw_body: &serde_json::Value, state_change: Option<(models::PaymentState, models::PaymentState)>, authorised_until: Option<&NaiveDateTime>, actor: &crate::audit::Actor) -> Self {
+        Self {
+            gateway: gateway.to_owned(),
+            order_code: order_code.to_owned(),
+            event_status: event_status.to_owned(),
+            payment_id: payment_id.map(|i| i.to_owned()),
+            raw_body: raw_body.to_owned(),
+            state_change,
+            authorised_until: authorised_until.map(|t| t.to_owned()),
+            actor: actor.to_owned(),
+        }
+    }
+}
+
+impl Message for RecordGatewayEvent {
+    type Result = Result<Option<models::PaymentState>, crate::state_machine::TransitionError>;
+}
+
+impl Handler<RecordGatewayEvent> for DbExecutor {
+    type Result = Result<Option<models::PaymentState>, crate::state_machine::TransitionError>;
+
+    fn handle(&mut self, msg: RecordGatewayEvent, _: &mut Self::Context) -> Self::Result {
+        let conn = &self.0;
+
+        // An event that can't be applied yet rolls back with its gateway_events row, so a redelivery can apply it
+        conn.transaction::<_, crate::state_machine::TransitionError, _>(|| {
+            let new_event = models::NewGatewayEvent {
+                gateway: &msg.gateway,
+                order_code: &msg.order_code,
+                event_status: &msg.event_status,
+                payment_id: msg.payment_id.as_ref(),
+                raw_body: &msg.raw_body,
+            };
+
+            let inserted = diesel::insert_into(schema::gateway_events::table)
+                .values(&new_event)
+                .on_conflict_do_nothing()
+                .execute(conn)?;
+            if inserted == 0 {
+                return Ok(None);
+            }
+
+            match (msg.payment_id, msg.state_change) {
+                (Some(payment_id), Some((from_state, to_state))) => {
+                    use schema::payments::dsl::*;
+
+                    let payment = payments.find(payment_id)
+                        .for_update()
+                        .first::<models::Payment>(conn)?;
+                    if payment.state != from_state {
+                        return Err(crate::state_machine::TransitionError::Conflict(from_state));
+                    }
+                    let payment = crate::state_machine::transition(conn, &payment, to_state, &msg.actor)?;
+                    let payment = diesel::update(&payment)
+                        .set((
+                            order_code.eq(&msg.order_code),
+                            gateway.eq(&msg.gateway),
+                            authorised_until.eq(msg.authorised_until),
+                        ))
+                        .get_result::<models::Payment>(conn)?;
+                    record_state_change(conn, from_state, &payment)?;
+
+                    Ok(Some(to_state))
+                }
+                _ => Ok(None)
+            }
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CreateCard {
+    id: Uuid,
+    customer_id: Uuid,
+    token: String,
+    last_four: String,
+    brand: String,
+    exp_month: u32,
+    exp_year: u32,
+    name_on_card: String,
+    environment: models::PaymentEnvironment,
+}
+
+impl CreateCard {
+    pub fn new(id: &Uuid, customer_id: &Uuid, token: &str, last_four: &str, brand: &str, exp_month: u32, exp_year: u32, name_on_card: &str, environment: models::PaymentEnvironment) -> Self {
+        Self {
+            id: id.to_owned(),
+            customer_id: customer_id.to_owned(),
+            token: token.to_string(),
+            last_four: last_four.to_string(),
+            brand: brand.to_string(),
+            exp_month,
+            exp_year,
+            name_on_card: name_on_card.to_string(),
+            environment,
+        }
+    }
+}
+
+impl Message for CreateCard {
+    type Result = Result<(models::Card, Option<String>), diesel::result::Error>;
+}
+
+impl Handler<CreateCard> for DbExecutor {
+    type Result = Result<(models::Card, Option<String>), diesel::result::Error>;
+
+    fn handle(&mut self, msg: CreateCard, _: &mut Self::Context) -> Self::Result {
+        use schema::cards::dsl::*;
+        let existing_card = cards
+            .filter(customer_id.eq(&msg.customer_id))
+            .filter(last_four.eq(&msg.last_four))
+            .filter(brand.eq(&msg.brand))
+            .filter(exp_month.eq(msg.exp_month as i32))
+            .filter(exp_year.eq(msg.exp_year as i32))
+            .filter(environment.eq(msg.environment))
+            .first::<models::Card>(&self.0);
+
+        match existing_card {
+            Ok(card) => Ok((
+                diesel::update(&card)
+                    .set((token.eq(&msg.token), name_on_card.eq(&msg.name_on_card)))
+                    .get_result(&self.0)?,
+                Some(card.token).filter(|t| *t != msg.token)
+            )),
+            Err(diesel::result::Error::NotFound) => {
+                let new_data = models::NewCard {
+                    id: &msg.id,
+                    customer_id: &msg.customer_id,
+                    token: &msg.token,
+                    last_four: &msg.last_four,
+                    brand: &msg.brand,
+                    exp_month: msg.exp_month as i32,
+                    exp_year: msg.exp_year as i32,
+                    name_on_card: &msg.name_on_card,
+                    environment: msg.environment,
+                };
+
+                Ok((diesel::insert_into(schema::cards::table)
+                    .values(&new_data)
+                    .get_result(&self.0)?, None))
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+pub struct GetCard {
+    id: Uuid,
+}
+
+impl GetCard {
+    pub fn new(id: &Uuid) -> Self {
+        Self {
+            id: id.to_owned()
+        }
+    }
+}
+
+impl Message for GetCard {
+    type Result = Result<models::Card, diesel::result::Error>;
+}
+
+impl Handler<GetCard> for DbExecutor {
+    type Result = Result<models::Card, diesel::result::Error>;
+
+    fn handle(&mut self, msg: GetCard, _: &mut Self::Context) -> Self::Result {
+        use schema::cards::dsl::*;
+
+        cards.find(msg.id)
+            .first::<models::Card>(&self.0)
+    }
+}
+
+pub struct GetCards {
+    customer_id: Uuid,
+}
+
+impl GetCards {
+    pub fn new(customer_id: &Uuid) -> Self {
+        Self {
+            customer_id: customer_id.to_owned()
+        }
+    }
+}
+
+impl Message for GetCards {
+    type Result = Result<Vec<models::Card>, diesel::result::Error>;
+}
+
+impl Handler<GetCards> for DbExecutor {
+    type Result = Result<Vec<models::Card>, diesel::result::Error>;
+
+    fn handle(&mut self, msg: GetCards, _: &mut Self::Context) -> Self::Result {
+        use schema::cards::dsl::*;
+
+        cards.filter(customer_id.eq(msg.customer_id))
+            .order_by((exp_year.desc(), exp_month.desc()))
+            .load::<models::Card>(&self.0)
+    }
+}
+
+pub struct DeleteCard {
+    card: models::Card,
+}
+
+impl DeleteCard {
+    pub fn new(card: &models::Card) -> Self {
+        Self {
+            card: card.to_owned()
+        }
+    }
+}
+
+impl Message for DeleteCard {
+    type Result = Result<(), diesel::result::Error>;
+}
+
+impl Handler<DeleteCard> for DbExecutor {
+    type Result = Result<(), diesel::result::Error>;
+
+    fn handle(&mut self, msg: DeleteCard, _: &mut Self::Context) -> Self::Result {
+        diesel::delete(&msg.card)
+            .execute(&self.0)?;
+
+        Ok(())
+    }
+}
+
+
+pub struct GetDueWebhookDeliveries {
+    now: NaiveDateTime,
+    max_attempts: i32,
+}
+
+impl GetDueWebhookDeliveries {
+    pub fn new(now: &NaiveDateTime, max_attempts: i32) -> Self {
+        Self {
+            now: now.to_owned(),
+            max_attempts,
+        }
+    }
+}
+
+impl Message for GetDueWebhookDeliveries {
+    type Result = Result<Vec<models::WebhookDelivery>, diesel::result::Error>;
+}
+
+impl Handler<GetDueWebhookDeliveries> for DbExecutor {
+    type Result = Result<Vec<models::WebhookDelivery>, diesel::result::Error>;
+
+    fn handle(&mut self, msg: GetDueWebhookDeliveries, _: &mut Self::Context) -> Self::Result {
+        use schema::webhook_deliveries::dsl::*;
+
+        webhook_deliveries.filter(delivered.is_null())
+            .filter(next_attempt.le(msg.now))
+            .filter(attempts.lt(msg.max_attempts))
+            .order_by(next_attempt.asc())
+            .load::<models::WebhookDelivery>(&self.0)
+    }
+}
+
+pub struct RecordWebhookDeliveryAttempt {
+    delivery: models::WebhookDelivery,
+    timestamp: NaiveDateTime,
+    status_code: Option<i32>,
+    error: Option<String>,
+    next_attempt: NaiveDateTime,
+}
+
+impl RecordWebhookDeliveryAttempt {
+    pub fn new(delivery: &models::WebhookDelivery, timestamp: &NaiveDateTime, status_code: Option<i32>, error: Option<&str>, next_attempt: &NaiveDateTime) -> Self {
+        Self {
+            delivery: delivery.to_owned(),
+            timestamp: timestamp.to_owned(),
+            status_code,
+            error: error.map(|s| s.to_owned()),
+            next_attempt: next_attempt.to_owned(),
+        }
+    }
+}
+
+impl Message for RecordWebhookDeliveryAttempt {
+    type Result = Result<(), diesel::result::Error>;
+}
+
+impl Handler<RecordWebhookDeliveryAttempt> for DbExecutor {
+    type Result = Result<(), diesel::result::Error>;
+
+    fn handle(&mut self, msg: RecordWebhookDeliveryAttempt, _: &mut Self::Context) -> Self::Result {
+        let conn = &self.0;
+
+        conn.transaction::<_, diesel::result::Error, _>(|| {
+            let new_attempt = models::NewWebhookDeliveryAttempt {
+                delivery_id: &msg.delivery.id,
+                timestamp: &msg.timestamp,
+                status_code: msg.status_code,
+                error: msg.error.as_deref(),
+            };
+
+            diesel::insert_into(schema::webhook_delivery_attempts::table)
+                .values(&new_attempt)
+                .execute(conn)?;
+
+            use schema::webhook_deliveries::dsl::*;
+
+            let delivery_time = match msg.error {
+                Some(_) => None,
+                None => Some(msg.timestamp)
+            };
+            diesel::update(webhook_deliveries.find(msg.delivery.id))
+                .set((
+                    attempts.eq(attempts + 1),
+                    next_attempt.eq(msg.next_attempt),
+                    delivered.eq(delivery_time),
+                ))
+                .execute(conn)?;
+
+            Ok(())
+        })
+    }
+}
+
+pub struct CreatePaymentEvent {
+    payment_id: Uuid,
+    event: crate::events::PaymentEvent,
+}
+
+impl CreatePaymentEvent {
+    pub fn new(payment_id: &Uuid, event: crate::events::PaymentEvent) -> Self {
+        Self {
+            payment_id: payment_id.to_owned(),
+            event,
+        }
+    }
+}
+
+impl Message for CreatePaymentEvent {
+    type Result = Result<(), diesel::result::Error>;
+}
+
+impl Handler<CreatePaymentEvent> for DbExecutor {
+    type Result = Result<(), diesel::result::Error>;
+
+    fn handle(&mut self, msg: CreatePaymentEvent, _: &mut Self::Context) -> Self::Result {
+        let conn = &self.0;
+        conn.transaction(|| {
+            let payment = schema::payments::table.find(msg.payment_id)
+                .first::<models::Payment>(conn)?;
+
+            record_payment_event(conn, &payment, msg.event)?;
+            if msg.event == crate::events::PaymentEvent::Failed {
+                crate::webhooks::record_event(conn, &payment, crate::webhooks::EventType::PaymentFailed)?;
+            }
+
+            Ok(())
+        })
+    }
+}
+
+pub struct GetPendingOutboxEvents {
+    limit: i64,
+}
+
+impl GetPendingOutboxEvents {
+    pub fn new(limit: i64) -> Self {
+        Self {
+            limit
+        }
+    }
+}
+
+impl Message for GetPendingOutboxEvents {
+    type Result = Result<Vec<models::OutboxEvent>, diesel::result::Error>;
+}
+
+impl Handler<GetPendingOutboxEvents> for DbExecutor {
+    type Result = Result<Vec<models::OutboxEvent>, diesel::result::Error>;
+
+    fn handle(&mut self, msg: GetPendingOutboxEvents, _: &mut Self::Context) -> Self::Result {
+        use schema::event_outbox::dsl::*;
+
+        event_outbox.filter(published.is_null())
+            .order_by(created.asc())
+            .limit(msg.limit)
+            .load::<models::OutboxEvent>(&self.0)
+    }
+}
+
+pub struct UpdateOutboxEvent {
+    id: Uuid,
+    published: Option<NaiveDateTime>,
+}
+
+impl UpdateOutboxEvent {
+    pub fn new(id: &Uuid, published: Option<&NaiveDateTime>) -> Self {
+        Self {
+            id: id.to_owned(),
+            published: published.map(|p| p.to_owned()),
+        }
+    }
+}
+
+impl Message for UpdateOutboxEvent {
+    type Result = Result<(), diesel::result::Error>;
+}
+
+impl Handler<UpdateOutboxEvent> for DbExecutor {
+    type Result = Result<(), diesel::result::Error>;
+
+    fn handle(&mut self, msg: UpdateOutboxEvent, _: &mut Self::Context) -> Self::Result {
+        use schema::event_outbox::dsl::*;
+
+        diesel::update(event_outbox.find(msg.id))
+            .set((
+                attempts.eq(attempts + 1),
+                published.eq(msg.published),
+            ))
+            .execute(&self.0)?;
+
+        Ok(())
+    }
+}
+
+pub struct ClaimJob {
+    now: NaiveDateTime,
+    stale_before: NaiveDateTime,
+}
+
+impl ClaimJob {
+    pub fn new(now: &NaiveDateTime, stale_before: &NaiveDateTime) -> Self {
+        Self {
+            now: now.to_owned(),
+            stale_before: stale_before.to_owned(),
+        }
+    }
+}
+
+impl Message for ClaimJob {
+    type Result = Result<Option<models::Job>, diesel::result::Error>;
+}
+
+impl Handler<ClaimJob> for DbExecutor {
+    type Result = Result<Option<models::Job>, diesel::result::Error>;
+
+    fn handle(&mut self, msg: ClaimJob, _: &mut Self::Context) -> Self::Result {
+        use schema::jobs::dsl::*;
+
+        self.0.transaction(|| {
+            let job = jobs
+                .filter(
+                    state.eq(models::JobState::PENDING).and(run_at.le(msg.now))
+                        .or(state.eq(models::JobState::RUNNING).and(updated.lt(msg.stale_before)))
+                )
+                .order_by(run_at.asc())
+                .for_update()
+                .skip_locked()
+                .first::<models::Job>(&self.0)
+                .optional()?;
+
+            match job {
+                Some(job) => Ok(Some(diesel::update(&job)
+                    .set((
+                        state.eq(models::JobState::RUNNING),
+                        attempts.eq(attempts + 1),
+                        updated.eq(msg.now),
+                    ))
+                    .get_result(&self.0)?)),
+                None => Ok(None)
+            }
+        })
+    }
+}
+
+pub struct FinishJob {
+    id: Uuid,
+    error: Option<String>,
+    retry_at: Option<NaiveDateTime>,
+}
+
+impl FinishJob {
+    pub fn new(id: &Uuid, error: Option<&str>, retry_at: Option<&NaiveDateTime>) -> Self {
+        Self {
+            id: id.to_owned(),
+            error: error.map(|e| e.to_owned()),
+            retry_at: retry_at.map(|r| r.to_owned()),
+        }
+    }
+}
+
+impl Message for FinishJob {
+    type Result = Result<(), diesel::result::Error>;
+}
+
+impl Handler<FinishJob> for DbExecutor {
+    type Result = Result<(), diesel::result::Error>;
+
+    fn handle(&mut self, msg: FinishJob, _: &mut Self::Context) -> Self::Result {
+        use schema::jobs::dsl::*;
+
+        let now = Utc::now().naive_utc();
+        let new_state = match (&msg.error, &msg.retry_at) {
+            (None, _) => models::JobState::COMPLETE,
+            (Some(_), Some(_)) => models::JobState::PENDING,
+            (Some(_), None) => models::JobState::DEAD,
+        };
+
+        diesel::update(jobs.find(msg.id))
+            .set((
+                state.eq(new_state),
+                last_error.eq(&msg.error),
+                run_at.eq(msg.retry_at.unwrap_or(now)),
+                updated.eq(now),
+            ))
+            .execute(&self.0)?;
+
+        Ok(())
+    }
+}
+
+pub struct GetJobs {
+    state: models::JobState,
+}
+
+impl GetJobs {
+    pub fn new(state: models::JobState) -> Self {
+        Self {
+            state
+        }
+    }
+}
+
+impl Message for GetJobs {
+    type Result = Result<Vec<models::Job>, diesel::result::Error>;
+}
+
+impl Handler<GetJobs> for DbExecutor {
+    type Result = Result<Vec<models::Job>, diesel::result::Error>;
+
+    fn handle(&mut self, msg: GetJobs, _: &mut Self::Context) -> Self::Result {
+        use schema::jobs::dsl::*;
+
+        jobs.filter(state.eq(msg.state))
+            .order_by(updated.desc())
+            .load::<models::Job>(&self.0)
+    }
+}
+
+pub struct RetryJob {
+    id: Uuid,
+}
+
+impl RetryJob {
+    pub fn new(id: &Uuid) -> Self {
+        Self {
+            id: id.to_owned()
+        }
+    }
+}
+
+impl Message for RetryJob {
+    type Result = Result<models::Job, diesel::result::Error>;
+}
+
+impl Handler<RetryJob> for DbExecutor {
+    type Result = Result<models::Job, diesel::result::Error>;
+
+    fn handle(&mut self, msg: RetryJob, _: &mut Self::Context) -> Self::Result {
+        use schema::jobs::dsl::*;
+
+        let now = Utc::now().naive_utc();
+        diesel::update(jobs.find(msg.id).filter(state.eq(models::JobState::DEAD)))
+            .set((
+                state.eq(models::JobState::PENDING),
+                attempts.eq(0),
+                run_at.eq(now),
+                updated.eq(now),
+            ))
+            .get_result(&self.0)
+    }
+}
+
+pub struct GetOrCreateInvoice {
+    payment_id: Uuid,
+}
+
+impl GetOrCreateInvoice {
+    pub fn new(payment_id: &Uuid) -> Self {
+        Self {
+            payment_id: payment_id.to_owned(),
+        }
+    }
+}
+
+impl Message for GetOrCreateInvoice {
+    type Result = Result<models::Invoice, diesel::result::Error>;
+}
+
+impl Handler<GetOrCreateInvoice> for DbExecutor {
+    type Result = Result<models::Invoice, diesel::result::Error>;
+
+    fn handle(&mut self, msg: GetOrCreateInvoice, _: &mut Self::Context) -> Self::Result {
+        use schema::invoices::dsl::*;
+
+        let conn = &self.0;
+        conn.transaction(|| {
+            diesel::sql_query("LOCK TABLE invoices IN EXCLUSIVE MODE").execute(conn)?;
+
+            if let Some(invoice) = invoices.filter(payment_id.eq(&msg.payment_id))
+                .first::<models::Invoice>(conn)
+                .optional()? {
+                return Ok(invoice);
+            }
+
+            let last_id = invoices.select(diesel::dsl::max(id)).first::<Option<i64>>(conn)?;
+            let new_invoice = models::NewInvoice {
+                id: last_id.unwrap_or(0) + 1,
+                payment_id: &msg.payment_id,
+            };
+
+            diesel::insert_into(invoices)
+                .values(&new_invoice)
+                .get_result(conn)
+        })
+    }
+}
+
+pub struct CreateDiscountCode {
+    id: Uuid,
+    code: String,
+    discount_type: models::DiscountType,
+    amount: i64,
+    currency: Option<String>,
+    valid_from: Option<NaiveDateTime>,
+    valid_until: Option<NaiveDateTime>,
+    max_uses: Option<i32>,
+    max_uses_per_customer: Option<i32>,
+    item_types: Option<Vec<String>>,
+}
+
+impl CreateDiscountCode {
+    pub fn new(
+        id: &Uuid, code: &str, discount_type: models::DiscountType, amount: i64, currency: Option<&str>,
+        valid_from: Option<&NaiveDateTime>, valid_until: Option<&NaiveDateTime>, max_uses: Option<i32>,
+        max_uses_per_customer: Option<i32>, item_types: Option<&[String]>,
+    ) -> Self {
+        Self {
+            id: id.to_owned(),
+            code: code.to_owned(),
+            discount_type,
+            amount,
+            currency: currency.map(|c| c.to_owned()),
+            valid_from: valid_from.map(|t| t.to_owned()),
+            valid_until: valid_until.map(|t| t.to_owned()),
+            max_uses,
+            max_uses_per_customer,
+            item_types: item_types.map(|t| t.to_vec()),
+        }
+    }
+}
+
+impl Message for CreateDiscountCode {
+    type Result = Result<models::DiscountCode, diesel::result::Error>;
+}
+
+impl Handler<CreateDiscountCode> for DbExecutor {
+    type Result = Result<models::DiscountCode, diesel::result::Error>;
+
+    fn handle(&mut self, msg: CreateDiscountCode, _: &mut Self::Context) -> Self::Result {
+        let new_code = models::NewDiscountCode {
+            id: &msg.id,
+            code: &msg.code,
+            discount_type: msg.discount_type,
+            amount: msg.amount,
+            currency: msg.currency.as_deref(),
+            valid_from: msg.valid_from.as_ref(),
+            valid_until: msg.valid_until.as_ref(),
+            max_uses: msg.max_uses,
+            max_uses_per_customer: msg.max_uses_per_customer,
+            item_types: msg.item_types,
+        };
+
+        diesel::insert_into(schema::discount_codes::table)
+            .values(&new_code)
+            .get_result(&self.0)
+    }
+}
+
+pub struct ApplyDiscountCode {
+    payment_id: Uuid,
+    code: String,
+    now: NaiveDateTime,
+    actor: crate::audit::Actor,
+}
+
+impl ApplyDiscountCode {
+    pub fn new(payment_id: &Uuid, code: &str, now: &NaiveDateTime, actor: &crate::audit::Actor) -> Self {
+        Self {
+            payment_id: payment_id.to_owned(),
+            code: code.to_owned(),
+            now: now.to_owned(),
+            actor: actor.to_owned(),
+        }
+    }
+}
+
+impl Message for ApplyDiscountCode {
+    type Result = Result<Vec<models::PaymentItem>, crate::discounts::DiscountError>;
+}
+
+impl Handler<ApplyDiscountCode> for DbExecutor {
+    type Result = Result<Vec<models::PaymentItem>, crate::discounts::DiscountError>;
+
+    fn handle(&mut self, msg: ApplyDiscountCode, _: &mut Self::Context) -> Self::Result {
+        use crate::discounts::DiscountError;
+        use schema::discount_code_uses::dsl::*;
+
+        let conn = &self.0;
+        conn.transaction::<_, DiscountError, _>(|| {
+            let payment = schema::payments::table.find(&msg.payment_id)
+                .for_update()
+                .first::<models::Payment>(conn)
+                .map_err(DiscountError::Database)?;
+            if payment.state != models::PaymentState::OPEN || payment.is_expired(&msg.now) {
+                return Err(DiscountError::PaymentNotOpen);
+            }
+            if payment.store_credit_amount > 0 {
+                return Err(DiscountError::StoreCreditApplied);
+            }
+            let tenders = models::PaymentTender::belonging_to(&payment)
+                .load::<models::PaymentTender>(conn)
+                .map_err(DiscountError::Database)?;
+            if !tenders.is_empty() {
+                return Err(DiscountError::TenderExists);
+            }
+
+            let code = schema::discount_codes::table
+                .filter(schema::discount_codes::code.eq(&msg.code))
+                .for_update()
+                .first::<models::DiscountCode>(conn)?;
+            crate::discounts::check_validity(&code, &payment, &msg.now)?;
+
+            let payment_uses: i64 = discount_code_uses.filter(payment_id.eq(&payment.id))
+                .count()
+                .get_result(conn)?;
+            if payment_uses > 0 {
+                return Err(DiscountError::AlreadyApplied);
+            }
+            if let Some(max_uses) = code.max_uses {
+                let uses: i64 = discount_code_uses.filter(discount_code_id.eq(&code.id))
+                    .count()
+                    .get_result(conn)?;
+                if uses >= max_uses as i64 {
+                    return Err(DiscountError::UsageLimitReached);
+                }
+            }
+            if let Some(max_uses) = code.max_uses_per_customer {
+                let uses: i64 = discount_code_uses.filter(discount_code_id.eq(&code.id))
+                    .filter(customer_id.eq(&payment.customer_id))
+                    .count()
+                    .get_result(conn)?;
+                if uses >= max_uses as i64 {
+                    return Err(DiscountError::CustomerLimitReached);
+                }
+            }
+
+            let items = models::PaymentItem::belonging_to(&payment)
+                .load::<models::PaymentItem>(conn)
+                .map_err(DiscountError::Database)?;
+            let lines = crate::discounts::discount_lines(&code, &items);
+            if lines.is_empty() {
+                return Err(DiscountError::NotApplicable);
+            }
+
+            diesel::insert_into(discount_code_uses)
+                .values(&models::NewDiscountCodeUse {
+                    discount_code_id: &code.id,
+                    payment_id: &payment.id,
+                    customer_id: &payment.customer_id,
+                })
+                .execute(conn)?;
+
+            let item_data = serde_json::json!({
+                "code": code.code,
+                "discount_code_id": code.id,
+                "item_types": code.item_types,
+            });
+            let title = format!("Discount ({})", code.code);
+            for (tax_category, tax_rate, price) in lines {
+                let (net_amount, tax_amount) = crate::tax::split_gross(price, tax_rate);
+                diesel::insert_into(schema::payment_items::table)
+                    .values(&models::NewPaymentItem {
+                        id: &Uuid::new_v4(),
+                        payment_id: &payment.id,
+                        item_type: crate::discounts::ITEM_TYPE,
+                        item_data: &item_data,
+                        title: &title,
+                        quantity: 1,
+                        price,
+                        tax_category,
+                        tax_rate,
+                        net_amount,
+                        tax_amount,
+                    })
+                    .execute(conn)?;
+            }
+            crate::audit::record(conn, &payment.id, &msg.actor, crate::audit::DISCOUNT_APPLIED, None, Some(&serde_json::json!({
+                "code": code.code,
+                "discount_code_id": code.id,
+            })))?;
+
+            models::PaymentItem::belonging_to(&payment)
+                .load::<models::PaymentItem>(conn)
+                .map_err(DiscountError::Database)
+        })
+    }
+}
+
+pub struct GetStoreCreditEntries {
+    customer_id: Uuid,
+}
+
+impl GetStoreCreditEntries {
+    pub fn new(customer_id: &Uuid) -> Self {
+        Self {
+            customer_id: customer_id.to_owned(),
+        }
+    }
+}
+
+impl Message for GetStoreCreditEntries {
+    type Result = Result<Vec<models::StoreCreditEntry>, diesel::result::Error>;
+}
+
+impl Handler<GetStoreCreditEntries> for DbExecutor {
+    type Result = Result<Vec<models::StoreCreditEntry>, diesel::result::Error>;
+
+    fn handle(&mut self, msg: GetStoreCreditEntries, _: &mut Self::Context) -> Self::Result {
+        use schema::store_credit_entries::dsl::*;
+
+        store_credit_entries.filter(customer_id.eq(&msg.customer_id))
+            .order_by(timestamp.desc())
+            .load::<models::StoreCreditEntry>(&self.0)
+    }
+}
+
+pub struct CreateStoreCreditEntry {
+    customer_id: Uuid,
+    currency: String,
+    amount: i64,
+    entry_type: models::StoreCreditEntryType,
+    payment_id: Option<Uuid>,
+    reason: Option<String>,
+    created_by: Option<Uuid>,
+}
+
+impl CreateStoreCreditEntry {
+    pub fn new(
+        customer_id: &Uuid, currency: &str, amount: i64, entry_type: models::StoreCreditEntryType,
+        payment_id: Option<&Uuid>, reason: Option<&str>, created_by: Option<&Uuid>,
+    ) -> Self {
+        Self {
+            customer_id: customer_id.to_owned(),
+            currency: currency.to_owned(),
+            amount,
+            entry_type,
+            payment_id: payment_id.map(|p| p.to_owned()),
+            reason: reason.map(|r| r.to_owned()),
+            created_by: created_by.map(|c| c.to_owned()),
+        }
+    }
+}
+
+impl Message for CreateStoreCreditEntry {
+    type Result = Result<models::StoreCreditEntry, crate::store_credit::StoreCreditError>;
+}
+
+impl Handler<CreateStoreCreditEntry> for DbExecutor {
+    type Result = Result<models::StoreCreditEntry, crate::store_credit::StoreCreditError>;
+
+    fn handle(&mut self, msg: CreateStoreCreditEntry, _: &mut Self::Context) -> Self::Result {
+        use crate::store_credit::StoreCreditError;
+
+        let conn = &self.0;
+        conn.transaction::<_, StoreCreditError, _>(|| {
+            crate::store_credit::lock(conn, &msg.customer_id)?;
+            if crate::store_credit::balance(conn, &msg.customer_id, &msg.currency)? + msg.amount < 0 {
+                return Err(StoreCreditError::InsufficientBalance);
+            }
+
+            Ok(crate::store_credit::record(
+                conn, &msg.customer_id, &msg.currency, msg.amount, msg.entry_type,
+                msg.payment_id.as_ref(), msg.reason.as_deref(), msg.created_by.as_ref(),
+            )?)
+        })
+    }
+}
+
+pub struct ApplyStoreCredit {
+    payment_id: Uuid,
+    max_amount: Option<i64>,
+    actor: crate::audit::Actor,
+}
+
+impl ApplyStoreCredit {
+    pub fn new(payment_id: &Uuid, max_amount: Option<i64>, actor: &crate::audit::Actor) -> Self {
+        Self {
+            payment_id: payment_id.to_owned(),
+            max_amount,
+            actor: actor.to_owned(),
+        }
+    }
+}
+
+impl Message for ApplyStoreCredit {
+    type Result = Result<models::Payment, crate::store_credit::StoreCreditError>;
+}
+
+impl Handler<ApplyStoreCredit> for DbExecutor {
+    type Result = Result<models::Payment, crate::store_credit::StoreCreditError>;
+
+    fn handle(&mut self, msg: ApplyStoreCredit, _: &mut Self::Context) -> Self::Result {
+        use crate::store_credit::StoreCreditError;
+        use schema::payments::dsl::*;
+
+        let conn = &self.0;
+        conn.transaction::<_, StoreCreditError, _>(|| {
+            let payment = payments.find(&msg.payment_id)
+                .for_update()
+                .first::<models::Payment>(conn)?;
+            if payment.state != models::PaymentState::OPEN || payment.is_expired(&Utc::now().naive_utc()) {
+                return Err(StoreCreditError::PaymentNotOpen);
+            }
+
+            crate::store_credit::lock(conn, &payment.customer_id)?;
+            let balance = crate::store_credit::balance(conn, &payment.customer_id, &payment.currency)?;
+            let items = models::PaymentItem::belonging_to(&payment)
+                .load::<models::PaymentItem>(conn)?;
+            let tenders = models::PaymentTender::belonging_to(&payment)
+                .load::<models::PaymentTender>(conn)?;
+
+            let mut amount = balance.min(crate::tenders::outstanding(&payment, &items, &tenders));
+            if let Some(max_amount) = msg.max_amount {
+                amount = amount.min(max_amount);
+            }
+            if amount <= 0 {
+                return Err(StoreCreditError::NothingToApply);
+            }
+
+            crate::store_credit::record(
+                conn, &payment.customer_id, &payment.currency, -amount,
+                models::StoreCreditEntryType::SPEND, Some(&payment.id), None, None,
+            )?;
+
+            let updated = diesel::update(&payment)
+                .set(store_credit_amount.eq(payment.store_credit_amount + amount))
+                .get_result::<models::Payment>(conn)?;
+            crate::audit::record(
+                conn, &payment.id, &msg.actor, crate::audit::STORE_CREDIT_APPLIED,
+                Some(&serde_json::json!({"store_credit_amount": payment.store_credit_amount})),
+                Some(&serde_json::json!({"store_credit_amount": updated.store_credit_amount})),
+            )?;
+
+            Ok(updated)
+        })
+    }
+}
+
+pub struct RemoveStoreCredit {
+    payment_id: Uuid,
+    actor: crate::audit::Actor,
+}
+
+impl RemoveStoreCredit {
+    pub fn new(payment_id: &Uuid, actor: &crate::audit::Actor) -> Self {
+        Self {
+            payment_id: payment_id.to_owned(),
+            actor: actor.to_owned(),
+        }
+    }
+}
+
+impl Message for RemoveStoreCredit {
+    type Result = Result<models::Payment, crate::store_credit::StoreCreditError>;
+}
+
+impl Handler<RemoveStoreCredit> for DbExecutor {
+    type Result = Result<models::Payment, crate::store_credit::StoreCreditError>;
+
+    fn handle(&mut self, msg: RemoveStoreCredit, _: &mut Self::Context) -> Self::Result {
+        use crate::store_credit::StoreCreditError;
+        use schema::payments::dsl::*;
+
+        let conn = &self.0;
+        conn.transaction::<_, StoreCreditError, _>(|| {
+            let payment = payments.find(&msg.payment_id)
+                .for_update()
+                .first::<models::Payment>(conn)?;
+            if payment.state != models::PaymentState::OPEN {
+                return Err(StoreCreditError::PaymentNotOpen);
+            }
+            if payment.store_credit_amount == 0 {
+                return Ok(payment);
+            }
+
+            crate::store_credit::record(
+                conn, &payment.customer_id, &payment.currency, payment.store_credit_amount,
+                models::StoreCreditEntryType::REVERSAL, Some(&payment.id), None, None,
+            )?;
+
+            let updated = diesel::update(&payment)
+                .set(store_credit_amount.eq(0))
+                .get_result::<models::Payment>(conn)?;
+            crate::audit::record(
+                conn, &payment.id, &msg.actor, crate::audit::STORE_CREDIT_REMOVED,
+                Some(&serde_json::json!({"store_credit_amount": payment.store_credit_amount})),
+                Some(&serde_json::json!({"store_credit_amount": updated.store_credit_amount})),
+            )?;
+
+            Ok(updated)
+        })
+    }
+}
+
+pub struct GetPaymentTokens {
+}
+
+impl GetPaymentTokens {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl Message for GetPaymentTokens {
+    type Result = Result<Vec<models::PaymentToken>, diesel::result::Error>;
+}
+
+impl Handler<GetPaymentTokens> for DbExecutor {
+    type Result = Result<Vec<models::PaymentToken>, diesel::result::Error>;
+
+    fn handle(&mut self, _msg: GetPaymentTokens, _: &mut Self::Context) -> Self::Result {
+        schema::payment_tokens::table.load::<models::PaymentToken>(&self.0)
+    }
+}
+
+pub struct AdminTransitionPayment {
+    payment_id: Uuid,
+    admin_id: Uuid,
+    state: models::PaymentState,
+    reason: Option<String>,
+    actor: crate::audit::Actor,
+}
+
+impl AdminTransitionPayment {
+    pub fn new(payment_id: &Uuid, admin_id: &Uuid, state: models::PaymentState, reason: Option<&str>, actor: &crate::audit::Actor) -> Self {
+        Self {
+            payment_id: payment_id.to_owned(),
+            admin_id: admin_id.to_owned(),
+            state,
+            reason: reason.map(|r| r.to_owned()),
+            actor: actor.to_owned(),
+        }
+    }
+}
+
+impl Message for AdminTransitionPayment {
+    type Result = Result<models::Payment, crate::admin_actions::AdminActionError>;
+}
+
+impl Handler<AdminTransitionPayment> for DbExecutor {
+    type Result = Result<models::Payment, crate::admin_actions::AdminActionError>;
+
+    fn handle(&mut self, msg: AdminTransitionPayment, _: &mut Self::Context) -> Self::Result {
+        use crate::admin_actions::AdminActionError;
+
+        let conn = &self.0;
+        conn.transaction::<_, AdminActionError, _>(|| {
+            let payment = schema::payments::table.find(&msg.payment_id)
+                .for_update()
+                .first::<models::Payment>(conn)?;
+            if !crate::admin_actions::allowed_transition(payment.state, msg.state) {
+                return Err(AdminActionError::InvalidTransition(payment.state, msg.state));
+            }
+            if payment.state == models::PaymentState::OPEN {
+                let tenders = models::PaymentTender::belonging_to(&payment)
+                    .load::<models::PaymentTender>(conn)?;
+                if crate::tenders::paid(&tenders) > 0 {
+                    return Err(AdminActionError::PartiallyPaid);
+                }
+            }
+
+            let updated = crate::state_machine::transition(conn, &payment, msg.state, &msg.actor)?;
+            record_state_change(conn, payment.state, &updated)?;
+            crate::admin_actions::record(
+                conn, &payment.id, &msg.admin_id, crate::admin_actions::TRANSITION, Some(payment.state), Some(msg.state),
+                &serde_json::json!({}), msg.reason.as_deref(),
+            )?;
+
+            Ok(updated)
+        })
+    }
+}
+
+pub struct AdminReplacePaymentItems {
+    payment_id: Uuid,
+    admin_id: Uuid,
+    items: Vec<CreatePaymentItem>,
+    reason: Option<String>,
+    actor: crate::audit::Actor,
+}
+
+impl AdminReplacePaymentItems {
+    pub fn new(payment_id: &Uuid, admin_id: &Uuid, items: &[CreatePaymentItem], reason: Option<&str>, actor: &crate::audit::Actor) -> Self {
+        Self {
+            payment_id: payment_id.to_owned(),
+            admin_id: admin_id.to_owned(),
+            items: items.to_vec(),
+            reason: reason.map(|r| r.to_owned()),
+            actor: actor.to_owned(),
+        }
+    }
+}
+
+impl Message for AdminReplacePaymentItems {
+    type Result = Result<Vec<models::PaymentItem>, crate::admin_actions::AdminActionError>;
+}
+
+impl Handler<AdminReplacePaymentItems> for DbExecutor {
+    type Result = Result<Vec<models::PaymentItem>, crate::admin_actions::AdminActionError>;
+
+    fn handle(&mut self, msg: AdminReplacePaymentItems, _: &mut Self::Context) -> Self::Result {
+        use crate::admin_actions::AdminActionError;
+
+        let conn = &self.0;
+        conn.transaction::<_, AdminActionError, _>(|| {
+            let payment = schema::payments::table.find(&msg.payment_id)
+                .for_update()
+                .first::<models::Payment>(conn)?;
+            if payment.state != models::PaymentState::OPEN {
+                return Err(AdminActionError::PaymentNotOpen);
+            }
+
+            let previous_items = models::PaymentItem::belonging_to(&payment)
+                .load::<models::PaymentItem>(conn)?;
+            let tenders = models::PaymentTender::belonging_to(&payment)
+                .load::<models::PaymentTender>(conn)?;
+            if !tenders.is_empty() {
+                return Err(AdminActionError::PartiallyPaid);
+            }
+            if payment.store_credit_amount > 0 || previous_items.iter().any(|i| i.item_type == crate::discounts::ITEM_TYPE) {
+                return Err(AdminActionError::HasAdjustments);
+            }
+
+            diesel::delete(models::PaymentItem::belonging_to(&payment))
+                .execute(conn)?;
+            insert_payment_items(conn, &payment.id, &msg.items)?;
+            let items = models::PaymentItem::belonging_to(&payment)
+                .load::<models::PaymentItem>(conn)?;
+
+            let item_details = |items: &[models::PaymentItem]| items.iter()
+                .map(|i| serde_json::json!({
+                    "id": i.id,
+                    "type": i.item_type,
+                    "title": i.title,
+                    "quantity": i.quantity,
+                    "price": i.price,
+                    "tax_category": i.tax_category,
+                }))
+                .collect::<Vec<_>>();
+            let old_values = serde_json::json!({"items": item_details(&previous_items)});
+            let new_values = serde_json::json!({"items": item_details(&items)});
+            crate::admin_actions::record(
+                conn, &payment.id, &msg.admin_id, crate::admin_actions::EDIT_ITEMS, None, None,
+                &serde_json::json!({
+                    "old": old_values["items"],
+                    "new": new_values["items"],
+                }),
+                msg.reason.as_deref(),
+            )?;
+            crate::audit::record(conn, &payment.id, &msg.actor, crate::audit::ITEMS_EDITED, Some(&old_values), Some(&new_values))?;
+
+            Ok(items)
+        })
+    }
+}
+
+pub struct RecordPaymentAccess {
+    payment_id: Uuid,
+    actor: crate::audit::Actor,
+    since: NaiveDateTime,
+}
+
+impl RecordPaymentAccess {
+    pub fn new(payment_id: &Uuid, actor: &crate::audit::Actor, since: &NaiveDateTime) -> Self {
+        Self {
+            payment_id: payment_id.to_owned(),
+            actor: actor.to_owned(),
+            since: since.to_owned(),
+        }
+    }
+}
+
+impl Message for RecordPaymentAccess {
+    type Result = Result<(), diesel::result::Error>;
+}
+
+impl Handler<RecordPaymentAccess> for DbExecutor {
+    type Result = Result<(), diesel::result::Error>;
+
+    fn handle(&mut self, msg: RecordPaymentAccess, _: &mut Self::Context) -> Self::Result {
+        use schema::payment_events::dsl::*;
+
+        let recent = payment_events.filter(payment_id.eq(&msg.payment_id))
+            .filter(action.eq(crate::audit::VIEWED))
+            .filter(timestamp.gt(&msg.since))
+            .load::<models::PaymentAuditEvent>(&self.0)?;
+        if recent.iter().any(|e| e.actor_type == msg.actor.actor_type && e.actor == msg.actor.id && e.ip_address == msg.actor.ip_address) {
+            return Ok(());
+        }
+
+        crate::audit::record(&self.0, &msg.payment_id, &msg.actor, crate::audit::VIEWED, None, None)
+    }
+}
+
+pub struct GetPaymentAuditEvents {
+    payment: models::Payment,
+}
+
+impl GetPaymentAuditEvents {
+    pub fn new(payment: &models::Payment) -> Self {
+        Self {
+            payment: payment.to_owned()
+        }
+    }
+}
+
+impl Message for GetPaymentAuditEvents {
+    type Result = Result<Vec<models::PaymentAuditEvent>, diesel::result::Error>;
+}
+
+impl Handler<GetPaymentAuditEvents> for DbExecutor {
+    type Result = Result<Vec<models::PaymentAuditEvent>, diesel::result::Error>;
+
+    fn handle(&mut self, msg: GetPaymentAuditEvents, _: &mut Self::Context) -> Self::Result {
+        use schema::payment_events::dsl::*;
+
+        models::PaymentAuditEvent::belonging_to(&msg.payment)
+            .order_by(id.asc())
+            .load::<models::PaymentAuditEvent>(&self.0)
+    }
+}
+
+pub struct BeginIdempotentRequest {
+    scope: String,
+    key: String,
+    request_hash: String,
+    now: NaiveDateTime,
+    locked_until: NaiveDateTime,
+    expires_at: NaiveDateTime,
+}
+
+impl BeginIdempotentRequest {
+    pub fn new(scope: &str, key: &str, request_hash: &str, now: &NaiveDateTime, locked_until: &NaiveDateTime, expires_at: &NaiveDateTime) -> Self {
+        Self {
+            scope: scope.to_owned(),
+            key: key.to_owned(),
+            request_hash: request_hash.to_owned(),
+            now: now.to_owned(),
+            locked_until: locked_until.to_owned(),
+            expires_at: expires_at.to_owned(),
+        }
+    }
+}
+
+impl Message for BeginIdempotentRequest {
+    type Result = Result<crate::idempotency::Outcome, diesel::result::Error>;
+}
+
+impl Handler<BeginIdempotentRequest> for DbExecutor {
+    type Result = Result<crate::idempotency::Outcome, diesel::result::Error>;
+
+    fn handle(&mut self, msg: BeginIdempotentRequest, _: &mut Self::Context) -> Self::Result {
+        use crate::idempotency::Outcome;
+        use schema::idempotency_keys::dsl::*;
+
+        let conn = &self.0;
+        conn.transaction(|| {
+            diesel::delete(idempotency_keys.find((msg.scope.as_str(), msg.key.as_str())))
+                .filter(expires_at.le(msg.now))
+                .execute(conn)?;
+
+            let inserted = diesel::insert_into(idempotency_keys)
+                .values(&models::NewIdempotencyKey {
+                    scope: &msg.scope,
+                    key: &msg.key,
+                    request_hash: &msg.request_hash,
+                    expires_at: &msg.expires_at,
+                    locked_until: Some(&msg.locked_until),
+                })
+                .on_conflict_do_nothing()
+                .execute(conn)?;
+            if inserted == 1 {
+                return Ok(Outcome::New);
+            }
+
+            let existing = idempotency_keys.find((msg.scope.as_str(), msg.key.as_str()))
+                .for_update()
+                .first::<models::IdempotencyKey>(conn)?;
+            if existing.request_hash != msg.request_hash {
+                return Ok(Outcome::Mismatch);
+            }
+
+            match (existing.response_status, existing.response_body) {
+                (Some(status), Some(body)) => Ok(Outcome::Replay(status, body)),
+                _ => match existing.locked_until {
+                    Some(l) if l > msg.now => Ok(Outcome::InProgress),
+                    _ => {
+                        diesel::update(idempotency_keys.find((msg.scope.as_str(), msg.key.as_str())))
+                            .set(locked_until.eq(msg.locked_until))
+                            .execute(conn)?;
+                        Ok(Outcome::New)
+                    }
+                }
+            }
+        })
+    }
+}
+
+pub struct CompleteIdempotentRequest {
+    scope: String,
+    key: String,
+    status: i32,
+    body: serde_json::Value,
+}
+
+impl CompleteIdempotentRequest {
+    pub fn new(scope: &str, key: &str, status: i32, body: &serde_json::Value) -> Self {
+        Self {
+            scope: scope.to_owned(),
+            key: key.to_owned(),
+            status,
+            body: body.to_owned(),
+        }
+    }
+}
+
+impl Message for CompleteIdempotentRequest {
+    type Result = Result<(), diesel::result::Error>;
+}
+
+impl Handler<CompleteIdempotentRequest> for DbExecutor {
+    type Result = Result<(), diesel::result::Error>;
+
+    fn handle(&mut self, msg: CompleteIdempotentRequest, _: &mut Self::Context) -> Self::Result {
+        use schema::idempotency_keys::dsl::*;
+
+        diesel::update(idempotency_keys.find((msg.scope.as_str(), msg.key.as_str())))
+            .set((
+                response_status.eq(msg.status),
+                response_body.eq(&msg.body),
+                locked_until.eq(None::<NaiveDateTime>),
+            ))
+            .execute(&self.0)?;
+
+        Ok(())
+    }
+}
+
+pub struct ReleaseIdempotencyKey {
+    scope: String,
+    key: String,
+}
+
+impl ReleaseIdempotencyKey {
+    pub fn new(scope: &str, key: &str) -> Self {
+        Self {
+            scope: scope.to_owned(),
+            key: key.to_owned(),
+        }
+    }
+}
+
+impl Message for ReleaseIdempotencyKey {
+    type Result = Result<(), diesel::result::Error>;
+}
+
+impl Handler<ReleaseIdempotencyKey> for DbExecutor {
+    type Result = Result<(), diesel::result::Error>;
+
+    fn handle(&mut self, msg: ReleaseIdempotencyKey, _: &mut Self::Context) -> Self::Result {
+        use schema::idempotency_keys::dsl::*;
+
+        diesel::delete(idempotency_keys.find((msg.scope.as_str(), msg.key.as_str())))
+            .filter(response_body.is_null())
+            .execute(&self.0)?;
+
+        Ok(())
+    }
+}
+
+pub struct DeleteExpiredIdempotencyKeys {
+    now: NaiveDateTime,
+}
+
+impl DeleteExpiredIdempotencyKeys {
+    pub fn new(now: &NaiveDateTime) -> Self {
+        Self {
+            now: now.to_owned()
+        }
+    }
+}
+
+impl Message for DeleteExpiredIdempotencyKeys {
+    type Result = Result<usize, diesel::result::Error>;
+}
+
+impl Handler<DeleteExpiredIdempotencyKeys> for DbExecutor {
+    type Result = Result<usize, diesel::result::Error>;
+
+    fn handle(&mut self, msg: DeleteExpiredIdempotencyKeys, _: &mut Self::Context) -> Self::Result {
+        use schema::idempotency_keys::dsl::*;
+
+        diesel::delete(idempotency_keys.filter(expires_at.le(msg.now)))
+            .execute(&self.0)
     }
 }
@@ -0,0 +1,130 @@
+use actix_web::{HttpRequest, HttpResponse, web};
+use chrono::prelude::*;
+use rust_decimal::prelude::*;
+use crate::db;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct NewDiscountCodeData {
+    code: String,
+    discount_type: crate::models::DiscountType,
+    amount: rust_decimal::Decimal,
+    currency: Option<String>,
+    valid_from: Option<DateTime<Utc>>,
+    valid_until: Option<DateTime<Utc>>,
+    max_uses: Option<i32>,
+    max_uses_per_customer: Option<i32>,
+    item_types: Option<Vec<String>>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct DiscountCodeResponseData {
+    id: uuid::Uuid,
+    code: String,
+}
+
+pub async fn create_discount_code(token: crate::oauth::BearerAuthToken, data: web::Data<crate::config::AppState>, new_code: web::Json<NewDiscountCodeData>) -> actix_web::Result<impl actix_web::Responder> {
+    data.oauth.verify_token(token.token(), "manage-discounts").await?;
+
+    let code = new_code.code.trim().to_uppercase();
+    if code.is_empty() {
+        return Err(actix_web::error::ErrorBadRequest("invalid code"));
+    }
+
+    let (amount, currency) = match new_code.discount_type {
+        crate::models::DiscountType::FIXED => {
+            let currency = match crate::currency::Currency::lookup(
+                &new_code.currency.as_deref().unwrap_or(crate::currency::DEFAULT).to_uppercase()
+            ) {
+                Some(c) => c,
+                None => return Err(actix_web::error::ErrorBadRequest("invalid currency"))
+            };
+            match currency.to_minor(&new_code.amount) {
+                Some(a) if a > 0 => (a, Some(currency.code)),
+                _ => return Err(actix_web::error::ErrorBadRequest("invalid amount"))
+            }
+        }
+        crate::models::DiscountType::PERCENTAGE => {
+            let basis_points = (new_code.amount * rust_decimal::Decimal::new(100, 0)).normalize();
+            match if basis_points.scale() == 0 { basis_points.to_i64() } else { None } {
+                Some(a) if a > 0 && a <= 10000 => (a, None),
+                _ => return Err(actix_web::error::ErrorBadRequest("invalid percentage"))
+            }
+        }
+    };
+
+    let discount_code = match match data.db.send(db::CreateDiscountCode::new(
+        &uuid::Uuid::new_v4(),
+        &code,
+        new_code.discount_type,
+        amount,
+        currency,
+        new_code.valid_from.map(|t| t.naive_utc()).as_ref(),
+        new_code.valid_until.map(|t| t.naive_utc()).as_ref(),
+        new_code.max_uses,
+        new_code.max_uses_per_customer,
+        new_code.item_types.as_deref(),
+    )).await {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Ok(r) => r,
+        Err(e) => return match e {
+            diesel::result::Error::DatabaseError(diesel::result::DatabaseErrorKind::UniqueViolation, _) =>
+                Err(actix_web::error::ErrorConflict("code already exists")),
+            _ => Err(actix_web::error::ErrorInternalServerError(e))
+        }
+    };
+
+    Ok(HttpResponse::Ok().json(DiscountCodeResponseData {
+        id: discount_code.id,
+        code: discount_code.code,
+    }))
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ApplyDiscountCodeData {
+    code: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct ApplyDiscountCodeResponseData {
+    discount: f64,
+    total: f64,
+}
+
+pub async fn apply_discount_code(req: HttpRequest, token: crate::oauth::OptionalBearerAuthToken, data: web::Data<crate::config::AppState>, info: web::Path<uuid::Uuid>, session: actix_session::Session, discount_data: web::Json<ApplyDiscountCodeData>) -> actix_web::Result<impl actix_web::Responder> {
+    let payment = match match data.db.send(db::GetPayment::new(&info.into_inner())).await {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Ok(r) => r,
+        Err(e) => return match e {
+            diesel::result::Error::NotFound => Err(actix_web::error::ErrorNotFound(e)),
+            _ => Err(actix_web::error::ErrorInternalServerError(e))
+        }
+    };
+
+    let actor = crate::payment_views::check_payment_access(&req, &token, &data, &session, &payment).await?;
+    let items = match match data.db.send(db::ApplyDiscountCode::new(
+        &payment.id, &discount_data.code.trim().to_uppercase(), &Utc::now().naive_utc(), &actor,
+    )).await {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Ok(r) => r,
+        Err(e) => return match e {
+            crate::discounts::DiscountError::Database(e) => Err(actix_web::error::ErrorInternalServerError(e)),
+            crate::discounts::DiscountError::UnknownCode => Err(actix_web::error::ErrorNotFound(e)),
+            e => Err(actix_web::error::ErrorBadRequest(e))
+        }
+    };
+
+    let currency = crate::currency::Currency::for_payment(&payment);
+    Ok(HttpResponse::Ok().json(ApplyDiscountCodeResponseData {
+        discount: currency.to_major(items.iter()
+            .filter(|i| i.item_type == crate::discounts::ITEM_TYPE)
+            .map(|i| i.price * i.quantity as i64)
+            .sum()),
+        total: currency.to_major(items.iter().map(|i| i.price * i.quantity as i64).sum()),
+    }))
+}
@@ -0,0 +1,242 @@
+use chrono::prelude::*;
+
+use crate::models;
+
+pub const ITEM_TYPE: &str = "discount";
+
+#[derive(Debug)]
+pub enum DiscountError {
+    UnknownCode,
+    NotYetValid,
+    Expired,
+    UsageLimitReached,
+    CustomerLimitReached,
+    WrongCurrency,
+    NotApplicable,
+    AlreadyApplied,
+    PaymentNotOpen,
+    StoreCreditApplied,
+    TenderExists,
+    Database(diesel::result::Error),
+}
+
+impl std::fmt::Display for DiscountError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DiscountError::UnknownCode => write!(f, "unknown discount code"),
+            DiscountError::NotYetValid => write!(f, "discount code is not yet valid"),
+            DiscountError::Expired => write!(f, "discount code has expired"),
+            DiscountError::UsageLimitReached => write!(f, "discount code has been used too many times"),
+            DiscountError::CustomerLimitReached => write!(f, "discount code has already been used"),
+            DiscountError::WrongCurrency => write!(f, "discount code cannot be used in this currency"),
+            DiscountError::NotApplicable => write!(f, "discount code does not apply to any items"),
+            DiscountError::AlreadyApplied => write!(f, "a discount code has already been applied"),
+            DiscountError::PaymentNotOpen => write!(f, "payment is not open"),
+            DiscountError::StoreCreditApplied => write!(f, "store credit must be removed before applying a discount"),
+            DiscountError::TenderExists => write!(f, "a discount cannot be applied after payment has started"),
+            DiscountError::Database(e) => e.fmt(f),
+        }
+    }
+}
+
+impl From<diesel::result::Error> for DiscountError {
+    fn from(e: diesel::result::Error) -> Self {
+        match e {
+            diesel::result::Error::NotFound => DiscountError::UnknownCode,
+            e => DiscountError::Database(e)
+        }
+    }
+}
+
+pub fn check_validity(code: &models::DiscountCode, payment: &models::Payment, now: &NaiveDateTime) -> Result<(), DiscountError> {
+    if let Some(from) = code.valid_from {
+        if *now < from {
+            return Err(DiscountError::NotYetValid);
+        }
+    }
+    if let Some(until) = code.valid_until {
+        if *now >= until {
+            return Err(DiscountError::Expired);
+        }
+    }
+    if let Some(currency) = &code.currency {
+        if *currency != payment.currency {
+            return Err(DiscountError::WrongCurrency);
+        }
+    }
+
+    Ok(())
+}
+
+pub fn discount_lines(code: &models::DiscountCode, items: &[models::PaymentItem]) -> Vec<(models::TaxCategory, i32, i64)> {
+    let mut eligible: Vec<(models::TaxCategory, i32, i64)> = vec![];
+    for item in items {
+        if item.item_type == ITEM_TYPE {
+            continue;
+        }
+        if let Some(item_types) = &code.item_types {
+            if !item_types.contains(&item.item_type) {
+                continue;
+            }
+        }
+        let gross = item.price * item.quantity as i64;
+        match eligible.iter_mut().find(|(c, r, _)| *c == item.tax_category && *r == item.tax_rate) {
+            Some(l) => l.2 += gross,
+            None => eligible.push((item.tax_category, item.tax_rate, gross))
+        }
+    }
+
+    let eligible_total: i64 = eligible.iter().map(|l| l.2).sum();
+    if eligible_total <= 0 {
+        return vec![];
+    }
+    let discount = match code.discount_type {
+        models::DiscountType::FIXED => code.amount.min(eligible_total),
+        models::DiscountType::PERCENTAGE => (eligible_total * code.amount + 5000) / 10000,
+    };
+
+    let mut remaining = discount;
+    let count = eligible.len();
+    eligible.into_iter()
+        .enumerate()
+        .map(|(i, (category, rate, gross))| {
+            let share = if i + 1 == count {
+                remaining
+            } else {
+                discount * gross / eligible_total
+            };
+            remaining -= share;
+            (category, rate, -share)
+        })
+        .filter(|l| l.2 != 0)
+        .collect()
+}
+
+fn discounts_item(line: &models::PaymentItem, item: &models::PaymentItem) -> bool {
+    if item.item_type == ITEM_TYPE || item.tax_category != line.tax_category || item.tax_rate != line.tax_rate {
+        return false;
+    }
+    match line.item_data.get("item_types").and_then(|t| t.as_array()) {
+        Some(item_types) => item_types.iter().any(|t| t.as_str() == Some(item.item_type.as_str())),
+        None => true
+    }
+}
+
+pub fn discounted_totals(items: &[models::PaymentItem]) -> Vec<(uuid::Uuid, i64)> {
+    let mut totals: Vec<(uuid::Uuid, i64)> = items.iter()
+        .filter(|i| i.item_type != ITEM_TYPE)
+        .map(|i| (i.id, i.price * i.quantity as i64))
+        .collect();
+
+    for line in items.iter().filter(|i| i.item_type == ITEM_TYPE) {
+        let discount = -(line.price * line.quantity as i64);
+        let eligible = items.iter().filter(|i| discounts_item(line, i)).collect::<Vec<_>>();
+        let eligible_total: i64 = eligible.iter().map(|i| i.price * i.quantity as i64).sum();
+        if eligible_total <= 0 {
+            continue;
+        }
+
+        let mut remaining = discount;
+        for (n, item) in eligible.iter().enumerate() {
+            let share = if n + 1 == eligible.len() {
+                remaining
+            } else {
+                discount * (item.price * item.quantity as i64) / eligible_total
+            };
+            remaining -= share;
+            if let Some(t) = totals.iter_mut().find(|t| t.0 == item.id) {
+                t.1 -= share;
+            }
+        }
+    }
+
+    totals
+}
+
+pub fn item_refund_amount(item_total: i64, quantity: i32, refunded_quantity: i32, refunded_amount: i64, refund_quantity: i32) -> i64 {
+    if refunded_quantity + refund_quantity >= quantity {
+        (item_total - refunded_amount).max(0)
+    } else {
+        item_total * refund_quantity as i64 / quantity as i64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(item_type: &str, quantity: i32, price: i64, item_data: serde_json::Value) -> models::PaymentItem {
+        models::PaymentItem {
+            id: uuid::Uuid::new_v4(),
+            payment_id: uuid::Uuid::nil(),
+            item_type: item_type.to_string(),
+            item_data,
+            title: item_type.to_string(),
+            quantity,
+            price,
+            tax_category: models::TaxCategory::STANDARD,
+            tax_rate: 2000,
+            net_amount: 0,
+            tax_amount: 0,
+        }
+    }
+
+    fn code(discount_type: models::DiscountType, amount: i64) -> models::DiscountCode {
+        models::DiscountCode {
+            id: uuid::Uuid::new_v4(),
+            code: "TEST".to_string(),
+            discount_type,
+            amount,
+            currency: None,
+            valid_from: None,
+            valid_until: None,
+            max_uses: None,
+            max_uses_per_customer: None,
+            item_types: None,
+            created: Utc::now().naive_utc(),
+        }
+    }
+
+    #[test]
+    fn percentage_discounts_put_the_rounding_remainder_on_the_last_line() {
+        let mut reduced = item("part", 1, 1000, serde_json::json!({}));
+        reduced.tax_category = models::TaxCategory::REDUCED;
+        reduced.tax_rate = 500;
+        let items = vec![item("repair", 1, 999, serde_json::json!({})), reduced];
+
+        let lines = discount_lines(&code(models::DiscountType::PERCENTAGE, 1000), &items);
+        assert_eq!(lines, vec![
+            (models::TaxCategory::STANDARD, 2000, -99),
+            (models::TaxCategory::REDUCED, 500, -101),
+        ]);
+    }
+
+    #[test]
+    fn fixed_discounts_are_capped_at_the_eligible_total() {
+        let items = vec![item("repair", 1, 500, serde_json::json!({}))];
+        assert_eq!(discount_lines(&code(models::DiscountType::FIXED, 1000), &items), vec![(models::TaxCategory::STANDARD, 2000, -500)]);
+    }
+
+    #[test]
+    fn item_refunds_include_their_share_of_discounts() {
+        let repair = item("repair", 2, 3000, serde_json::json!({}));
+        let part = item("part", 1, 4000, serde_json::json!({}));
+        let discount = item(ITEM_TYPE, 1, -1000, serde_json::json!({}));
+        let totals = discounted_totals(&[repair.clone(), part.clone(), discount]);
+
+        assert_eq!(totals, vec![(repair.id, 5400), (part.id, 3600)]);
+        assert_eq!(item_refund_amount(5400, 2, 0, 0, 1), 2700);
+        assert_eq!(item_refund_amount(3600, 1, 0, 0, 1), 3600);
+        assert_eq!(item_refund_amount(5400, 2, 1, 2700, 1), 2700);
+    }
+
+    #[test]
+    fn discounts_only_spread_over_their_item_types() {
+        let repair = item("repair", 1, 3000, serde_json::json!({}));
+        let part = item("part", 1, 4000, serde_json::json!({}));
+        let discount = item(ITEM_TYPE, 1, -1000, serde_json::json!({"item_types": ["part"]}));
+        let totals = discounted_totals(&[repair.clone(), part.clone(), discount]);
+
+        assert_eq!(totals, vec![(repair.id, 3000), (part.id, 3000)]);
+    }
+}
@@ -0,0 +1,127 @@
+use chrono::prelude::*;
+use failure::Fallible;
+use lapin::options::{BasicPublishOptions, ConfirmSelectOptions, ExchangeDeclareOptions};
+use lapin::publisher_confirm::Confirmation;
+use lapin::types::FieldTable;
+
+use crate::models;
+
+pub const EXCHANGE: &str = "payments";
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PaymentEvent {
+    Created,
+    Authorised,
+    Paid,
+    Failed,
+    Completed,
+    Refunded,
+    ChargedBack,
+    Cancelled,
+    Expired,
+}
+
+impl PaymentEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PaymentEvent::Created => "created",
+            PaymentEvent::Authorised => "authorised",
+            PaymentEvent::Paid => "paid",
+            PaymentEvent::Failed => "failed",
+            PaymentEvent::Completed => "completed",
+            PaymentEvent::Refunded => "refunded",
+            PaymentEvent::ChargedBack => "charged_back",
+            PaymentEvent::Cancelled => "cancelled",
+            PaymentEvent::Expired => "expired",
+        }
+    }
+
+    pub fn for_state(state: models::PaymentState) -> Option<Self> {
+        match state {
+            models::PaymentState::OPEN => None,
+            models::PaymentState::AUTHORISED => Some(PaymentEvent::Authorised),
+            models::PaymentState::PAID => Some(PaymentEvent::Paid),
+            models::PaymentState::COMPLETE => Some(PaymentEvent::Completed),
+            models::PaymentState::REFUNDED | models::PaymentState::PARTIALLY_REFUNDED => Some(PaymentEvent::Refunded),
+            models::PaymentState::CHARGED_BACK => Some(PaymentEvent::ChargedBack),
+            models::PaymentState::CANCELLED => Some(PaymentEvent::Cancelled),
+            models::PaymentState::EXPIRED => Some(PaymentEvent::Expired),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct EventPayload {
+    id: uuid::Uuid,
+    #[serde(rename = "type")]
+    event_type: String,
+    timestamp: DateTime<Utc>,
+    payment_id: uuid::Uuid,
+    customer_id: uuid::Uuid,
+    state: models::PaymentState,
+    environment: models::PaymentEnvironment,
+}
+
+pub fn routing_key(event: PaymentEvent, environment: models::PaymentEnvironment) -> String {
+    format!("payment.{}.{}", event.as_str(), match environment {
+        models::PaymentEnvironment::LIVE => "live",
+        models::PaymentEnvironment::TEST => "test",
+    })
+}
+
+pub fn payload(id: &uuid::Uuid, event: PaymentEvent, payment: &models::Payment) -> serde_json::Value {
+    serde_json::to_value(EventPayload {
+        id: id.to_owned(),
+        event_type: format!("payment.{}", event.as_str()),
+        timestamp: Utc::now(),
+        payment_id: payment.id,
+        customer_id: payment.customer_id,
+        state: payment.state,
+        environment: payment.environment,
+    }).unwrap()
+}
+
+pub struct Publisher {
+    _connection: lapin::Connection,
+    channel: lapin::Channel,
+}
+
+impl Publisher {
+    pub async fn connect(url: &str) -> Fallible<Self> {
+        let connection = lapin::Connection::connect(url, lapin::ConnectionProperties::default()).await?;
+        let channel = connection.create_channel().await?;
+        channel.confirm_select(ConfirmSelectOptions::default()).await?;
+        channel.exchange_declare(
+            EXCHANGE,
+            lapin::ExchangeKind::Topic,
+            ExchangeDeclareOptions {
+                durable: true,
+                ..ExchangeDeclareOptions::default()
+            },
+            FieldTable::default(),
+        ).await?;
+
+        Ok(Self {
+            _connection: connection,
+            channel,
+        })
+    }
+
+    pub async fn publish(&self, event: &models::OutboxEvent) -> Fallible<bool> {
+        let confirm = self.channel.basic_publish(
+            EXCHANGE,
+            &event.routing_key,
+            BasicPublishOptions::default(),
+            serde_json::to_vec(&event.payload)?,
+            lapin::BasicProperties::default()
+                .with_content_type("application/json".into())
+                .with_message_id(event.id.to_string().into())
+                .with_delivery_mode(2),
+        ).await?;
+
+        Ok(match confirm.await? {
+            Confirmation::Ack(_) => true,
+            _ => false
+        })
+    }
+}
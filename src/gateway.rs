@@ -0,0 +1,345 @@
+use failure::Fallible;
+use futures::future::LocalBoxFuture;
+
+use crate::models;
+
+#[derive(Clone, Deserialize)]
+pub struct CardDetails {
+    pub name: String,
+    pub exp_month: u32,
+    pub exp_year: u32,
+    pub card_number: String,
+    pub cvc: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct CardToken {
+    pub token: String,
+    pub last_four: String,
+    pub brand: String,
+    pub exp_month: u32,
+    pub exp_year: u32,
+    pub name: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct BillingAddress {
+    pub address_lines: Vec<String>,
+    pub city: String,
+    pub region: String,
+    pub postal_code: String,
+    pub country_code: String,
+    pub phone: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct Shopper {
+    pub name: String,
+    pub email: Option<String>,
+    pub ip_address: String,
+    pub user_agent: String,
+    pub accept_header: String,
+    pub session_id: String,
+    pub billing_address: Option<BillingAddress>,
+}
+
+#[derive(Clone)]
+pub struct AuthoriseRequest {
+    pub payment: models::Payment,
+    pub items: Vec<models::PaymentItem>,
+    pub amount: i64,
+    pub source: String,
+    pub cvc: Option<String>,
+    pub shopper: Shopper,
+}
+
+impl AuthoriseRequest {
+    pub fn description(&self) -> String {
+        self.items.iter().map(|i| i.title.clone()).collect::<Vec<String>>().join(", ")
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ThreedsResponse {
+    pub response_code: Option<String>,
+    pub amount: Option<i64>,
+    pub shopper: Shopper,
+}
+
+#[derive(Clone, Debug)]
+pub enum AuthoriseResult {
+    Success {
+        order_code: String,
+        payment_method: Option<String>,
+    },
+    ThreedsRequired {
+        order_code: String,
+        redirect_url: String,
+        one_time_token: String,
+    },
+    ActionRequired {
+        order_code: String,
+        client_secret: String,
+    },
+    Failed,
+    Unknown,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum OrderStatus {
+    Pending,
+    Authorised,
+    Paid,
+    Settled,
+    Cancelled,
+    Expired,
+    Failed,
+    Refunded,
+    PartiallyRefunded,
+    ChargedBack,
+    Unknown,
+}
+
+impl OrderStatus {
+    pub fn next_payment_state(&self, current: models::PaymentState) -> Option<models::PaymentState> {
+        use models::PaymentState::*;
+
+        match (self, current) {
+            (OrderStatus::Authorised, OPEN) => Some(AUTHORISED),
+            (OrderStatus::Paid, OPEN) | (OrderStatus::Settled, OPEN) |
+            (OrderStatus::Paid, AUTHORISED) | (OrderStatus::Settled, AUTHORISED) => Some(PAID),
+            (OrderStatus::Cancelled, AUTHORISED) | (OrderStatus::Expired, AUTHORISED) => Some(CANCELLED),
+            (OrderStatus::PartiallyRefunded, PAID) | (OrderStatus::PartiallyRefunded, COMPLETE) => Some(PARTIALLY_REFUNDED),
+            (OrderStatus::Refunded, PAID) | (OrderStatus::Refunded, COMPLETE) |
+            (OrderStatus::Refunded, PARTIALLY_REFUNDED) => Some(REFUNDED),
+            (OrderStatus::ChargedBack, OPEN) | (OrderStatus::ChargedBack, AUTHORISED) | (OrderStatus::ChargedBack, PAID) |
+            (OrderStatus::ChargedBack, COMPLETE) | (OrderStatus::ChargedBack, PARTIALLY_REFUNDED) |
+            (OrderStatus::ChargedBack, REFUNDED) => Some(CHARGED_BACK),
+            _ => None
+        }
+    }
+}
+
+pub trait PaymentGateway {
+    fn name(&self) -> &'static str;
+
+    fn authorisation_window(&self) -> chrono::Duration;
+
+    fn tokenise_card<'a>(&'a self, environment: models::PaymentEnvironment, card: &'a CardDetails) -> LocalBoxFuture<'a, Fallible<CardToken>>;
+
+    fn delete_token<'a>(&'a self, environment: models::PaymentEnvironment, token: &'a str) -> LocalBoxFuture<'a, Fallible<()>>;
+
+    fn authorise<'a>(&'a self, request: &'a AuthoriseRequest) -> LocalBoxFuture<'a, Fallible<AuthoriseResult>>;
+
+    fn continue_threeds<'a>(&'a self, payment: &'a models::Payment, order_code: &'a str, response: &'a ThreedsResponse) -> LocalBoxFuture<'a, Fallible<AuthoriseResult>>;
+
+    fn capture<'a>(&'a self, payment: &'a models::Payment, order_code: &'a str, amount: Option<i64>) -> LocalBoxFuture<'a, Fallible<()>>;
+
+    fn cancel<'a>(&'a self, payment: &'a models::Payment, order_code: &'a str) -> LocalBoxFuture<'a, Fallible<()>>;
+
+    fn refund<'a>(&'a self, payment: &'a models::Payment, order_code: &'a str, amount: Option<i64>) -> LocalBoxFuture<'a, Fallible<()>>;
+
+    fn status<'a>(&'a self, payment: &'a models::Payment, order_code: &'a str) -> LocalBoxFuture<'a, Fallible<OrderStatus>>;
+}
+
+fn gateway_name(mock_gateway: bool, name: &str, environment: models::PaymentEnvironment) -> Option<&str> {
+    let mock_allowed = mock_gateway && environment == models::PaymentEnvironment::TEST;
+    match name {
+        "mock" if !mock_allowed => None,
+        _ if mock_allowed => Some("mock"),
+        _ => Some(name)
+    }
+}
+
+pub fn get(state: &crate::config::AppState, name: &str, environment: models::PaymentEnvironment) -> Option<Box<dyn PaymentGateway>> {
+    match gateway_name(state.mock_gateway, name, environment)? {
+        "mock" => Some(Box::new(MockGateway)),
+        "worldpay" => Some(Box::new(crate::worldpay::WorldpayGateway::new(&state.worldpay, &state.db))),
+        "stripe" => Some(Box::new(crate::stripe::StripeGateway::new(&state.stripe, &state.db))),
+        _ => None
+    }
+}
+
+pub fn for_payment(state: &crate::config::AppState, payment: &models::Payment) -> actix_web::Result<Box<dyn PaymentGateway>> {
+    match get(state, &payment.gateway, payment.environment) {
+        Some(g) => Ok(g),
+        None => Err(actix_web::error::ErrorInternalServerError(format!("unknown gateway {}", payment.gateway)))
+    }
+}
+
+pub async fn record_attempt(db: &actix::Addr<crate::db::DbExecutor>, payment: &models::Payment, attempt: crate::db::CreatePaymentAttempt) {
+    match db.send(attempt).await {
+        Ok(Ok(_)) => {}
+        Ok(Err(e)) => error!("Unable to record payment attempt for payment {}: {}", payment.id, e),
+        Err(e) => error!("Unable to record payment attempt for payment {}: {}", payment.id, e),
+    }
+}
+
+pub struct MockGateway;
+
+impl PaymentGateway for MockGateway {
+    fn name(&self) -> &'static str {
+        "mock"
+    }
+
+    fn authorisation_window(&self) -> chrono::Duration {
+        chrono::Duration::days(7)
+    }
+
+    fn tokenise_card<'a>(&'a self, _environment: models::PaymentEnvironment, card: &'a CardDetails) -> LocalBoxFuture<'a, Fallible<CardToken>> {
+        Box::pin(async move {
+            let last_four = card.card_number.chars().rev().take(4).collect::<Vec<_>>().into_iter().rev().collect();
+            Ok(CardToken {
+                token: format!("MOCK_{}", uuid::Uuid::new_v4()),
+                last_four,
+                brand: "MOCK".to_string(),
+                exp_month: card.exp_month,
+                exp_year: card.exp_year,
+                name: card.name.clone(),
+            })
+        })
+    }
+
+    fn delete_token<'a>(&'a self, _environment: models::PaymentEnvironment, _token: &'a str) -> LocalBoxFuture<'a, Fallible<()>> {
+        Box::pin(futures::future::ready(Ok(())))
+    }
+
+    fn authorise<'a>(&'a self, request: &'a AuthoriseRequest) -> LocalBoxFuture<'a, Fallible<AuthoriseResult>> {
+        Box::pin(async move {
+            if request.cvc.as_deref() == Some("000") {
+                return Ok(AuthoriseResult::Failed);
+            }
+            Ok(AuthoriseResult::Success {
+                order_code: format!("MOCK-{}", uuid::Uuid::new_v4()),
+                payment_method: Some("MOCK".to_string()),
+            })
+        })
+    }
+
+    fn continue_threeds<'a>(&'a self, _payment: &'a models::Payment, order_code: &'a str, _response: &'a ThreedsResponse) -> LocalBoxFuture<'a, Fallible<AuthoriseResult>> {
+        Box::pin(futures::future::ready(Ok(AuthoriseResult::Success {
+            order_code: order_code.to_string(),
+            payment_method: Some("MOCK".to_string()),
+        })))
+    }
+
+    fn capture<'a>(&'a self, _payment: &'a models::Payment, _order_code: &'a str, _amount: Option<i64>) -> LocalBoxFuture<'a, Fallible<()>> {
+        Box::pin(futures::future::ready(Ok(())))
+    }
+
+    fn cancel<'a>(&'a self, _payment: &'a models::Payment, _order_code: &'a str) -> LocalBoxFuture<'a, Fallible<()>> {
+        Box::pin(futures::future::ready(Ok(())))
+    }
+
+    fn refund<'a>(&'a self, _payment: &'a models::Payment, _order_code: &'a str, _amount: Option<i64>) -> LocalBoxFuture<'a, Fallible<()>> {
+        Box::pin(futures::future::ready(Ok(())))
+    }
+
+    fn status<'a>(&'a self, _payment: &'a models::Payment, _order_code: &'a str) -> LocalBoxFuture<'a, Fallible<OrderStatus>> {
+        Box::pin(futures::future::ready(Ok(OrderStatus::Paid)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use models::{PaymentEnvironment, PaymentState};
+
+    fn shopper() -> Shopper {
+        Shopper {
+            name: "Test".to_string(),
+            email: None,
+            ip_address: "127.0.0.1".to_string(),
+            user_agent: "test".to_string(),
+            accept_header: "*/*".to_string(),
+            session_id: "test".to_string(),
+            billing_address: None,
+        }
+    }
+
+    fn authorise_request(cvc: Option<&str>) -> AuthoriseRequest {
+        AuthoriseRequest {
+            payment: models::Payment {
+                id: uuid::Uuid::new_v4(),
+                time: chrono::Utc::now().naive_utc(),
+                state: PaymentState::OPEN,
+                customer_id: uuid::Uuid::new_v4(),
+                environment: PaymentEnvironment::TEST,
+                payment_method: None,
+                order_code: None,
+                gateway: "mock".to_string(),
+                authorise_only: false,
+                authorised_until: None,
+                captured_amount: None,
+                callback_url: None,
+                currency: "GBP".to_string(),
+                store_credit_amount: 0,
+                expires_at: None,
+            },
+            items: vec![],
+            amount: 1000,
+            source: "MOCK".to_string(),
+            cvc: cvc.map(|c| c.to_string()),
+            shopper: shopper(),
+        }
+    }
+
+    #[test]
+    fn only_test_payments_use_the_mock_gateway() {
+        assert_eq!(gateway_name(true, "worldpay", PaymentEnvironment::TEST), Some("mock"));
+        assert_eq!(gateway_name(true, "stripe", PaymentEnvironment::LIVE), Some("stripe"));
+        assert_eq!(gateway_name(false, "worldpay", PaymentEnvironment::TEST), Some("worldpay"));
+        assert_eq!(gateway_name(false, "stripe", PaymentEnvironment::LIVE), Some("stripe"));
+    }
+
+    #[test]
+    fn mock_payments_need_the_mock_gateway_enabled() {
+        assert_eq!(gateway_name(true, "mock", PaymentEnvironment::TEST), Some("mock"));
+        assert_eq!(gateway_name(false, "mock", PaymentEnvironment::TEST), None);
+        assert_eq!(gateway_name(true, "mock", PaymentEnvironment::LIVE), None);
+    }
+
+    #[test]
+    fn order_statuses_map_to_payment_states() {
+        assert_eq!(OrderStatus::Authorised.next_payment_state(PaymentState::OPEN), Some(PaymentState::AUTHORISED));
+        assert_eq!(OrderStatus::Settled.next_payment_state(PaymentState::AUTHORISED), Some(PaymentState::PAID));
+        assert_eq!(OrderStatus::Paid.next_payment_state(PaymentState::OPEN), Some(PaymentState::PAID));
+        assert_eq!(OrderStatus::Expired.next_payment_state(PaymentState::AUTHORISED), Some(PaymentState::CANCELLED));
+        assert_eq!(OrderStatus::PartiallyRefunded.next_payment_state(PaymentState::PAID), Some(PaymentState::PARTIALLY_REFUNDED));
+        assert_eq!(OrderStatus::Refunded.next_payment_state(PaymentState::PARTIALLY_REFUNDED), Some(PaymentState::REFUNDED));
+        assert_eq!(OrderStatus::Authorised.next_payment_state(PaymentState::PAID), None);
+        assert_eq!(OrderStatus::Pending.next_payment_state(PaymentState::OPEN), None);
+        assert_eq!(OrderStatus::Failed.next_payment_state(PaymentState::OPEN), None);
+        assert_eq!(OrderStatus::Unknown.next_payment_state(PaymentState::AUTHORISED), None);
+    }
+
+    #[test]
+    fn mock_gateway_authorises_payments() {
+        actix_rt::System::new("test").block_on(async {
+            match MockGateway.authorise(&authorise_request(Some("123"))).await.unwrap() {
+                AuthoriseResult::Success { order_code, .. } => assert!(order_code.starts_with("MOCK-")),
+                r => panic!("unexpected result {:?}", r)
+            }
+            match MockGateway.authorise(&authorise_request(Some("000"))).await.unwrap() {
+                AuthoriseResult::Failed => {}
+                r => panic!("unexpected result {:?}", r)
+            }
+        });
+    }
+
+    #[test]
+    fn mock_gateway_tokenises_cards() {
+        actix_rt::System::new("test").block_on(async {
+            let card = CardDetails {
+                name: "Test".to_string(),
+                exp_month: 12,
+                exp_year: 2030,
+                card_number: "4444333322221111".to_string(),
+                cvc: "123".to_string(),
+            };
+            let token = MockGateway.tokenise_card(PaymentEnvironment::TEST, &card).await.unwrap();
+            assert_eq!(token.last_four, "1111");
+            assert!(token.token.starts_with("MOCK_"));
+        });
+    }
+}
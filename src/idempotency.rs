@@ -0,0 +1,109 @@
+use actix_web::{HttpRequest, HttpResponse};
+use chrono::prelude::*;
+use crypto::mac::Mac;
+
+pub const HEADER: &str = "Idempotency-Key";
+const MAX_KEY_LENGTH: usize = 255;
+const LEASE_MINUTES: i64 = 5;
+
+#[derive(Debug, Clone)]
+pub enum Outcome {
+    New,
+    InProgress,
+    Mismatch,
+    Replay(i32, serde_json::Value),
+}
+
+fn key(req: &HttpRequest) -> actix_web::Result<Option<String>> {
+    match req.headers().get(HEADER) {
+        Some(v) => match v.to_str().map(str::trim) {
+            Ok(k) if !k.is_empty() && k.len() <= MAX_KEY_LENGTH => Ok(Some(k.to_owned())),
+            _ => Err(actix_web::error::ErrorBadRequest("invalid Idempotency-Key"))
+        },
+        None => Ok(None)
+    }
+}
+
+pub fn request_hash(secret: &[u8], body: &serde_json::Value) -> String {
+    let mut hmac = crypto::hmac::Hmac::new(crypto::sha2::Sha256::new(), secret);
+    hmac.input(&serde_json::to_vec(body).unwrap());
+    hmac.result().code().iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[derive(Clone, Default)]
+pub struct Attempt(std::rc::Rc<std::cell::Cell<bool>>);
+
+impl Attempt {
+    pub fn charging(&self) {
+        self.0.set(true);
+    }
+
+    fn charged(&self) -> bool {
+        self.0.get()
+    }
+}
+
+async fn complete(db: &actix::Addr<crate::db::DbExecutor>, scope: &str, key: &str, status: u16, body: &serde_json::Value) {
+    match db.send(crate::db::CompleteIdempotentRequest::new(scope, key, status as i32, body)).await {
+        Ok(Ok(_)) => {}
+        Ok(Err(e)) => warn!("Unable to store response for idempotency key {}: {}", key, e),
+        Err(e) => warn!("Unable to store response for idempotency key {}: {}", key, e),
+    }
+}
+
+pub async fn run<F, Fut, T>(req: &HttpRequest, data: &crate::config::AppState, scope: &str, body: &serde_json::Value, f: F) -> actix_web::Result<HttpResponse>
+    where F: FnOnce(Attempt) -> Fut, Fut: std::future::Future<Output=actix_web::Result<T>>, T: serde::Serialize {
+    let db = &data.db;
+    let key = match key(req)? {
+        Some(k) => k,
+        None => return Ok(HttpResponse::Ok().json(f(Attempt::default()).await?))
+    };
+
+    let now = Utc::now().naive_utc();
+    match match db.send(crate::db::BeginIdempotentRequest::new(
+        scope, &key, &request_hash(&data.idempotency_key_secret, body), &now, &(now + chrono::Duration::minutes(LEASE_MINUTES)), &(now + chrono::Duration::hours(24)),
+    )).await {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Ok(Outcome::New) => {}
+        Ok(Outcome::InProgress) => return Err(actix_web::error::ErrorConflict("a request with this Idempotency-Key is in progress")),
+        Ok(Outcome::Mismatch) => return Err(actix_web::error::ErrorUnprocessableEntity("Idempotency-Key has already been used for a different request")),
+        Ok(Outcome::Replay(status, body)) => return Ok(
+            HttpResponse::build(actix_web::http::StatusCode::from_u16(status as u16).unwrap_or(actix_web::http::StatusCode::OK))
+                .header("Idempotent-Replayed", "true")
+                .json(body)
+        ),
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    }
+
+    let attempt = Attempt::default();
+    let response = match f(attempt.clone()).await {
+        Ok(r) => r,
+        Err(e) => {
+            if attempt.charged() {
+                let status = e.as_response_error().error_response().status();
+                complete(db, scope, &key, status.as_u16(), &serde_json::json!({
+                    "error": e.to_string()
+                })).await;
+            } else {
+                match db.send(crate::db::ReleaseIdempotencyKey::new(scope, &key)).await {
+                    Ok(Ok(_)) => {}
+                    Ok(Err(err)) => warn!("Unable to release idempotency key {}: {}", key, err),
+                    Err(err) => warn!("Unable to release idempotency key {}: {}", key, err),
+                }
+            }
+            return Err(e);
+        }
+    };
+    let response = match serde_json::to_value(&response) {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    };
+
+    complete(db, scope, &key, 200, &response).await;
+
+    Ok(HttpResponse::Ok().json(response))
+}
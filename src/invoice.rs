@@ -0,0 +1,159 @@
+use chrono::prelude::*;
+use failure::Fallible;
+use printpdf::{BuiltinFont, IndirectFontRef, Mm, PdfDocument, PdfLayerReference};
+
+use crate::models;
+
+const PAGE_WIDTH: f64 = 210.0;
+const PAGE_HEIGHT: f64 = 297.0;
+const MARGIN: f64 = 20.0;
+
+pub fn tax_rate_label(category: models::TaxCategory, rate: i32) -> String {
+    match category {
+        models::TaxCategory::EXEMPT => "Exempt".to_string(),
+        _ => crate::tax::format_rate(rate)
+    }
+}
+
+pub fn invoice_number(invoice: &models::Invoice) -> String {
+    format!("INV-{:06}", invoice.id)
+}
+
+pub fn customer_address(user: &crate::keycloak::User) -> Vec<String> {
+    ["street_address", "locality", "region", "postal_code", "country"].iter()
+        .filter_map(|a| user.get_attribute(a))
+        .flat_map(|a| a.lines().map(|l| l.trim().to_string()).collect::<Vec<_>>())
+        .filter(|l| !l.is_empty())
+        .collect()
+}
+
+struct Writer {
+    doc: printpdf::PdfDocumentReference,
+    layer: PdfLayerReference,
+    font: IndirectFontRef,
+    bold_font: IndirectFontRef,
+    y: f64,
+}
+
+impl Writer {
+    fn text(&self, text: &str, size: i64, x: f64, bold: bool) {
+        self.layer.use_text(text, size, Mm(x), Mm(self.y), if bold { &self.bold_font } else { &self.font });
+    }
+
+    fn line(&mut self, height: f64) {
+        self.y -= height;
+        if self.y < MARGIN {
+            let (page, layer) = self.doc.add_page(Mm(PAGE_WIDTH), Mm(PAGE_HEIGHT), "Layer 1");
+            self.layer = self.doc.get_page(page).get_layer(layer);
+            self.y = PAGE_HEIGHT - MARGIN;
+        }
+    }
+}
+
+pub fn render(
+    config: &crate::config::InvoiceConfig, invoice: &models::Invoice, payment: &models::Payment,
+    items: &[models::PaymentItem], user: &crate::keycloak::User,
+) -> Fallible<Vec<u8>> {
+    let number = invoice_number(invoice);
+    let currency = crate::currency::Currency::for_payment(payment);
+    let (doc, page, layer) = PdfDocument::new(&number, Mm(PAGE_WIDTH), Mm(PAGE_HEIGHT), "Layer 1");
+    let font = doc.add_builtin_font(BuiltinFont::Helvetica)
+        .map_err(|e| failure::err_msg(e.to_string()))?;
+    let bold_font = doc.add_builtin_font(BuiltinFont::HelveticaBold)
+        .map_err(|e| failure::err_msg(e.to_string()))?;
+    let layer = doc.get_page(page).get_layer(layer);
+
+    let mut w = Writer {
+        doc,
+        layer,
+        font,
+        bold_font,
+        y: PAGE_HEIGHT - MARGIN,
+    };
+
+    w.text("VAT INVOICE", 20, MARGIN, true);
+    w.line(10.0);
+    w.text(&config.company_name, 12, MARGIN, true);
+    for line in &config.company_address {
+        w.line(5.0);
+        w.text(line, 10, MARGIN, false);
+    }
+    if let Some(email) = &config.company_email {
+        w.line(5.0);
+        w.text(email, 10, MARGIN, false);
+    }
+    if let Some(vat_number) = &config.vat_number {
+        w.line(5.0);
+        w.text(&format!("VAT number: {}", vat_number), 10, MARGIN, false);
+    }
+
+    w.line(12.0);
+    w.text(&format!("Invoice number: {}", number), 10, MARGIN, false);
+    w.line(5.0);
+    w.text(&format!("Invoice date: {}", DateTime::<Utc>::from_utc(invoice.issued, Utc).format("%d/%m/%Y")), 10, MARGIN, false);
+    w.line(5.0);
+    w.text(&format!("Order ID: {}", payment.id), 10, MARGIN, false);
+    w.line(5.0);
+    w.text(&format!("Payment method: {}", payment.payment_method.as_deref().unwrap_or("N/A")), 10, MARGIN, false);
+
+    w.line(12.0);
+    w.text("Bill to", 10, MARGIN, true);
+    w.line(5.0);
+    w.text(&format!(
+        "{} {}", user.first_name.as_deref().unwrap_or(""), user.last_name.as_deref().unwrap_or("")
+    ).trim(), 10, MARGIN, false);
+    for line in customer_address(user) {
+        w.line(5.0);
+        w.text(&line, 10, MARGIN, false);
+    }
+    if let Some(email) = &user.email {
+        w.line(5.0);
+        w.text(email, 10, MARGIN, false);
+    }
+
+    w.line(12.0);
+    w.text("Item", 10, MARGIN, true);
+    w.text("Qty", 10, 100.0, true);
+    w.text("Unit price", 10, 112.0, true);
+    w.text("VAT rate", 10, 137.0, true);
+    w.text("Net", 10, 157.0, true);
+    w.text("VAT", 10, 177.0, true);
+    for item in items {
+        w.line(6.0);
+        w.text(&item.title, 10, MARGIN, false);
+        w.text(&item.quantity.to_string(), 10, 100.0, false);
+        w.text(&currency.format(item.price), 10, 112.0, false);
+        w.text(&tax_rate_label(item.tax_category, item.tax_rate), 10, 137.0, false);
+        w.text(&currency.format(item.net_amount), 10, 157.0, false);
+        w.text(&currency.format(item.tax_amount), 10, 177.0, false);
+    }
+
+    let totals = crate::tax::totals(items);
+    w.line(12.0);
+    for line in crate::tax::breakdown(items) {
+        w.text(&format!("VAT @ {} on {}", tax_rate_label(line.category, line.rate), currency.format_with_code(line.net)), 10, 112.0, false);
+        w.text(&currency.format_with_code(line.tax), 10, 170.0, false);
+        w.line(6.0);
+    }
+    w.text("Total net", 10, 112.0, false);
+    w.text(&currency.format_with_code(totals.net), 10, 170.0, false);
+    w.line(6.0);
+    w.text("Total VAT", 10, 112.0, false);
+    w.text(&currency.format_with_code(totals.tax), 10, 170.0, false);
+    w.line(6.0);
+    w.text("Total", 10, 112.0, true);
+    w.text(&currency.format_with_code(totals.gross), 10, 170.0, true);
+    if let Some(captured) = payment.captured_amount {
+        if captured != totals.gross {
+            w.line(6.0);
+            w.text("Amount paid", 10, 112.0, true);
+            w.text(&currency.format_with_code(captured), 10, 170.0, true);
+        }
+    }
+
+    let mut buf = std::io::BufWriter::new(Vec::new());
+    w.doc.save(&mut buf)
+        .map_err(|e| failure::err_msg(e.to_string()))?;
+    Ok(buf.into_inner()
+        .map_err(|e| failure::err_msg(e.to_string()))?)
+}
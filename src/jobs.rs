@@ -2,8 +2,12 @@ use lettre_email::Email;
 use lettre::Transport;
 use chrono::prelude::*;
 use failure::Fallible;
-use std::sync::{Arc, Mutex};
-use crate::db;
+use std::sync::Arc;
+use futures::lock::Mutex;
+use futures::stream::StreamExt;
+use crate::{db, invoice};
+
+pub const MAX_ATTEMPTS: i32 = 8;
 
 #[derive(Clone)]
 pub struct JobsState {
@@ -11,33 +15,158 @@ pub struct JobsState {
     pub keycloak: crate::keycloak::KeycloakClient,
     pub db: actix::Addr<crate::db::DbExecutor>,
     pub mail_client: lettre::smtp::SmtpClient,
-    pub amqp: Arc<Mutex<amqp::Channel>>,
+    pub notifications: crate::config::NotificationConfig,
+    pub invoice: crate::config::InvoiceConfig,
+    pub amqp_url: String,
+    pub amqp: Arc<Mutex<Option<crate::events::Publisher>>>,
+    pub webhook_key: Vec<u8>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum Job {
+    SendPaymentNotification {
+        payment_id: uuid::Uuid
+    },
+    SendPaymentReceipt {
+        payment_id: uuid::Uuid
+    },
+}
+
+impl Job {
+    pub fn job_type(&self) -> &'static str {
+        match self {
+            Job::SendPaymentNotification { .. } => "send_payment_notification",
+            Job::SendPaymentReceipt { .. } => "send_payment_receipt",
+        }
+    }
+
+    async fn run(self, state: &JobsState) -> Fallible<()> {
+        match self {
+            Job::SendPaymentNotification { payment_id } => send_payment_notification(&payment_id, state).await,
+            Job::SendPaymentReceipt { payment_id } => send_payment_receipt(&payment_id, state).await,
+        }
+    }
+}
+
+fn retry_delay(attempts: i32) -> chrono::Duration {
+    chrono::Duration::seconds(30 * 2i64.pow(attempts.min(12) as u32))
+}
+
+async fn run_next_job(state: &JobsState) -> Fallible<bool> {
+    let now = Utc::now().naive_utc();
+    let job = match state.db.send(db::ClaimJob::new(&now, &(now - chrono::Duration::minutes(15)))).await?? {
+        Some(j) => j,
+        None => return Ok(false)
+    };
+
+    let res = match serde_json::from_value::<Job>(job.payload.clone()) {
+        Ok(j) => j.run(state).await,
+        Err(e) => Err(e.into())
+    };
+
+    match res {
+        Ok(_) => {
+            state.db.send(db::FinishJob::new(&job.id, None, None)).await??;
+        }
+        Err(e) => {
+            let retry_at = if job.attempts < job.max_attempts {
+                Some(Utc::now().naive_utc() + retry_delay(job.attempts))
+            } else {
+                error!("Job {} ({}) failed permanently: {}", job.id, job.job_type, e);
+                None
+            };
+            warn!("Job {} ({}) failed on attempt {}: {}", job.id, job.job_type, job.attempts, e);
+            state.db.send(db::FinishJob::new(&job.id, Some(&e.to_string()), retry_at.as_ref())).await??;
+        }
+    }
+
+    Ok(true)
+}
+
+async fn worker(state: JobsState) {
+    loop {
+        match run_next_job(&state).await {
+            Ok(true) => continue,
+            Ok(false) => {}
+            Err(e) => error!("Unable to run jobs: {}", e)
+        }
+        actix_rt::time::delay_for(std::time::Duration::from_secs(5)).await;
+    }
+}
+
+fn every<F, Fut>(period: std::time::Duration, state: JobsState, name: &'static str, f: F)
+    where F: Fn(JobsState) -> Fut + 'static, Fut: std::future::Future<Output=Fallible<()>> + 'static {
+    actix_rt::spawn(async move {
+        let mut interval = actix_rt::time::interval(period);
+        loop {
+            interval.tick().await;
+            if let Err(e) = f(state.clone()).await {
+                error!("Unable to {}: {}", name, e);
+            }
+        }
+    });
 }
 
-#[derive(Clone, Debug)]
-pub struct CompletePayment {
-    payment_id: uuid::Uuid
+pub fn start(state: JobsState, workers: usize) {
+    for _ in 0..workers {
+        actix_rt::spawn(worker(state.clone()));
+    }
+
+    every(std::time::Duration::from_secs(15 * 60), state.clone(), "expire authorisations", expire_authorisations);
+    every(std::time::Duration::from_secs(5 * 60), state.clone(), "expire payments", expire_payments);
+    every(std::time::Duration::from_secs(60 * 60), state.clone(), "expire idempotency keys", expire_idempotency_keys);
+    every(std::time::Duration::from_secs(5), state.clone(), "publish events", publish_events);
+    every(std::time::Duration::from_secs(30), state, "deliver webhooks", deliver_webhooks);
 }
 
-impl CompletePayment {
-    pub fn new(payment_id: &uuid::Uuid) -> Self {
-        Self {
-            payment_id: payment_id.to_owned()
+fn notification_recipients(config: &crate::config::NotificationConfig, payment: &crate::models::Payment, items: &[crate::models::PaymentItem]) -> Vec<String> {
+    let recipients = match payment.environment {
+        crate::models::PaymentEnvironment::LIVE => &config.live,
+        crate::models::PaymentEnvironment::TEST => &config.test,
+    };
+
+    let mut to = Vec::new();
+    if items.is_empty() {
+        to.extend(recipients.default.iter().cloned());
+    }
+    for item in items {
+        let item_recipients = recipients.item_types.get(&item.item_type.to_lowercase())
+            .unwrap_or(&recipients.default);
+        for r in item_recipients {
+            if !to.contains(r) {
+                to.push(r.clone());
+            }
         }
     }
+
+    to
 }
 
-pub fn send_payment_notification(data: CompletePayment, state: JobsState) -> Fallible<()> {
-    let payment = futures::executor::block_on(state.db.send(db::GetPayment::new(&data.payment_id)))??;
-    let items = futures::executor::block_on(state.db.send(db::GetPaymentItems::new(&payment)))??;
-    let token = futures::executor::block_on(state.oauth.get_access_token())?;
-    let user = futures::executor::block_on(state.keycloak.get_user(payment.customer_id, &token))?;
-    let email_items: String = items.into_iter()
+async fn send_payment_notification(payment_id: &uuid::Uuid, state: &JobsState) -> Fallible<()> {
+    let payment = state.db.send(db::GetPayment::new(payment_id)).await??;
+    let items = state.db.send(db::GetPaymentItems::new(&payment)).await??;
+
+    let recipients = notification_recipients(&state.notifications, &payment, &items);
+    if recipients.is_empty() {
+        info!("No notification recipients for payment {}, not sending notification", payment.id);
+        return Ok(());
+    }
+
+    let token = state.oauth.get_access_token().await?;
+    let user = state.keycloak.get_user(payment.customer_id, &token).await?;
+    let totals = crate::tax::totals(&items);
+    let currency = crate::currency::Currency::for_payment(&payment);
+    let email_items: String = items.iter()
         .map(|item| format!(
-            "- {}x {} @ {} GBP
+            "- {}x {} @ {}
+- VAT: {} ({} net, {} VAT)
 - Item type: {}
 - Item data: {}",
-            item.quantity, item.title, (item.price.0 as f64) / 100.0, item.item_type, item.item_data
+            item.quantity, item.title, currency.format_with_code(item.price),
+            invoice::tax_rate_label(item.tax_category, item.tax_rate),
+            currency.format_with_code(item.net_amount), currency.format_with_code(item.tax_amount),
+            item.item_type, item.item_data
         ))
         .collect::<Vec<_>>()
         .join("\n\n");
@@ -57,6 +186,10 @@ Customer phone: {}
 Items:
 
 {}
+---
+Net: {}
+VAT: {}
+Total: {}
 ",
         payment.id, DateTime::<Utc>::from_utc(payment.time, Utc),
         payment.environment, match &payment.payment_method {
@@ -69,16 +202,242 @@ Items:
             Some(s) => s,
             None => "N/A"
         }, email_items,
+        currency.format_with_code(totals.net), currency.format_with_code(totals.tax), currency.format_with_code(totals.gross),
     );
 
-    let email = Email::builder()
-        .to("q@misell.cymru")
-        .from("noreply@noreply.wewillfixyourpc.co.uk")
-        .subject("New order notification")
-        .text(email_content)
+    let mut subject_context = tera::Context::new();
+    subject_context.insert("payment_id", &payment.id.to_string());
+    subject_context.insert("environment", &payment.environment);
+    subject_context.insert("customer_name", &format!(
+        "{} {}", user.first_name.as_deref().unwrap_or(""), user.last_name.as_deref().unwrap_or("")
+    ).trim());
+    subject_context.insert("item_types", &items.iter()
+        .map(|item| item.item_type.as_str())
+        .collect::<Vec<_>>());
+    let subject = tera::Tera::one_off(&state.notifications.subject, &subject_context, false)
+        .map_err(|e| failure::err_msg(e.to_string()))?;
+
+    let mut email = Email::builder()
+        .from(state.notifications.from.as_str())
+        .subject(subject)
+        .text(email_content);
+    for recipient in recipients {
+        email = email.to(recipient);
+    }
+    if let Some(reply_to) = &state.notifications.reply_to {
+        email = email.reply_to(reply_to.as_str());
+    }
+    let email = email.build()?;
+
+    let mail_client = state.mail_client.clone();
+    actix_web::web::block(move || mail_client.transport().send(email.into())).await
+        .map_err(|e| failure::err_msg(e.to_string()))?;
+
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct ReceiptItem {
+    title: String,
+    quantity: i32,
+    price: String,
+    total: String,
+}
+
+#[derive(Serialize)]
+struct ReceiptVatLine {
+    rate: String,
+    amount: String,
+}
+
+async fn send_payment_receipt(payment_id: &uuid::Uuid, state: &JobsState) -> Fallible<()> {
+    let payment = state.db.send(db::GetPayment::new(payment_id)).await??;
+    let items = state.db.send(db::GetPaymentItems::new(&payment)).await??;
+    let token = state.oauth.get_access_token().await?;
+    let user = state.keycloak.get_user(payment.customer_id, &token).await?;
+
+    let email_address = match &user.email {
+        Some(e) => e.to_owned(),
+        None => {
+            warn!("Not sending receipt for payment {}, customer {} has no email", payment.id, payment.customer_id);
+            return Ok(());
+        }
+    };
+
+    let totals = crate::tax::totals(&items);
+    let currency = crate::currency::Currency::for_payment(&payment);
+
+    let mut context = tera::Context::new();
+    context.insert("currency", currency.code);
+    context.insert("name", user.first_name.as_deref().or(user.username.as_deref()).unwrap_or("there"));
+    context.insert("payment_id", &payment.id.to_string());
+    context.insert("date", &DateTime::<Utc>::from_utc(payment.time, Utc).format("%d/%m/%Y %H:%M").to_string());
+    context.insert("payment_method", payment.payment_method.as_deref().unwrap_or("N/A"));
+    context.insert("items", &items.iter().map(|item| ReceiptItem {
+        title: item.title.clone(),
+        quantity: item.quantity,
+        price: currency.format(item.price),
+        total: currency.format(item.price * item.quantity as i64),
+    }).collect::<Vec<_>>());
+    context.insert("net", &currency.format(totals.net));
+    context.insert("vat_lines", &crate::tax::breakdown(&items).into_iter().map(|line| ReceiptVatLine {
+        rate: invoice::tax_rate_label(line.category, line.rate),
+        amount: currency.format(line.tax),
+    }).collect::<Vec<_>>());
+    context.insert("total", &currency.format(payment.captured_amount.unwrap_or(totals.gross)));
+
+    let html = crate::TERA.render("emails/receipt.html", &context)
+        .map_err(|e| failure::err_msg(e.to_string()))?;
+    let text = crate::TERA.render("emails/receipt.txt", &context)
+        .map_err(|e| failure::err_msg(e.to_string()))?;
+
+    let mut email = Email::builder()
+        .to(email_address)
+        .from(state.notifications.from.as_str())
+        .subject(state.notifications.receipt_subject.as_str())
+        .alternative(html, text);
+    if let Some(reply_to) = &state.notifications.reply_to {
+        email = email.reply_to(reply_to.as_str());
+    }
+
+    // Test payments don't take a number from the invoice series
+    if payment.environment != crate::models::PaymentEnvironment::TEST {
+        let payment_invoice = state.db.send(db::GetOrCreateInvoice::new(&payment.id)).await??;
+        let pdf = invoice::render(&state.invoice, &payment_invoice, &payment, &items, &user)?;
+        email = email.attachment(&pdf, &format!("{}.pdf", invoice::invoice_number(&payment_invoice)), &mime::APPLICATION_PDF)?;
+    }
+
+    let email = email.build()?;
+
+    let mail_client = state.mail_client.clone();
+    actix_web::web::block(move || mail_client.transport().send(email.into())).await
+        .map_err(|e| failure::err_msg(e.to_string()))?;
+
+    Ok(())
+}
+
+async fn expire_authorisations(state: JobsState) -> Fallible<()> {
+    let now = Utc::now().naive_utc();
+    let payments = state.db.send(db::GetExpiredAuthorisations::new(&now)).await??;
+
+    for payment in payments {
+        match state.db.send(db::UpdatePaymentState::new(
+            &payment.id, crate::models::PaymentState::AUTHORISED, crate::models::PaymentState::CANCELLED, None, None, None, &crate::audit::Actor::system(),
+        )).await? {
+            Ok(_) => info!("Authorisation for payment {} has expired", payment.id),
+            Err(crate::state_machine::TransitionError::Database(e)) => return Err(e.into()),
+            Err(e) => warn!("Not cancelling expired authorisation for payment {}: {}", payment.id, e)
+        }
+    }
+
+    Ok(())
+}
+
+async fn expire_payments(state: JobsState) -> Fallible<()> {
+    let now = Utc::now().naive_utc();
+    let payments = state.db.send(db::GetExpiredPayments::new(&now)).await??;
+
+    for payment in payments {
+        if state.db.send(db::ExpirePayment::new(&payment.id, &now, &crate::audit::Actor::system())).await??.is_some() {
+            info!("Payment {} has expired", payment.id);
+        }
+    }
+
+    Ok(())
+}
+
+async fn expire_idempotency_keys(state: JobsState) -> Fallible<()> {
+    let count = state.db.send(db::DeleteExpiredIdempotencyKeys::new(&Utc::now().naive_utc())).await??;
+    if count > 0 {
+        info!("Removed {} expired idempotency keys", count);
+    }
+
+    Ok(())
+}
+
+async fn deliver_webhooks(state: JobsState) -> Fallible<()> {
+    let now = Utc::now().naive_utc();
+    let deliveries = state.db.send(db::GetDueWebhookDeliveries::new(&now, crate::webhooks::MAX_ATTEMPTS)).await??;
+
+    let client = reqwest::Client::builder()
+        .timeout(std::time::Duration::from_secs(crate::webhooks::TIMEOUT_SECS))
         .build()?;
+    futures::stream::iter(deliveries)
+        .for_each_concurrent(crate::webhooks::MAX_CONCURRENT_DELIVERIES, |delivery| {
+            let state = &state;
+            let client = &client;
+            async move {
+                if let Err(e) = deliver_webhook(state, client, &delivery).await {
+                    error!("Unable to deliver webhook {}: {}", delivery.id, e);
+                }
+            }
+        }).await;
+
+    Ok(())
+}
+
+async fn deliver_webhook(state: &JobsState, client: &reqwest::Client, delivery: &crate::models::WebhookDelivery) -> Fallible<()> {
+    let body = serde_json::to_vec(&delivery.payload)?;
+    let timestamp = Utc::now().timestamp();
+    let signature = crate::webhooks::sign_event(&state.webhook_key, timestamp, &body);
 
-    state.mail_client.transport().send(email.into())?;
+    let res = client.post(&delivery.url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header("X-Webhook-Timestamp", timestamp.to_string())
+        .header("X-Webhook-Signature", format!("sha256={}", signature))
+        .body(body)
+        .send().await;
+    let (status_code, error) = match res {
+        Ok(r) => (
+            Some(r.status().as_u16() as i32),
+            if r.status().is_success() { None } else { Some(format!("received status {}", r.status())) }
+        ),
+        Err(e) => (None, Some(e.to_string()))
+    };
+    if let Some(e) = &error {
+        warn!("Webhook delivery {} to {} failed: {}", delivery.id, delivery.url, e);
+    }
+
+    let attempted = Utc::now().naive_utc();
+    state.db.send(db::RecordWebhookDeliveryAttempt::new(
+        delivery,
+        &attempted,
+        status_code,
+        error.as_deref(),
+        &(attempted + crate::webhooks::retry_delay(delivery.attempts + 1)),
+    )).await??;
+
+    Ok(())
+}
+
+async fn publish_events(state: JobsState) -> Fallible<()> {
+    let events = state.db.send(db::GetPendingOutboxEvents::new(100)).await??;
+    if events.is_empty() {
+        return Ok(());
+    }
+
+    let mut publisher = state.amqp.lock().await;
+    if publisher.is_none() {
+        *publisher = Some(crate::events::Publisher::connect(&state.amqp_url).await?);
+    }
+
+    for event in events {
+        match publisher.as_ref().unwrap().publish(&event).await {
+            Ok(true) => {
+                state.db.send(db::UpdateOutboxEvent::new(&event.id, Some(&Utc::now().naive_utc()))).await??;
+            }
+            Ok(false) => {
+                warn!("Broker did not acknowledge event {}", event.id);
+                state.db.send(db::UpdateOutboxEvent::new(&event.id, None)).await??;
+                break;
+            }
+            Err(e) => {
+                *publisher = None;
+                state.db.send(db::UpdateOutboxEvent::new(&event.id, None)).await??;
+                return Err(e);
+            }
+        }
+    }
 
     Ok(())
-}
\ No newline at end of file
+}
@@ -18,7 +18,7 @@ extern crate tera;
 
 use std::collections::HashMap;
 use std::env;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 
 use actix::prelude::*;
 use actix_cors::Cors;
@@ -31,12 +31,30 @@ pub mod oauth;
 pub mod keycloak;
 pub mod db;
 pub mod util;
+pub mod webhooks;
+pub mod events;
+pub mod currency;
+pub mod discounts;
+pub mod invoice;
+pub mod payment_links;
+pub mod store_credit;
+pub mod tax;
+pub mod tenders;
+pub mod audit;
+pub mod idempotency;
+pub mod state_machine;
+pub mod admin_actions;
 pub mod jobs;
+pub mod gateway;
 pub mod worldpay;
+pub mod stripe;
 pub mod apple_pay;
 pub mod config;
 pub mod login_views;
 pub mod payment_views;
+pub mod card_views;
+pub mod discount_views;
+pub mod store_credit_views;
 pub mod admin_views;
 
 include!(concat!(env!("OUT_DIR"), "/generated.rs"));
@@ -79,32 +97,49 @@ fn main()  {
         let keycloak_client = config::keycloak_client();
         let mail_client = config::mail_client();
         let worldpay_config = config::worldpay_config();
-        let amqp_client = config::amqp_client();
+        let stripe_config = config::stripe_config();
+        let invoice_config = config::invoice_config();
 
         let jobs_data = jobs::JobsState {
             db: db_addr.clone(),
             keycloak: keycloak_client.clone(),
             oauth: oauth_client.clone(),
             mail_client,
-            amqp: Arc::new(Mutex::new(amqp_client)),
+            notifications: config::notification_config(),
+            invoice: invoice_config.clone(),
+            amqp_url: config::amqp_url(),
+            amqp: Arc::new(futures::lock::Mutex::new(None)),
+            webhook_key: config::webhook_signing_key(),
         };
 
+        let mock_gateway = config::mock_gateway();
+        if mock_gateway {
+            warn!("MOCK_GATEWAY is enabled, TEST payments will use the mock gateway");
+        }
+
         let data = config::AppState {
             oauth: oauth_client,
             keycloak: keycloak_client,
             worldpay: worldpay_config,
+            stripe: stripe_config,
+            mock_gateway,
+            invoice: invoice_config,
+            payment_link_key: config::payment_link_key(),
+            idempotency_key_secret: config::idempotency_key_secret(),
             apple_pay_client: config::apple_pay_identity(),
             db: db_addr,
             jobs_state: jobs_data,
         };
 
+        jobs::start(data.jobs_state.clone(), 2);
+
         let mut server = HttpServer::new(move || {
             let generated = generate();
 
             App::new()
 //            .middleware(sentry_actix::SentryMiddleware::new())
                 .data(data.clone())
-                .wrap(middleware::Logger::default())
+                .wrap(middleware::Logger::new(r#"%a "%U" %s %b "%{Referer}i" "%{User-Agent}i" %T"#))
                 .wrap(middleware::Compress::default())
                 .wrap(config::cookie_session())
                 .service(actix_web_static_files::ResourceFiles::new(
@@ -135,6 +170,12 @@ fn main()  {
                             .finish())
                         .route(web::get().to(payment_views::get_payment))
                 )
+                .route("/payment/{payment_id}/invoice.pdf", web::get().to(payment_views::get_invoice))
+                .route("/payment/{payment_id}/link/", web::post().to(payment_views::create_payment_link))
+                .route("/payment/link/{token}/", web::get().to(payment_views::open_payment_link))
+                .route("/payment/{payment_id}/refund/", web::post().to(payment_views::refund_payment))
+                .route("/payment/{payment_id}/capture/", web::post().to(payment_views::capture_payment))
+                .route("/payment/{payment_id}/cancel/", web::post().to(payment_views::cancel_payment))
                 .service(
                     web::resource("/payments/")
                         .wrap(Cors::new()
@@ -142,6 +183,41 @@ fn main()  {
                             .finish())
                         .route(web::get().to(payment_views::get_payments))
                 )
+                .service(
+                    web::resource("/jobs/failed/")
+                        .wrap(Cors::new()
+                            .supports_credentials()
+                            .finish())
+                        .route(web::get().to(admin_views::get_failed_jobs))
+                )
+                .service(
+                    web::resource("/jobs/{job_id}/retry/")
+                        .wrap(Cors::new()
+                            .supports_credentials()
+                            .finish())
+                        .route(web::post().to(admin_views::retry_job))
+                )
+                .service(
+                    web::resource("/payment/{payment_id}/state/")
+                        .wrap(Cors::new()
+                            .supports_credentials()
+                            .finish())
+                        .route(web::post().to(admin_views::update_payment_state))
+                )
+                .service(
+                    web::resource("/payment/{payment_id}/events/")
+                        .wrap(Cors::new()
+                            .supports_credentials()
+                            .finish())
+                        .route(web::get().to(admin_views::get_payment_events))
+                )
+                .service(
+                    web::resource("/payment/{payment_id}/items/")
+                        .wrap(Cors::new()
+                            .supports_credentials()
+                            .finish())
+                        .route(web::put().to(admin_views::update_payment_items))
+                )
                 .service(
                     web::resource("/payment/worldpay/{payment_id}/")
                         .wrap(Cors::new()
@@ -149,9 +225,62 @@ fn main()  {
                             .finish())
                         .route(web::post().to(worldpay::process_worldpay_payment))
                 )
-                .route("/payment/3ds/{payment_id}/", web::get().to(worldpay::render_3ds_form))
-                .route("/payment/3ds-complete/{payment_id}/", web::post().to(worldpay::render_3ds_complete))
+                .service(
+                    web::resource("/payment/{payment_id}/discount/")
+                        .wrap(Cors::new()
+                            .supports_credentials()
+                            .finish())
+                        .route(web::post().to(discount_views::apply_discount_code))
+                )
+                .route("/discount-codes/", web::post().to(discount_views::create_discount_code))
+                .service(
+                    web::resource("/payment/{payment_id}/store-credit/")
+                        .wrap(Cors::new()
+                            .supports_credentials()
+                            .finish())
+                        .route(web::post().to(store_credit_views::apply_store_credit))
+                        .route(web::delete().to(store_credit_views::remove_store_credit))
+                )
+                .service(
+                    web::resource("/store-credit/")
+                        .wrap(Cors::new()
+                            .supports_credentials()
+                            .finish())
+                        .route(web::get().to(store_credit_views::get_own_store_credit))
+                )
+                .service(
+                    web::resource("/store-credit/{customer_id}/")
+                        .wrap(Cors::new()
+                            .supports_credentials()
+                            .finish())
+                        .route(web::get().to(store_credit_views::get_store_credit))
+                        .route(web::post().to(store_credit_views::create_store_credit_entry))
+                )
+                .service(
+                    web::resource("/payment/stripe/{payment_id}/")
+                        .wrap(Cors::new()
+                            .supports_credentials()
+                            .finish())
+                        .route(web::post().to(stripe::process_stripe_payment))
+                )
+                .route("/webhooks/worldpay/", web::post().to(worldpay::process_worldpay_webhook))
+                .route("/payment/3ds/{payment_id}/", web::get().to(payment_views::render_3ds_form))
+                .route("/payment/3ds-complete/{payment_id}/", web::post().to(payment_views::render_3ds_complete))
                 .route("/payment/fb/{payment_id}/", web::get().to(payment_views::render_fb_payment))
+                .service(
+                    web::resource("/cards/")
+                        .wrap(Cors::new()
+                            .supports_credentials()
+                            .finish())
+                        .route(web::get().to(card_views::get_cards))
+                )
+                .service(
+                    web::resource("/cards/{card_id}/")
+                        .wrap(Cors::new()
+                            .supports_credentials()
+                            .finish())
+                        .route(web::delete().to(card_views::delete_card))
+                )
                 .service(
                     web::scope("/admin")
                         .default_service(web::route().to(admin_views::render_admin))
@@ -1,17 +1,82 @@
 use uuid::Uuid;
 use std::fmt;
-use super::schema::{payments, payment_items, threeds_datas, cards, payment_tokens};
+use super::schema::{payments, payment_items, threeds_datas, cards, payment_tokens, refunds, payment_attempts, gateway_events, webhook_deliveries, webhook_delivery_attempts, event_outbox, jobs, invoices, discount_codes, discount_code_uses, store_credit_entries, payment_tenders, payment_links, payment_admin_actions, payment_events, idempotency_keys};
 use chrono::prelude::*;
-use diesel::data_types::PgMoney as Pence;
 
 #[derive(Copy, Clone, Debug, Deserialize, Serialize, DbEnum, PartialEq)]
 pub enum PaymentState {
     OPEN,
+    AUTHORISED,
     PAID,
-    COMPLETE
+    COMPLETE,
+    REFUNDED,
+    PARTIALLY_REFUNDED,
+    CHARGED_BACK,
+    CANCELLED,
+    EXPIRED
 }
 
 
+#[derive(Copy, Clone, Debug, Deserialize, Serialize, DbEnum, PartialEq)]
+pub enum JobState {
+    PENDING,
+    RUNNING,
+    COMPLETE,
+    DEAD
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, Serialize, DbEnum, PartialEq, Eq, Hash)]
+pub enum TaxCategory {
+    STANDARD,
+    REDUCED,
+    ZERO,
+    EXEMPT
+}
+
+impl Default for TaxCategory {
+    fn default() -> Self {
+        TaxCategory::STANDARD
+    }
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, Serialize, DbEnum, PartialEq)]
+pub enum DiscountType {
+    FIXED,
+    PERCENTAGE
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, Serialize, DbEnum, PartialEq)]
+pub enum StoreCreditEntryType {
+    ISSUE,
+    ADJUSTMENT,
+    SPEND,
+    REVERSAL,
+    REFUND
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, Serialize, DbEnum, PartialEq)]
+pub enum PaymentEventActorType {
+    SYSTEM,
+    CLIENT,
+    USER,
+    ANONYMOUS,
+    GATEWAY
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, Serialize, DbEnum, PartialEq)]
+pub enum PaymentTenderState {
+    PENDING,
+    SUCCEEDED,
+    FAILED
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, Serialize, DbEnum, PartialEq)]
+pub enum RefundState {
+    PENDING,
+    SUCCEEDED,
+    FAILED
+}
+
 #[derive(Copy, Clone, Debug, Deserialize, Serialize, DbEnum, PartialEq)]
 pub enum PaymentEnvironment {
     TEST,
@@ -35,6 +100,25 @@ pub struct Payment {
     pub customer_id: Uuid,
     pub environment: PaymentEnvironment,
     pub payment_method: Option<String>,
+    pub order_code: Option<String>,
+    pub gateway: String,
+    pub authorise_only: bool,
+    pub authorised_until: Option<NaiveDateTime>,
+    pub captured_amount: Option<i64>,
+    pub callback_url: Option<String>,
+    pub currency: String,
+    pub store_credit_amount: i64,
+    pub expires_at: Option<NaiveDateTime>,
+}
+
+impl Payment {
+    pub fn is_expired(&self, now: &NaiveDateTime) -> bool {
+        match self.state {
+            PaymentState::EXPIRED => true,
+            PaymentState::OPEN => self.expires_at.map(|e| e <= *now).unwrap_or(false),
+            _ => false
+        }
+    }
 }
 
 #[derive(Clone, Debug, Insertable)]
@@ -44,7 +128,27 @@ pub struct NewPayment<'a> {
     pub time: &'a NaiveDateTime,
     pub state: PaymentState,
     pub customer_id: &'a Uuid,
-    pub environment:PaymentEnvironment
+    pub environment:PaymentEnvironment,
+    pub authorise_only: bool,
+    pub callback_url: Option<&'a str>,
+    pub currency: &'a str,
+    pub expires_at: Option<&'a NaiveDateTime>,
+}
+
+#[derive(Clone, Debug, AsChangeset)]
+#[table_name="payments"]
+pub struct PaymentStateChangeset<'a> {
+    pub state: PaymentState,
+    pub payment_method: Option<&'a str>,
+    pub order_code: Option<&'a str>,
+    pub gateway: Option<&'a str>,
+}
+
+#[derive(Clone, Debug, AsChangeset)]
+#[table_name="payments"]
+pub struct PaymentGatewayChangeset<'a> {
+    pub order_code: Option<&'a str>,
+    pub gateway: Option<&'a str>,
 }
 
 #[derive(Queryable, Identifiable, Associations, AsChangeset, Clone, Debug, PartialEq)]
@@ -56,7 +160,11 @@ pub struct PaymentItem {
     pub item_data: serde_json::Value,
     pub title: String,
     pub quantity: i32,
-    pub price: Pence
+    pub price: i64,
+    pub tax_category: TaxCategory,
+    pub tax_rate: i32,
+    pub net_amount: i64,
+    pub tax_amount: i64,
 }
 
 #[derive(Clone, Debug, Insertable)]
@@ -68,7 +176,11 @@ pub struct NewPaymentItem<'a> {
     pub item_data: &'a serde_json::Value,
     pub title: &'a str,
     pub quantity: i32,
-    pub price: &'a Pence
+    pub price: i64,
+    pub tax_category: TaxCategory,
+    pub tax_rate: i32,
+    pub net_amount: i64,
+    pub tax_amount: i64,
 }
 
 #[derive(Queryable, Identifiable, Associations, AsChangeset, Clone, Debug, PartialEq)]
@@ -91,14 +203,317 @@ pub struct NewThreedsData<'a> {
     pub order_id: &'a str,
 }
 
+#[derive(Queryable, Identifiable, Associations, Clone, Debug, PartialEq)]
+#[belongs_to(Payment)]
+pub struct PaymentAttempt {
+    pub id: i64,
+    pub payment_id: Uuid,
+    pub gateway: String,
+    pub order_code: Option<String>,
+    pub payment_status: Option<String>,
+    pub card_issuer: Option<String>,
+    pub masked_pan: Option<String>,
+    pub timestamp: NaiveDateTime,
+    pub raw_response: serde_json::Value,
+}
+
+#[derive(Clone, Debug, Insertable)]
+#[table_name="payment_attempts"]
+pub struct NewPaymentAttempt<'a> {
+    pub payment_id: &'a Uuid,
+    pub gateway: &'a str,
+    pub order_code: Option<&'a str>,
+    pub payment_status: Option<&'a str>,
+    pub card_issuer: Option<&'a str>,
+    pub masked_pan: Option<&'a str>,
+    pub raw_response: &'a serde_json::Value,
+}
+
+#[derive(Queryable, Identifiable, Clone, Debug, PartialEq)]
+pub struct GatewayEvent {
+    pub id: i64,
+    pub gateway: String,
+    pub order_code: String,
+    pub event_status: String,
+    pub payment_id: Option<Uuid>,
+    pub timestamp: NaiveDateTime,
+    pub raw_body: serde_json::Value,
+}
+
+#[derive(Clone, Debug, Insertable)]
+#[table_name="gateway_events"]
+pub struct NewGatewayEvent<'a> {
+    pub gateway: &'a str,
+    pub order_code: &'a str,
+    pub event_status: &'a str,
+    pub payment_id: Option<&'a Uuid>,
+    pub raw_body: &'a serde_json::Value,
+}
+
+#[derive(Queryable, Identifiable, Associations, Clone, Debug, PartialEq)]
+#[belongs_to(Payment)]
+#[table_name="webhook_deliveries"]
+pub struct WebhookDelivery {
+    pub id: Uuid,
+    pub payment_id: Uuid,
+    pub url: String,
+    pub event_type: String,
+    pub payload: serde_json::Value,
+    pub created: NaiveDateTime,
+    pub attempts: i32,
+    pub next_attempt: NaiveDateTime,
+    pub delivered: Option<NaiveDateTime>,
+}
+
+#[derive(Clone, Debug, Insertable)]
+#[table_name="webhook_deliveries"]
+pub struct NewWebhookDelivery<'a> {
+    pub id: &'a Uuid,
+    pub payment_id: &'a Uuid,
+    pub url: &'a str,
+    pub event_type: &'a str,
+    pub payload: &'a serde_json::Value,
+}
+
+#[derive(Clone, Debug, Insertable)]
+#[table_name="webhook_delivery_attempts"]
+pub struct NewWebhookDeliveryAttempt<'a> {
+    pub delivery_id: &'a Uuid,
+    pub timestamp: &'a NaiveDateTime,
+    pub status_code: Option<i32>,
+    pub error: Option<&'a str>,
+}
+
+#[derive(Queryable, Identifiable, Associations, Clone, Debug, PartialEq)]
+#[belongs_to(Payment)]
+#[table_name="event_outbox"]
+pub struct OutboxEvent {
+    pub id: Uuid,
+    pub payment_id: Uuid,
+    pub routing_key: String,
+    pub payload: serde_json::Value,
+    pub created: NaiveDateTime,
+    pub attempts: i32,
+    pub published: Option<NaiveDateTime>,
+}
+
+#[derive(Clone, Debug, Insertable)]
+#[table_name="event_outbox"]
+pub struct NewOutboxEvent<'a> {
+    pub id: &'a Uuid,
+    pub payment_id: &'a Uuid,
+    pub routing_key: &'a str,
+    pub payload: &'a serde_json::Value,
+}
+
+#[derive(Queryable, Identifiable, Clone, Debug, PartialEq)]
+pub struct Job {
+    pub id: Uuid,
+    pub job_type: String,
+    pub payload: serde_json::Value,
+    pub state: JobState,
+    pub attempts: i32,
+    pub max_attempts: i32,
+    pub run_at: NaiveDateTime,
+    pub last_error: Option<String>,
+    pub created: NaiveDateTime,
+    pub updated: NaiveDateTime,
+}
+
+#[derive(Clone, Debug, Insertable)]
+#[table_name="jobs"]
+pub struct NewJob<'a> {
+    pub id: &'a Uuid,
+    pub job_type: &'a str,
+    pub payload: &'a serde_json::Value,
+    pub max_attempts: i32,
+}
+
+#[derive(Queryable, Identifiable, Clone, Debug, PartialEq)]
+pub struct DiscountCode {
+    pub id: Uuid,
+    pub code: String,
+    pub discount_type: DiscountType,
+    pub amount: i64,
+    pub currency: Option<String>,
+    pub valid_from: Option<NaiveDateTime>,
+    pub valid_until: Option<NaiveDateTime>,
+    pub max_uses: Option<i32>,
+    pub max_uses_per_customer: Option<i32>,
+    pub item_types: Option<Vec<String>>,
+    pub created: NaiveDateTime,
+}
+
+#[derive(Clone, Debug, Insertable)]
+#[table_name="discount_codes"]
+pub struct NewDiscountCode<'a> {
+    pub id: &'a Uuid,
+    pub code: &'a str,
+    pub discount_type: DiscountType,
+    pub amount: i64,
+    pub currency: Option<&'a str>,
+    pub valid_from: Option<&'a NaiveDateTime>,
+    pub valid_until: Option<&'a NaiveDateTime>,
+    pub max_uses: Option<i32>,
+    pub max_uses_per_customer: Option<i32>,
+    pub item_types: Option<Vec<String>>,
+}
+
+#[derive(Clone, Debug, Insertable)]
+#[table_name="discount_code_uses"]
+pub struct NewDiscountCodeUse<'a> {
+    pub discount_code_id: &'a Uuid,
+    pub payment_id: &'a Uuid,
+    pub customer_id: &'a Uuid,
+}
+
+#[derive(Queryable, Identifiable, Associations, Clone, Debug, PartialEq)]
+#[belongs_to(Payment)]
+pub struct PaymentAdminAction {
+    pub id: i64,
+    pub payment_id: Uuid,
+    pub actor: Uuid,
+    pub action: String,
+    pub from_state: Option<PaymentState>,
+    pub to_state: Option<PaymentState>,
+    pub details: serde_json::Value,
+    pub reason: Option<String>,
+    pub timestamp: NaiveDateTime,
+}
+
+#[derive(Clone, Debug, Insertable)]
+#[table_name="payment_admin_actions"]
+pub struct NewPaymentAdminAction<'a> {
+    pub payment_id: &'a Uuid,
+    pub actor: &'a Uuid,
+    pub action: &'a str,
+    pub from_state: Option<PaymentState>,
+    pub to_state: Option<PaymentState>,
+    pub details: &'a serde_json::Value,
+    pub reason: Option<&'a str>,
+}
+
+#[derive(Queryable, Identifiable, Associations, Clone, Debug, PartialEq)]
+#[belongs_to(Payment)]
+#[table_name="payment_events"]
+pub struct PaymentAuditEvent {
+    pub id: i64,
+    pub payment_id: Uuid,
+    pub actor_type: PaymentEventActorType,
+    pub actor: Option<String>,
+    pub ip_address: Option<String>,
+    pub action: String,
+    pub old_values: Option<serde_json::Value>,
+    pub new_values: Option<serde_json::Value>,
+    pub timestamp: NaiveDateTime,
+}
+
+#[derive(Clone, Debug, Insertable)]
+#[table_name="payment_events"]
+pub struct NewPaymentAuditEvent<'a> {
+    pub payment_id: &'a Uuid,
+    pub actor_type: PaymentEventActorType,
+    pub actor: Option<&'a str>,
+    pub ip_address: Option<&'a str>,
+    pub action: &'a str,
+    pub old_values: Option<&'a serde_json::Value>,
+    pub new_values: Option<&'a serde_json::Value>,
+}
+
+#[derive(Queryable, Identifiable, Associations, Clone, Debug, PartialEq)]
+#[belongs_to(Payment)]
+pub struct PaymentLink {
+    pub id: Uuid,
+    pub payment_id: Uuid,
+    pub expires_at: NaiveDateTime,
+    pub used_at: Option<NaiveDateTime>,
+    pub created: NaiveDateTime,
+}
+
+#[derive(Clone, Debug, Insertable)]
+#[table_name="payment_links"]
+pub struct NewPaymentLink<'a> {
+    pub id: &'a Uuid,
+    pub payment_id: &'a Uuid,
+    pub expires_at: &'a NaiveDateTime,
+}
+
+#[derive(Queryable, Identifiable, Associations, Clone, Debug, PartialEq)]
+#[belongs_to(Payment)]
+pub struct PaymentTender {
+    pub id: Uuid,
+    pub payment_id: Uuid,
+    pub gateway: String,
+    pub order_code: String,
+    pub payment_method: Option<String>,
+    pub amount: i64,
+    pub state: PaymentTenderState,
+    pub created: NaiveDateTime,
+}
+
+#[derive(Clone, Debug, Insertable)]
+#[table_name="payment_tenders"]
+pub struct NewPaymentTender<'a> {
+    pub id: &'a Uuid,
+    pub payment_id: &'a Uuid,
+    pub gateway: &'a str,
+    pub order_code: &'a str,
+    pub payment_method: Option<&'a str>,
+    pub amount: i64,
+    pub state: PaymentTenderState,
+}
+
+#[derive(Queryable, Identifiable, Clone, Debug, PartialEq)]
+pub struct StoreCreditEntry {
+    pub id: Uuid,
+    pub customer_id: Uuid,
+    pub currency: String,
+    pub amount: i64,
+    pub entry_type: StoreCreditEntryType,
+    pub payment_id: Option<Uuid>,
+    pub reason: Option<String>,
+    pub created_by: Option<Uuid>,
+    pub timestamp: NaiveDateTime,
+}
+
+#[derive(Clone, Debug, Insertable)]
+#[table_name="store_credit_entries"]
+pub struct NewStoreCreditEntry<'a> {
+    pub id: &'a Uuid,
+    pub customer_id: &'a Uuid,
+    pub currency: &'a str,
+    pub amount: i64,
+    pub entry_type: StoreCreditEntryType,
+    pub payment_id: Option<&'a Uuid>,
+    pub reason: Option<&'a str>,
+    pub created_by: Option<&'a Uuid>,
+}
+
+#[derive(Queryable, Identifiable, Clone, Debug, PartialEq)]
+pub struct Invoice {
+    pub id: i64,
+    pub payment_id: Uuid,
+    pub issued: NaiveDateTime,
+}
+
+#[derive(Clone, Debug, Insertable)]
+#[table_name="invoices"]
+pub struct NewInvoice<'a> {
+    pub id: i64,
+    pub payment_id: &'a Uuid,
+}
+
 #[derive(Queryable, Identifiable, AsChangeset, Clone, Debug, PartialEq)]
 pub struct Card {
     pub id: Uuid,
     pub customer_id: Uuid,
-    pub pan: String,
+    pub token: String,
+    pub last_four: String,
+    pub brand: String,
     pub exp_month: i32,
     pub exp_year: i32,
     pub name_on_card: String,
+    pub environment: PaymentEnvironment,
 }
 
 #[derive(Clone, Debug, Insertable)]
@@ -106,10 +521,13 @@ pub struct Card {
 pub struct NewCard<'a> {
     pub id: &'a Uuid,
     pub customer_id: &'a Uuid,
-    pub pan: &'a str,
+    pub token: &'a str,
+    pub last_four: &'a str,
+    pub brand: &'a str,
     pub exp_month: i32,
     pub exp_year: i32,
     pub name_on_card: &'a str,
+    pub environment: PaymentEnvironment,
 }
 
 #[derive(Queryable, Identifiable, AsChangeset, Clone, Debug, PartialEq)]
@@ -118,3 +536,53 @@ pub struct PaymentToken {
     pub name: String,
     pub token: Vec<u8>,
 }
+
+
+#[derive(Queryable, Identifiable, Associations, AsChangeset, Clone, Debug, PartialEq)]
+#[belongs_to(Payment)]
+pub struct Refund {
+    pub id: Uuid,
+    pub payment_id: Uuid,
+    pub time: NaiveDateTime,
+    pub payment_item_id: Option<Uuid>,
+    pub quantity: Option<i32>,
+    pub amount: i64,
+    pub order_code: String,
+    pub state: RefundState,
+}
+
+#[derive(Clone, Debug, Insertable)]
+#[table_name="refunds"]
+pub struct NewRefund<'a> {
+    pub id: &'a Uuid,
+    pub payment_id: &'a Uuid,
+    pub time: &'a NaiveDateTime,
+    pub payment_item_id: Option<&'a Uuid>,
+    pub quantity: Option<i32>,
+    pub amount: i64,
+    pub order_code: &'a str,
+    pub state: RefundState,
+}
+
+#[derive(Queryable, Identifiable, Clone, Debug, PartialEq)]
+#[primary_key(scope, key)]
+pub struct IdempotencyKey {
+    pub scope: String,
+    pub key: String,
+    pub request_hash: String,
+    pub response_status: Option<i32>,
+    pub response_body: Option<serde_json::Value>,
+    pub created: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub locked_until: Option<NaiveDateTime>,
+}
+
+#[derive(Clone, Debug, Insertable)]
+#[table_name="idempotency_keys"]
+pub struct NewIdempotencyKey<'a> {
+    pub scope: &'a str,
+    pub key: &'a str,
+    pub request_hash: &'a str,
+    pub expires_at: &'a NaiveDateTime,
+    pub locked_until: Option<&'a NaiveDateTime>,
+}
\ No newline at end of file
@@ -0,0 +1,43 @@
+use crate::models;
+
+pub const SESSION_KEY: &str = "payment_links";
+
+fn signature(key: &[u8], link: &models::PaymentLink) -> String {
+    crate::webhooks::sign(key, format!("{}:{}:{}", link.id, link.payment_id, link.expires_at.timestamp()).as_bytes())
+}
+
+pub fn token(key: &[u8], link: &models::PaymentLink) -> String {
+    format!("{}.{}", link.id, signature(key, link))
+}
+
+pub fn link_id(token: &str) -> Option<uuid::Uuid> {
+    let mut parts = token.splitn(2, '.');
+    uuid::Uuid::parse_str(parts.next()?).ok()
+}
+
+pub fn verify(key: &[u8], token: &str, link: &models::PaymentLink) -> bool {
+    let expected = self::token(key, link);
+    crypto::util::fixed_time_eq(expected.as_bytes(), token.as_bytes())
+}
+
+pub fn granted(session: &actix_session::Session, payment_id: &uuid::Uuid) -> actix_web::Result<bool> {
+    match session.get::<Vec<uuid::Uuid>>(SESSION_KEY) {
+        Ok(s) => Ok(s.unwrap_or_default().contains(payment_id)),
+        Err(e) => Err(actix_web::error::ErrorInternalServerError(e))
+    }
+}
+
+pub fn grant(session: &actix_session::Session, payment_id: &uuid::Uuid) -> actix_web::Result<()> {
+    let mut payments = match session.get::<Vec<uuid::Uuid>>(SESSION_KEY) {
+        Ok(s) => s.unwrap_or_default(),
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    };
+    if !payments.contains(payment_id) {
+        payments.push(payment_id.to_owned());
+    }
+
+    match session.set(SESSION_KEY, payments) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(actix_web::error::ErrorInternalServerError(e))
+    }
+}
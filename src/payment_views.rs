@@ -9,12 +9,19 @@ struct NewPaymentItemData {
     title: String,
     quantity: i32,
     price: rust_decimal::Decimal,
+    #[serde(default)]
+    tax_category: crate::models::TaxCategory,
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct NewPaymentData {
     environment: crate::models::PaymentEnvironment,
     customer_id: uuid::Uuid,
+    #[serde(default)]
+    authorise_only: bool,
+    callback_url: Option<String>,
+    currency: Option<String>,
+    expires_at: Option<DateTime<Utc>>,
     items: Vec<NewPaymentItemData>,
 }
 
@@ -23,40 +30,82 @@ struct NewPaymentResponseData {
     id: uuid::Uuid,
 }
 
-pub async fn new_payment(token: crate::oauth::BearerAuthToken, data: web::Data<crate::config::AppState>, new_payment: web::Json<NewPaymentData>) -> actix_web::Result<impl actix_web::Responder> {
+pub async fn new_payment(req: HttpRequest, token: crate::oauth::BearerAuthToken, data: web::Data<crate::config::AppState>, body: web::Json<serde_json::Value>) -> actix_web::Result<impl actix_web::Responder> {
+    let introspect = data.oauth.verify_token(token.token(), "create-payments").await?;
+    let actor = crate::audit::Actor::client(&req, &introspect);
+
+    let new_payment: NewPaymentData = match serde_json::from_value(body.clone()) {
+        Ok(p) => p,
+        Err(e) => return Err(actix_web::error::ErrorBadRequest(e))
+    };
+
+    crate::idempotency::run(
+        &req, &data, &format!("new_payment:{}", actor.id.as_deref().unwrap_or_default()), &body,
+        |_| create_payment(&data, new_payment, &actor),
+    ).await
+}
+
+async fn create_payment(data: &web::Data<crate::config::AppState>, new_payment: NewPaymentData, actor: &crate::audit::Actor) -> actix_web::Result<NewPaymentResponseData> {
     let new_payment_items = new_payment.items.to_owned();
 
-    data.oauth.verify_token(token.token(), "create-payments").await?;
+    if let Some(callback_url) = &new_payment.callback_url {
+        match url::Url::parse(callback_url) {
+            Ok(u) if u.scheme() == "https" => {}
+            _ => return Err(actix_web::error::ErrorBadRequest("invalid callback_url"))
+        }
+    }
+
+    let currency = match crate::currency::Currency::lookup(
+        &new_payment.currency.as_deref().unwrap_or(crate::currency::DEFAULT).to_uppercase()
+    ) {
+        Some(c) => c,
+        None => return Err(actix_web::error::ErrorBadRequest("invalid currency"))
+    };
+
+    let now = Utc::now();
+    if let Some(expires_at) = &new_payment.expires_at {
+        if *expires_at <= now {
+            return Err(actix_web::error::ErrorBadRequest("expires_at must be in the future"));
+        }
+    }
 
     let payment_id = uuid::Uuid::new_v4();
 
-    let items: Vec<db::CreatePaymentItem> = new_payment_items.into_iter()
-        .map(|i| db::CreatePaymentItem::new(
+    let mut items: Vec<db::CreatePaymentItem> = vec![];
+    for i in new_payment_items {
+        let price = match currency.to_minor(&i.price) {
+            Some(p) => p,
+            None => return Err(actix_web::error::ErrorBadRequest("invalid price"))
+        };
+        items.push(db::CreatePaymentItem::new(
             &uuid::Uuid::new_v4(),
             &i.item_type,
             &i.item_data,
             &i.title,
             i.quantity,
-            &i.price,
-        ))
-        .collect();
+            price,
+            i.tax_category,
+        ));
+    }
 
     let res = data.db.send(db::CreatePayment::new(
         &payment_id,
-        &Utc::now().naive_utc(),
+        &now.naive_utc(),
         crate::models::PaymentState::OPEN,
         new_payment.environment,
         &new_payment.customer_id,
+        new_payment.authorise_only,
+        new_payment.callback_url.as_deref(),
+        &currency,
+        new_payment.expires_at.map(|e| e.naive_utc()).as_ref(),
         &items,
+        actor,
     )).await?;
 
     match res {
-        Ok(payment) => {
-            let response = NewPaymentResponseData {
-                id: payment.id
-            };
-            Ok(HttpResponse::Ok().json(response))
-        }
+        Ok(payment) => Ok(NewPaymentResponseData {
+            id: payment.id
+        }),
         Err(e) => Err(actix_web::error::ErrorInternalServerError(e)),
     }
 }
@@ -71,6 +120,26 @@ struct PaymentItemResponseData {
     title: String,
     price: f64,
     quantity: i32,
+    tax_category: crate::models::TaxCategory,
+    tax_rate: f64,
+    net_amount: f64,
+    tax_amount: f64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct PaymentTotalsResponseData {
+    net: f64,
+    tax: f64,
+    gross: f64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct PaymentTenderResponseData {
+    id: uuid::Uuid,
+    timestamp: DateTime<Utc>,
+    payment_method: Option<String>,
+    amount: f64,
+    state: crate::models::PaymentTenderState,
 }
 
 #[derive(Clone, Debug, Serialize)]
@@ -89,11 +158,51 @@ struct PaymentResponseData {
     state: crate::models::PaymentState,
     environment: crate::models::PaymentEnvironment,
     customer: PaymentCustomerResponseData,
+    currency: String,
+    currency_exponent: u32,
     items: Vec<PaymentItemResponseData>,
+    totals: PaymentTotalsResponseData,
+    store_credit: f64,
+    tenders: Vec<PaymentTenderResponseData>,
+    outstanding: f64,
+    expires_at: Option<DateTime<Utc>>,
     payment_method: Option<String>,
 }
 
-pub async fn get_payment<'a>(token: crate::oauth::OptionalBearerAuthToken, data: web::Data<crate::config::AppState>, info: web::Path<uuid::Uuid>, session: actix_session::Session) -> actix_web::Result<impl actix_web::Responder> {
+pub async fn check_payment_access(req: &HttpRequest, token: &crate::oauth::OptionalBearerAuthToken, data: &web::Data<crate::config::AppState>, session: &actix_session::Session, payment: &crate::models::Payment) -> actix_web::Result<crate::audit::Actor> {
+    if let Some(t) = token.token() {
+        let introspect = data.oauth.verify_token(t, "view-payments").await?;
+        Ok(crate::audit::Actor::client(req, &introspect))
+    } else if crate::payment_links::granted(session, &payment.id)? {
+        Ok(crate::audit::Actor::anonymous(req))
+    } else {
+        let user_id = match crate::util::user_id_from_session(session, &data.oauth).await? {
+            Some(u) => u,
+            None => return Err(actix_web::error::ErrorUnauthorized(""))
+        };
+
+        if payment.customer_id != user_id {
+            let (_token_introspect, oauth_token) = match crate::util::user_token_from_session(session, &data.oauth).await? {
+                Some(u) => u,
+                None => return Err(actix_web::error::ErrorForbidden(""))
+            };
+            data.oauth.verify_token(&oauth_token.access_token, "view-payments").await?;
+        }
+
+        Ok(crate::audit::Actor::user(req, &user_id))
+    }
+}
+
+async fn record_payment_access(data: &web::Data<crate::config::AppState>, payment: &crate::models::Payment, actor: &crate::audit::Actor) {
+    let since = Utc::now().naive_utc() - chrono::Duration::minutes(crate::audit::VIEW_DEDUPE_MINUTES);
+    match data.db.send(db::RecordPaymentAccess::new(&payment.id, actor, &since)).await {
+        Ok(Ok(_)) => {}
+        Ok(Err(e)) => error!("Unable to record access to payment {}: {}", payment.id, e),
+        Err(e) => error!("Unable to record access to payment {}: {}", payment.id, e),
+    }
+}
+
+pub async fn get_payment<'a>(req: HttpRequest, token: crate::oauth::OptionalBearerAuthToken, data: web::Data<crate::config::AppState>, info: web::Path<uuid::Uuid>, session: actix_session::Session) -> actix_web::Result<impl actix_web::Responder> {
     match match session.get::<uuid::Uuid>("sess_id") {
         Ok(s) => s,
         Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
@@ -116,22 +225,9 @@ pub async fn get_payment<'a>(token: crate::oauth::OptionalBearerAuthToken, data:
         }
     };
 
-    if let Some(t) = token.token() {
-        data.oauth.verify_token(t, "view-payments").await?;
-    } else {
-        let user_id = match crate::util::user_id_from_session(&session, &data.oauth).await? {
-            Some(u) => u,
-            None => return Err(actix_web::error::ErrorUnauthorized(""))
-        };
+    let actor = check_payment_access(&req, &token, &data, &session, &payment).await?;
+    record_payment_access(&data, &payment, &actor).await;
 
-        if payment.customer_id != user_id {
-            let (_token_introspect, oauth_token) = match crate::util::user_token_from_session(&session, &data.oauth).await? {
-                Some(u) => u,
-                None => return Err(actix_web::error::ErrorForbidden(""))
-            };
-            data.oauth.verify_token(&oauth_token.access_token, "view-payments").await?;
-        }
-    }
     let items = match match data.db.send(db::GetPaymentItems::new(&payment)).await {
         Ok(items) => items,
         Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
@@ -140,11 +236,27 @@ pub async fn get_payment<'a>(token: crate::oauth::OptionalBearerAuthToken, data:
         Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
     };
 
+    let tenders = match match data.db.send(db::GetPaymentTenders::new(&payment)).await {
+        Ok(tenders) => tenders,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Ok(tenders) => tenders,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    };
+
     let token = data.oauth.get_access_token().await?;
     let user = data.keycloak.clone().get_user(payment.customer_id, &token).await?;
+    let totals = crate::tax::totals(&items);
+    let currency = crate::currency::Currency::for_payment(&payment);
+    let outstanding = match payment.state {
+        crate::models::PaymentState::OPEN => crate::tenders::outstanding(&payment, &items, &tenders),
+        _ => 0
+    };
 
     let response_data = PaymentResponseData {
         id: payment.id,
+        currency: currency.code.to_string(),
+        currency_exponent: currency.exponent,
         timestamp: DateTime::<Utc>::from_utc(payment.time, Utc),
         state: payment.state,
         environment: payment.environment,
@@ -162,10 +274,31 @@ pub async fn get_payment<'a>(token: crate::oauth::OptionalBearerAuthToken, data:
                 item_type: item.item_type,
                 item_data: item.item_data,
                 title: item.title,
-                price: (item.price.0 as f64) / 100.0,
+                price: currency.to_major(item.price),
                 quantity: item.quantity,
+                tax_category: item.tax_category,
+                tax_rate: (item.tax_rate as f64) / 100.0,
+                net_amount: currency.to_major(item.net_amount),
+                tax_amount: currency.to_major(item.tax_amount),
+            })
+            .collect(),
+        totals: PaymentTotalsResponseData {
+            net: currency.to_major(totals.net),
+            tax: currency.to_major(totals.tax),
+            gross: currency.to_major(totals.gross),
+        },
+        store_credit: currency.to_major(payment.store_credit_amount),
+        tenders: tenders.into_iter()
+            .map(|tender| PaymentTenderResponseData {
+                id: tender.id,
+                timestamp: DateTime::<Utc>::from_utc(tender.created, Utc),
+                payment_method: tender.payment_method,
+                amount: currency.to_major(tender.amount),
+                state: tender.state,
             })
             .collect(),
+        outstanding: currency.to_major(outstanding),
+        expires_at: payment.expires_at.map(|e| DateTime::<Utc>::from_utc(e, Utc)),
     };
 
     Ok(HttpResponse::Ok().json(response_data))
@@ -174,7 +307,89 @@ pub async fn get_payment<'a>(token: crate::oauth::OptionalBearerAuthToken, data:
 #[derive(Deserialize)]
 pub struct GetPaymentsRequest {
    limit: Option<i64>,
-   offset: Option<i64>,
+   cursor: Option<String>,
+   state: Option<crate::models::PaymentState>,
+   environment: Option<crate::models::PaymentEnvironment>,
+   customer_id: Option<uuid::Uuid>,
+   from: Option<DateTime<Utc>>,
+   to: Option<DateTime<Utc>>,
+   payment_method: Option<String>,
+   item_type: Option<String>,
+   search: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct PaymentSummaryResponseData {
+    id: uuid::Uuid,
+    timestamp: DateTime<Utc>,
+    state: crate::models::PaymentState,
+    environment: crate::models::PaymentEnvironment,
+    customer_id: uuid::Uuid,
+    currency: String,
+    payment_method: Option<String>,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct PaymentListResponseData {
+    payments: Vec<PaymentSummaryResponseData>,
+    total: i64,
+    next_cursor: Option<String>,
+}
+
+pub async fn get_invoice(req: HttpRequest, token: crate::oauth::OptionalBearerAuthToken, data: web::Data<crate::config::AppState>, info: web::Path<uuid::Uuid>, session: actix_session::Session) -> actix_web::Result<impl actix_web::Responder> {
+    let payment = match match data.db.send(db::GetPayment::new(&info.into_inner())).await {
+        Ok(payment) => payment,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Ok(payment) => payment,
+        Err(e) => return match e {
+            diesel::result::Error::NotFound => Err(actix_web::error::ErrorNotFound(e)),
+            _ => Err(actix_web::error::ErrorInternalServerError(e))
+        }
+    };
+
+    let actor = check_payment_access(&req, &token, &data, &session, &payment).await?;
+    record_payment_access(&data, &payment, &actor).await;
+
+    match payment.state {
+        crate::models::PaymentState::OPEN | crate::models::PaymentState::AUTHORISED |
+        crate::models::PaymentState::CANCELLED | crate::models::PaymentState::EXPIRED =>
+            return Err(actix_web::error::ErrorConflict("payment has not been taken")),
+        _ => {}
+    }
+    if payment.environment == crate::models::PaymentEnvironment::TEST {
+        return Err(actix_web::error::ErrorConflict("invoices are not issued for test payments"));
+    }
+
+    let items = match match data.db.send(db::GetPaymentItems::new(&payment)).await {
+        Ok(items) => items,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Ok(items) => items,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    };
+
+    let invoice = match match data.db.send(db::GetOrCreateInvoice::new(&payment.id)).await {
+        Ok(invoice) => invoice,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Ok(invoice) => invoice,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    };
+
+    let token = data.oauth.get_access_token().await?;
+    let user = data.keycloak.clone().get_user(payment.customer_id, &token).await?;
+
+    let pdf = match crate::invoice::render(&data.invoice, &invoice, &payment, &items, &user) {
+        Ok(p) => p,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/pdf")
+        .header("Content-Disposition", format!("inline; filename=\"{}.pdf\"", crate::invoice::invoice_number(&invoice)))
+        .body(pdf))
 }
 
 pub async fn get_payments<'a>(data: web::Data<crate::config::AppState>, session: actix_session::Session, query_data: web::Query<GetPaymentsRequest>) -> actix_web::Result<impl actix_web::Responder> {
@@ -185,7 +400,27 @@ pub async fn get_payments<'a>(data: web::Data<crate::config::AppState>, session:
 
     data.oauth.verify_token(&oauth_token.access_token, "view-payments").await?;
 
-    let payments = match match data.db.send(db::GetPayments::new(query_data.offset, query_data.limit)).await {
+    let cursor = match &query_data.cursor {
+        Some(c) => match db::PaymentCursor::decode(c) {
+            Some(c) => Some(c),
+            None => return Err(actix_web::error::ErrorBadRequest("invalid cursor"))
+        },
+        None => None
+    };
+    let limit = query_data.limit.unwrap_or(25).max(1).min(100);
+    let non_empty = |s: &Option<String>| s.as_ref().map(|s| s.trim().to_string()).filter(|s| !s.is_empty());
+    let filter = db::PaymentFilter {
+        state: query_data.state,
+        environment: query_data.environment,
+        customer_id: query_data.customer_id,
+        from: query_data.from.map(|f| f.naive_utc()),
+        to: query_data.to.map(|t| t.naive_utc()),
+        payment_method: non_empty(&query_data.payment_method),
+        item_type: non_empty(&query_data.item_type),
+        search: non_empty(&query_data.search),
+    };
+
+    let (payments, total) = match match data.db.send(db::GetPayments::new(&filter, cursor.as_ref(), limit)).await {
         Ok(p) => p,
         Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
     } {
@@ -193,30 +428,700 @@ pub async fn get_payments<'a>(data: web::Data<crate::config::AppState>, session:
         Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
     };
 
-    let response_data = payments.into_iter().map(|payment| {
-        PaymentResponseData {
-            id: payment.id,
-            timestamp: DateTime::<Utc>::from_utc(payment.time, Utc),
-            state: payment.state,
-            environment: payment.environment,
-            payment_method: payment.payment_method,
-            customer: PaymentCustomerResponseData {
-                id: payment.customer_id,
-                request_name: false,
-                email: None,
-                request_email: false,
-                request_phone: false,
+    let next_cursor = match payments.last() {
+        Some(p) if payments.len() as i64 == limit => Some(db::PaymentCursor::for_payment(p).encode()),
+        _ => None
+    };
+
+    Ok(HttpResponse::Ok().json(PaymentListResponseData {
+        payments: payments.into_iter()
+            .map(|payment| PaymentSummaryResponseData {
+                id: payment.id,
+                timestamp: DateTime::<Utc>::from_utc(payment.time, Utc),
+                state: payment.state,
+                environment: payment.environment,
+                customer_id: payment.customer_id,
+                currency: payment.currency,
+                payment_method: payment.payment_method,
+                expires_at: payment.expires_at.map(|e| DateTime::<Utc>::from_utc(e, Utc)),
+            })
+            .collect(),
+        total,
+        next_cursor,
+    }))
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct RefundItemData {
+    id: uuid::Uuid,
+    quantity: Option<i32>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct RefundPaymentData {
+    amount: Option<rust_decimal::Decimal>,
+    items: Option<Vec<RefundItemData>>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct RefundResponseData {
+    id: uuid::Uuid,
+    timestamp: DateTime<Utc>,
+    item: Option<uuid::Uuid>,
+    quantity: Option<i32>,
+    amount: f64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct RefundPaymentResponseData {
+    state: crate::models::PaymentState,
+    refunds: Vec<RefundResponseData>,
+}
+
+pub async fn refund_payment(req: HttpRequest, token: crate::oauth::BearerAuthToken, data: web::Data<crate::config::AppState>, info: web::Path<uuid::Uuid>, refund_data: web::Json<RefundPaymentData>) -> actix_web::Result<impl actix_web::Responder> {
+    let introspect = data.oauth.verify_token(token.token(), "refund-payments").await?;
+    let actor = crate::audit::Actor::client(&req, &introspect);
+
+    let payment = match match data.db.send(db::GetPayment::new(&info.into_inner())).await {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Ok(r) => r,
+        Err(e) => return match e {
+            diesel::result::Error::NotFound => Err(actix_web::error::ErrorNotFound(e)),
+            _ => Err(actix_web::error::ErrorInternalServerError(e))
+        }
+    };
+
+    match payment.state {
+        crate::models::PaymentState::PAID | crate::models::PaymentState::COMPLETE | crate::models::PaymentState::PARTIALLY_REFUNDED => {}
+        _ => return Err(actix_web::error::ErrorBadRequest("payment cannot be refunded"))
+    }
+    let order_code = match &payment.order_code {
+        Some(c) => c.clone(),
+        None if payment.store_credit_amount > 0 => String::new(),
+        None => return Err(actix_web::error::ErrorBadRequest("payment has no order code"))
+    };
+
+    let items = match match data.db.send(db::GetPaymentItems::new(&payment)).await {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    };
+    let previous_refunds = match match data.db.send(db::GetRefunds::new(&payment)).await {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    };
+
+    let currency = crate::currency::Currency::for_payment(&payment);
+    let total = match payment.captured_amount {
+        Some(a) => a + payment.store_credit_amount,
+        None => items.iter().map(|i| i.price * i.quantity as i64).fold(0, |acc, i| acc + i)
+    };
+    let refunded = previous_refunds.iter().map(|r| r.amount).fold(0, |acc, i| acc + i);
+    let remaining = total - refunded;
+    let now = Utc::now().naive_utc();
+
+    let refunds = match (&refund_data.amount, &refund_data.items) {
+        (Some(_), Some(_)) => return Err(actix_web::error::ErrorBadRequest("only one of amount and items can be given")),
+        (Some(amount), None) => {
+            let amount = match currency.to_minor(amount) {
+                Some(a) => a,
+                None => return Err(actix_web::error::ErrorBadRequest("invalid amount"))
+            };
+            vec![db::CreateRefund::new(&uuid::Uuid::new_v4(), &now, None, None, amount, &order_code)]
+        }
+        (None, Some(refund_items)) => {
+            let item_totals = crate::discounts::discounted_totals(&items);
+            let mut refunds = vec![];
+            for refund_item in refund_items {
+                let item = match items.iter().find(|i| i.id == refund_item.id) {
+                    Some(i) => i,
+                    None => return Err(actix_web::error::ErrorBadRequest("unknown item"))
+                };
+                if item.item_type == crate::discounts::ITEM_TYPE {
+                    return Err(actix_web::error::ErrorBadRequest("discounts cannot be refunded"));
+                }
+                let already_refunded = previous_refunds.iter()
+                    .filter(|r| r.payment_item_id == Some(item.id))
+                    .map(|r| r.quantity.unwrap_or(0))
+                    .fold(0, |acc, i| acc + i);
+                let already_refunded_amount = previous_refunds.iter()
+                    .filter(|r| r.payment_item_id == Some(item.id))
+                    .map(|r| r.amount)
+                    .fold(0, |acc, i| acc + i);
+                let quantity = refund_item.quantity.unwrap_or(item.quantity - already_refunded);
+                if quantity <= 0 || already_refunded + quantity > item.quantity {
+                    return Err(actix_web::error::ErrorBadRequest("invalid quantity"));
+                }
+                let item_total = item_totals.iter()
+                    .find(|t| t.0 == item.id)
+                    .map(|t| t.1)
+                    .unwrap_or(item.price * item.quantity as i64);
+
+                refunds.push(db::CreateRefund::new(
+                    &uuid::Uuid::new_v4(), &now, Some(&item.id), Some(quantity),
+                    crate::discounts::item_refund_amount(item_total, item.quantity, already_refunded, already_refunded_amount, quantity),
+                    &order_code,
+                ));
+            }
+            refunds
+        }
+        (None, None) => vec![db::CreateRefund::new(&uuid::Uuid::new_v4(), &now, None, None, remaining, &order_code)]
+    };
+
+    let amount = refunds.iter().map(|r| r.amount()).fold(0, |acc, i| acc + i);
+    if refunds.is_empty() || amount <= 0 || amount > remaining {
+        return Err(actix_web::error::ErrorBadRequest("invalid refund amount"));
+    }
+
+    let tenders = match match data.db.send(db::GetPaymentTenders::new(&payment)).await {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    };
+
+    let refund_ids = match match data.db.send(db::ReserveRefunds::new(&payment.id, refunded, &refunds)).await {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Ok(r) => r.into_iter().map(|r| r.id).collect::<Vec<_>>(),
+        Err(e) => return Err(e.into())
+    };
+
+    let (to_gateway, to_credit) = crate::store_credit::refund_split(total, payment.store_credit_amount, refunded, amount);
+    let gateway_result: actix_web::Result<()> = async {
+        if to_gateway > 0 && crate::tenders::paid(&tenders) > 0 {
+            let gateway_refunded = refunded.min(total - payment.store_credit_amount);
+            for (tender, tender_amount) in crate::tenders::allocate_refund(&tenders, gateway_refunded, to_gateway) {
+                let gateway = match crate::gateway::get(&data, &tender.gateway, payment.environment) {
+                    Some(g) => g,
+                    None => return Err(actix_web::error::ErrorInternalServerError(format!("unknown gateway {}", tender.gateway)))
+                };
+                gateway.refund(
+                    &payment,
+                    &tender.order_code,
+                    if tender_amount == tender.amount { None } else { Some(tender_amount) },
+                ).await?;
+            }
+        } else if to_gateway > 0 {
+            crate::gateway::for_payment(&data, &payment)?.refund(
+                &payment,
+                &order_code,
+                if to_gateway == total - payment.store_credit_amount { None } else { Some(to_gateway) },
+            ).await?;
+        }
+        Ok(())
+    }.await;
+    if let Err(e) = gateway_result {
+        error!("Refund of payment {} failed: {}", payment.id, e);
+        match data.db.send(db::FailRefunds::new(&refund_ids)).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(err)) => error!("Unable to mark refunds of payment {} as failed: {}", payment.id, err),
+            Err(err) => error!("Unable to mark refunds of payment {} as failed: {}", payment.id, err),
+        }
+        return Err(e);
+    }
+
+    let (updated, refunds) = match match data.db.send(db::RefundPayment::new(&payment.id, &refund_ids, to_credit, &actor)).await {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Ok(r) => r,
+        Err(e) => return Err(e.into())
+    };
+    let state = updated.state;
+
+    Ok(HttpResponse::Ok().json(RefundPaymentResponseData {
+        state,
+        refunds: refunds.into_iter()
+            .map(|refund| RefundResponseData {
+                id: refund.id,
+                timestamp: DateTime::<Utc>::from_utc(refund.time, Utc),
+                item: refund.payment_item_id,
+                quantity: refund.quantity,
+                amount: currency.to_major(refund.amount),
+            })
+            .collect(),
+    }))
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct CapturePaymentData {
+    amount: Option<rust_decimal::Decimal>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct CapturePaymentResponseData {
+    state: crate::models::PaymentState,
+    amount: f64,
+}
+
+async fn get_authorised_payment(data: &web::Data<crate::config::AppState>, payment_id: &uuid::Uuid) -> actix_web::Result<crate::models::Payment> {
+    let payment = match match data.db.send(db::GetPayment::new(payment_id)).await {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Ok(r) => r,
+        Err(e) => return match e {
+            diesel::result::Error::NotFound => Err(actix_web::error::ErrorNotFound(e)),
+            _ => Err(actix_web::error::ErrorInternalServerError(e))
+        }
+    };
+
+    if payment.state != crate::models::PaymentState::AUTHORISED {
+        return Err(actix_web::error::ErrorBadRequest("payment is not authorised"));
+    }
+
+    // The expire_authorisations job moves these to CANCELLED
+    match payment.authorised_until {
+        Some(until) if until < Utc::now().naive_utc() => Err(actix_web::error::ErrorBadRequest("authorisation has expired")),
+        _ => Ok(payment)
+    }
+}
+
+pub async fn capture_payment(req: HttpRequest, token: crate::oauth::BearerAuthToken, data: web::Data<crate::config::AppState>, info: web::Path<uuid::Uuid>, capture_data: web::Json<CapturePaymentData>) -> actix_web::Result<impl actix_web::Responder> {
+    let introspect = data.oauth.verify_token(token.token(), "capture-payments").await?;
+    let actor = crate::audit::Actor::client(&req, &introspect);
+
+    let payment = get_authorised_payment(&data, &info.into_inner()).await?;
+    // Payments covered entirely by store credit have nothing held at the gateway
+    let order_code = match &payment.order_code {
+        Some(c) => Some(c.clone()),
+        None if payment.store_credit_amount > 0 => None,
+        None => return Err(actix_web::error::ErrorBadRequest("payment has no order code"))
+    };
+
+    let items = match match data.db.send(db::GetPaymentItems::new(&payment)).await {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    };
+    let currency = crate::currency::Currency::for_payment(&payment);
+    let total = items.iter().map(|i| i.price * i.quantity as i64).fold(0, |acc, i| acc + i);
+
+    let capturable = total - payment.store_credit_amount;
+
+    let requested = match &capture_data.amount {
+        Some(amount) => match currency.to_minor(amount) {
+            Some(a) => Some(a),
+            None => return Err(actix_web::error::ErrorBadRequest("invalid amount"))
+        },
+        None => None
+    };
+    let amount = match crate::store_credit::capture_amount(total, payment.store_credit_amount, requested) {
+        Some(a) => a,
+        None => return Err(actix_web::error::ErrorBadRequest("invalid capture amount"))
+    };
+
+    if let (Some(order_code), true) = (&order_code, amount > 0) {
+        crate::gateway::for_payment(&data, &payment)?.capture(
+            &payment,
+            order_code,
+            if amount == capturable { None } else { Some(amount) },
+        ).await?;
+    }
+
+    match match data.db.send(db::CapturePayment::new(&payment.id, amount, &actor)).await {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Ok(_) => {}
+        Err(e) => return Err(e.into())
+    };
+
+    Ok(HttpResponse::Ok().json(CapturePaymentResponseData {
+        state: crate::models::PaymentState::PAID,
+        amount: currency.to_major(amount),
+    }))
+}
+
+pub async fn cancel_payment(req: HttpRequest, token: crate::oauth::BearerAuthToken, data: web::Data<crate::config::AppState>, info: web::Path<uuid::Uuid>) -> actix_web::Result<impl actix_web::Responder> {
+    let introspect = data.oauth.verify_token(token.token(), "capture-payments").await?;
+    let actor = crate::audit::Actor::client(&req, &introspect);
+
+    let payment = get_authorised_payment(&data, &info.into_inner()).await?;
+    // Payments covered entirely by store credit have nothing held at the gateway
+    let order_code = match &payment.order_code {
+        Some(c) => Some(c.clone()),
+        None if payment.store_credit_amount > 0 => None,
+        None => return Err(actix_web::error::ErrorBadRequest("payment has no order code"))
+    };
+
+    if let Some(order_code) = &order_code {
+        crate::gateway::for_payment(&data, &payment)?.cancel(&payment, order_code).await?;
+    }
+
+    match match data.db.send(db::UpdatePaymentState::new(
+        &payment.id, crate::models::PaymentState::AUTHORISED, crate::models::PaymentState::CANCELLED, None, None, None, &actor,
+    )).await {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Ok(_) => {}
+        Err(e) => return Err(e.into())
+    };
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub enum AuthoriseStatus {
+    SUCCESS,
+    FAILED,
+    #[serde(rename = "3DS")]
+    THREEDS,
+    #[serde(rename = "REQUIRES_ACTION")]
+    RequiresAction,
+    #[serde(rename = "EXISTING_ACCOUNT")]
+    ExistingAccount,
+    UNKNOWN,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct AuthoriseResponseData {
+    pub state: AuthoriseStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frame: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_secret: Option<String>,
+}
+
+pub async fn tender_amount(data: &web::Data<crate::config::AppState>, payment: &crate::models::Payment, items: &[crate::models::PaymentItem], requested: Option<&rust_decimal::Decimal>) -> actix_web::Result<i64> {
+    if payment.is_expired(&Utc::now().naive_utc()) {
+        return Err(actix_web::error::ErrorGone("payment has expired"));
+    }
+    if payment.state != crate::models::PaymentState::OPEN {
+        return Err(actix_web::error::ErrorBadRequest("payment is not open"));
+    }
+
+    let tenders = match match data.db.send(db::GetPaymentTenders::new(payment)).await {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    };
+    let outstanding = crate::tenders::outstanding(payment, items, &tenders);
+    if outstanding <= 0 {
+        return Err(actix_web::error::ErrorBadRequest("nothing is outstanding on this payment"));
+    }
+
+    let amount = match requested {
+        Some(amount) => match crate::currency::Currency::for_payment(payment).to_minor(amount) {
+            Some(a) if a > 0 && a <= outstanding => a,
+            _ => return Err(actix_web::error::ErrorBadRequest("invalid amount"))
+        },
+        None => outstanding
+    };
+    if payment.authorise_only && amount != outstanding {
+        return Err(actix_web::error::ErrorBadRequest("authorise only payments cannot be split"));
+    }
+
+    Ok(amount)
+}
+
+async fn record_pending_tender(data: &web::Data<crate::config::AppState>, gateway: &dyn crate::gateway::PaymentGateway, payment: &crate::models::Payment, order_code: &str, amount: Option<i64>) -> actix_web::Result<()> {
+    let amount = match amount {
+        Some(a) if !payment.authorise_only => a,
+        _ => return Ok(())
+    };
+
+    match match data.db.send(db::CreatePendingTender::new(&payment.id, gateway.name(), order_code, amount)).await {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Ok(_) => Ok(()),
+        Err(e) => Err(actix_web::error::ErrorInternalServerError(e))
+    }
+}
+
+async fn reverse_tender(data: &web::Data<crate::config::AppState>, gateway: &dyn crate::gateway::PaymentGateway, payment: &crate::models::Payment, order_code: &str, e: crate::tenders::TenderError) -> actix_web::Result<AuthoriseResponseData> {
+    error!("Refunding order {} of payment {}: {}", order_code, payment.id, e);
+    if let Err(err) = gateway.refund(payment, order_code, None).await {
+        error!("Unable to refund order {} of payment {}, refund it manually: {}", order_code, payment.id, err);
+        return Err(actix_web::error::ErrorInternalServerError(err));
+    }
+
+    match match data.db.send(db::FailTender::new(&payment.id, gateway.name(), order_code)).await {
+        Ok(r) => r,
+        Err(err) => return Err(actix_web::error::ErrorInternalServerError(err))
+    } {
+        Ok(_) => {}
+        Err(err) => return Err(actix_web::error::ErrorInternalServerError(err))
+    };
+
+    Err(actix_web::error::ErrorConflict(e))
+}
+
+pub async fn complete_authorisation(req: &HttpRequest, data: &web::Data<crate::config::AppState>, gateway: &dyn crate::gateway::PaymentGateway, payment: &crate::models::Payment, amount: Option<i64>, result: crate::gateway::AuthoriseResult, actor: &crate::audit::Actor) -> actix_web::Result<AuthoriseResponseData> {
+    match result {
+        crate::gateway::AuthoriseResult::Success { order_code, payment_method } => {
+            if payment.authorise_only {
+                match match data.db.send(db::AuthorisePayment::new(
+                    &payment.id,
+                    payment_method.as_deref(),
+                    &order_code,
+                    gateway.name(),
+                    &(Utc::now().naive_utc() + gateway.authorisation_window()),
+                    actor,
+                )).await {
+                    Ok(r) => r,
+                    Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+                } {
+                    Ok(_) => {}
+                    Err(e) => return Err(e.into())
+                };
+            } else {
+                match match data.db.send(db::CompleteTender::new(
+                    &payment.id,
+                    gateway.name(),
+                    &order_code,
+                    payment_method.as_deref(),
+                    amount,
+                    actor,
+                )).await {
+                    Ok(r) => r,
+                    Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+                } {
+                    Ok(_) => {}
+                    Err(crate::tenders::TenderError::Transition(e)) => return Err(e.into()),
+                    Err(e) => return reverse_tender(data, gateway, payment, &order_code, e).await
+                };
+            }
+
+            Ok(AuthoriseResponseData {
+                state: AuthoriseStatus::SUCCESS,
+                frame: None,
+                client_secret: None,
+            })
+        }
+        crate::gateway::AuthoriseResult::ThreedsRequired { order_code, redirect_url, one_time_token } => {
+            record_pending_tender(data, gateway, payment, &order_code, amount).await?;
+            match match data.db.send(db::CreateThreedsData::new(
+                &payment.id,
+                &one_time_token,
+                &redirect_url,
+                &order_code,
+            )).await {
+                Ok(r) => r,
+                Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+            } {
+                Ok(_) => {}
+                Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+            };
+            match match data.db.send(db::UpdatePaymentGateway::new(
+                &payment.id,
+                payment.state,
+                None,
+                gateway.name(),
+            )).await {
+                Ok(r) => r,
+                Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+            } {
+                Ok(_) => {}
+                Err(e) => return Err(e.into())
+            };
+
+            Ok(AuthoriseResponseData {
+                state: AuthoriseStatus::THREEDS,
+                frame: Some(format!("https://{}/payment/3ds/{}/", req.connection_info().host(), payment.id)),
+                client_secret: None,
+            })
+        }
+        crate::gateway::AuthoriseResult::ActionRequired { order_code, client_secret } => {
+            record_pending_tender(data, gateway, payment, &order_code, amount).await?;
+            match match data.db.send(db::UpdatePaymentGateway::new(
+                &payment.id,
+                payment.state,
+                Some(&order_code),
+                gateway.name(),
+            )).await {
+                Ok(r) => r,
+                Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+            } {
+                Ok(_) => {}
+                Err(e) => return Err(e.into())
+            };
+
+            Ok(AuthoriseResponseData {
+                state: AuthoriseStatus::RequiresAction,
+                frame: None,
+                client_secret: Some(client_secret),
+            })
+        }
+        crate::gateway::AuthoriseResult::Failed => {
+            match match data.db.send(db::CreatePaymentEvent::new(&payment.id, crate::events::PaymentEvent::Failed)).await {
+                Ok(r) => r,
+                Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+            } {
+                Ok(_) => {}
+                Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+            };
+
+            Ok(AuthoriseResponseData {
+                state: AuthoriseStatus::FAILED,
+                frame: None,
+                client_secret: None,
+            })
+        }
+        crate::gateway::AuthoriseResult::Unknown => Ok(AuthoriseResponseData {
+            state: AuthoriseStatus::UNKNOWN,
+            frame: None,
+            client_secret: None,
+        })
+    }
+}
+
+pub async fn render_3ds_form<'a>(req: HttpRequest, data: web::Data<crate::config::AppState>, info: web::Path<uuid::Uuid>) -> actix_web::Result<impl actix_web::Responder> {
+    let payment = match match data.db.send(db::GetPayment::new(&info.into_inner())).await {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Ok(r) => r,
+        Err(e) => return match e {
+            diesel::result::Error::NotFound => Err(actix_web::error::ErrorNotFound(e)),
+            _ => Err(actix_web::error::ErrorInternalServerError(e))
+        }
+    };
+
+    let threeds_data = match match data.db.send(db::GetThreedsData::new(&payment)).await {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Ok(r) => r,
+        Err(e) => return match e {
+            diesel::result::Error::NotFound => Err(actix_web::error::ErrorNotFound(e)),
+            _ => Err(actix_web::error::ErrorInternalServerError(e))
+        }
+    };
+
+    let connection_info = req.connection_info();
+    let mut context = tera::Context::new();
+    context.insert("redirect", &format!("https://{}/payment/3ds-complete/{}/", connection_info.host(), payment.id));
+    context.insert("redirect_url", &threeds_data.redirect_url);
+    context.insert("one_time_3ds_token", &threeds_data.one_time_3ds_token);
+    context.insert("order_id", &threeds_data.order_id);
+
+    match crate::TERA.render("3ds_form.html", &context) {
+        Ok(r) => Ok(HttpResponse::Ok().body(r)),
+        Err(e) => Err(actix_web::error::ErrorInternalServerError(e))
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ThreedsData {
+    #[serde(rename = "MD")]
+    order_id: String,
+    #[serde(rename = "PaRes")]
+    response_code: String,
+}
+
+pub async fn render_3ds_complete<'a>(req: HttpRequest, data: web::Data<crate::config::AppState>, info: web::Path<uuid::Uuid>, session: actix_session::Session, form: web::Form<ThreedsData>) -> actix_web::Result<impl actix_web::Responder> {
+    let sess_id = match match session.get::<uuid::Uuid>("sess_id") {
+        Ok(s) => s,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Some(s) => s.to_string(),
+        None => "".to_string()
+    };
+
+    let payment = match match data.db.send(db::GetPayment::new(&info.into_inner())).await {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Ok(r) => r,
+        Err(e) => return match e {
+            diesel::result::Error::NotFound => Err(actix_web::error::ErrorNotFound(e)),
+            _ => Err(actix_web::error::ErrorInternalServerError(e))
+        }
+    };
+
+    let threeds_data = match match data.db.send(db::GetThreedsData::new(&payment)).await {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Ok(r) => r,
+        Err(e) => return match e {
+            diesel::result::Error::NotFound => Err(actix_web::error::ErrorNotFound(e)),
+            _ => Err(actix_web::error::ErrorInternalServerError(e))
+        }
+    };
+    if threeds_data.order_id != form.order_id {
+        return Err(actix_web::error::ErrorBadRequest("order does not match payment"));
+    }
+
+    match match data.db.send(db::DeleteThreedsData::new(&payment)).await {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Ok(_) => {}
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    };
+
+    let gateway = crate::gateway::for_payment(&data, &payment)?;
+    let response = crate::gateway::ThreedsResponse {
+        response_code: Some(form.response_code.clone()),
+        amount: None,
+        shopper: crate::gateway::Shopper {
+            name: "".to_string(),
+            email: None,
+            ip_address: req.connection_info().remote().unwrap_or("").to_string(),
+            user_agent: req.headers().get(actix_web::http::header::USER_AGENT)
+                .unwrap_or(&actix_web::http::header::HeaderValue::from_static("")).to_str()
+                .unwrap_or("").to_string(),
+            accept_header: req.headers().get(actix_web::http::header::ACCEPT)
+                .unwrap_or(&actix_web::http::header::HeaderValue::from_static("*/*")).to_str()
+                .unwrap_or("*/*").to_string(),
+            session_id: sess_id,
+            billing_address: None,
+        },
+    };
+
+    let actor = crate::audit::Actor::from_session(&req, &session, &data.oauth).await?;
+    let mut context = tera::Context::new();
+    context.insert("payment_id", &payment.id);
+
+    let approved = match gateway.continue_threeds(&payment, &threeds_data.order_id, &response).await {
+        Ok(result) => match complete_authorisation(&req, &data, gateway.as_ref(), &payment, None, result, &actor).await {
+            Ok(r) => match r.state {
+                AuthoriseStatus::SUCCESS => true,
+                _ => false
             },
-            items: vec![]
+            Err(e) => {
+                error!("Unable to complete 3DS authorisation of payment {}: {}", payment.id, e);
+                false
+            }
+        },
+        Err(e) => {
+            error!("3DS continuation of payment {} failed: {}", payment.id, e);
+            false
         }
-    }).collect::<Vec<_>>();
+    };
+    context.insert("threeds_approved", &approved);
 
-    Ok(HttpResponse::Ok().json(response_data))
+    match crate::TERA.render("3ds_complete.html", &context) {
+        Ok(r) => Ok(HttpResponse::Ok().body(r)),
+        Err(e) => Err(actix_web::error::ErrorInternalServerError(e).into())
+    }
 }
 
 async fn render_payment(req: HttpRequest, data: web::Data<crate::config::AppState>, info: web::Path<uuid::Uuid>, query: web::Query<crate::login_views::LoginKey>, session: actix_session::Session, template_name: &str) -> actix_web::Result<impl actix_web::Responder> {
+    let payment_id = info.into_inner();
+    let has_link = crate::payment_links::granted(&session, &payment_id)?;
+
     let user_id = match crate::util::user_id_from_session(&session, &data.oauth).await? {
-        Some(u) => u,
+        Some(u) => Some(u),
+        None if has_link => None,
         None => {
             let mut params: Vec<(&str, String)> = vec![
                 ("next", req.uri().to_string()),
@@ -234,7 +1139,7 @@ async fn render_payment(req: HttpRequest, data: web::Data<crate::config::AppStat
         }
     };
 
-    let payment = match match data.db.send(db::GetPayment::new(&info.into_inner())).await {
+    let payment = match match data.db.send(db::GetPayment::new(&payment_id)).await {
         Ok(r) => r,
         Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
     } {
@@ -245,8 +1150,9 @@ async fn render_payment(req: HttpRequest, data: web::Data<crate::config::AppStat
         }
     };
 
-    let is_users_payment = payment.customer_id == user_id;
-    let is_open_payment = payment.state == crate::models::PaymentState::OPEN;
+    let is_users_payment = has_link || user_id == Some(payment.customer_id);
+    let is_expired_payment = payment.is_expired(&Utc::now().naive_utc());
+    let is_open_payment = payment.state == crate::models::PaymentState::OPEN && !is_expired_payment;
     let is_test = payment.environment != crate::models::PaymentEnvironment::LIVE;
     let accepts = match req.headers().get(actix_web::http::header::ACCEPT) {
         Some(a) => match a.to_str() {
@@ -261,6 +1167,7 @@ async fn render_payment(req: HttpRequest, data: web::Data<crate::config::AppStat
     context.insert("logout_url", &format!("/login/logout/?{}", serde_urlencoded::to_string(&[("next", req.uri().to_string())]).unwrap()));
     context.insert("is_users_payment", &is_users_payment);
     context.insert("is_open_payment", &is_open_payment);
+    context.insert("is_expired_payment", &is_expired_payment);
     context.insert("test", &is_test);
     context.insert("accepts_header", &accepts);
 
@@ -274,6 +1181,120 @@ pub async fn render_fb_payment(req: HttpRequest, data: web::Data<crate::config::
     render_payment(req, data, info, query, session,"fb_payment.html").await
 }
 
+#[derive(Clone, Debug, Deserialize)]
+pub struct CreatePaymentLinkData {
+    expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct PaymentLinkResponseData {
+    url: String,
+    expires_at: DateTime<Utc>,
+}
+
+pub async fn create_payment_link(req: HttpRequest, token: crate::oauth::BearerAuthToken, data: web::Data<crate::config::AppState>, info: web::Path<uuid::Uuid>, link_data: web::Json<CreatePaymentLinkData>) -> actix_web::Result<impl actix_web::Responder> {
+    let introspect = data.oauth.verify_token(token.token(), "create-payments").await?;
+    let actor = crate::audit::Actor::client(&req, &introspect);
+
+    let payment = match match data.db.send(db::GetPayment::new(&info.into_inner())).await {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Ok(r) => r,
+        Err(e) => return match e {
+            diesel::result::Error::NotFound => Err(actix_web::error::ErrorNotFound(e)),
+            _ => Err(actix_web::error::ErrorInternalServerError(e))
+        }
+    };
+
+    let now = Utc::now().naive_utc();
+    if payment.is_expired(&now) {
+        return Err(actix_web::error::ErrorGone("payment has expired"));
+    }
+    if payment.state != crate::models::PaymentState::OPEN {
+        return Err(actix_web::error::ErrorBadRequest("payment is not open"));
+    }
+
+    let mut expires_at = match &link_data.expires_at {
+        Some(e) if e.naive_utc() > now => e.naive_utc(),
+        Some(_) => return Err(actix_web::error::ErrorBadRequest("expires_at must be in the future")),
+        None => now + chrono::Duration::days(7)
+    };
+    if let Some(payment_expires_at) = payment.expires_at {
+        expires_at = expires_at.min(payment_expires_at);
+    }
+
+    let link = match match data.db.send(db::CreatePaymentLink::new(&payment.id, &expires_at, &actor)).await {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    };
+
+    Ok(HttpResponse::Ok().json(PaymentLinkResponseData {
+        url: format!(
+            "https://{}/payment/link/{}/", req.connection_info().host(),
+            crate::payment_links::token(&data.payment_link_key, &link)
+        ),
+        expires_at: DateTime::<Utc>::from_utc(link.expires_at, Utc),
+    }))
+}
+
+pub async fn open_payment_link(req: HttpRequest, data: web::Data<crate::config::AppState>, info: web::Path<String>, session: actix_session::Session) -> actix_web::Result<impl actix_web::Responder> {
+    let token = info.into_inner();
+    let link_id = match crate::payment_links::link_id(&token) {
+        Some(l) => l,
+        None => return Err(actix_web::error::ErrorNotFound(""))
+    };
+
+    let link = match match data.db.send(db::GetPaymentLink::new(&link_id)).await {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Ok(r) => r,
+        Err(e) => return match e {
+            diesel::result::Error::NotFound => Err(actix_web::error::ErrorNotFound(e)),
+            _ => Err(actix_web::error::ErrorInternalServerError(e))
+        }
+    };
+    if !crate::payment_links::verify(&data.payment_link_key, &token, &link) {
+        return Err(actix_web::error::ErrorNotFound(""));
+    }
+
+    let payment = match match data.db.send(db::GetPayment::new(&link.payment_id)).await {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    };
+
+    let now = Utc::now().naive_utc();
+    if payment.is_expired(&now) {
+        return Err(actix_web::error::ErrorGone("payment has expired"));
+    }
+
+    let used = match match data.db.send(db::UsePaymentLink::new(&link.id, &now, &crate::audit::Actor::anonymous(&req))).await {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    };
+    if !used {
+        return Err(actix_web::error::ErrorGone("this link has expired or has already been used"));
+    }
+
+    crate::payment_links::grant(&session, &payment.id)?;
+
+    Ok(
+        HttpResponse::Found()
+            .header(actix_web::http::header::LOCATION, format!("/payment/fb/{}/", payment.id))
+            .finish()
+    )
+}
+
 pub async fn render_login_complete<'a>(data: web::Data<crate::config::AppState>, session: actix_session::Session) -> actix_web::Result<impl actix_web::Responder> {
     let mut context = tera::Context::new();
 
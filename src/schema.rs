@@ -2,10 +2,141 @@ table! {
     cards (id) {
         id -> Uuid,
         customer_id -> Uuid,
-        pan -> Varchar,
+        token -> Varchar,
+        last_four -> Varchar,
+        brand -> Varchar,
         exp_month -> Int4,
         exp_year -> Int4,
         name_on_card -> Varchar,
+        environment -> crate::models::PaymentEnvironmentMapping,
+    }
+}
+
+table! {
+    discount_code_uses (id) {
+        id -> Int8,
+        discount_code_id -> Uuid,
+        payment_id -> Uuid,
+        customer_id -> Uuid,
+        timestamp -> Timestamp,
+    }
+}
+
+table! {
+    discount_codes (id) {
+        id -> Uuid,
+        code -> Varchar,
+        discount_type -> crate::models::DiscountTypeMapping,
+        amount -> Int8,
+        currency -> Nullable<Varchar>,
+        valid_from -> Nullable<Timestamp>,
+        valid_until -> Nullable<Timestamp>,
+        max_uses -> Nullable<Int4>,
+        max_uses_per_customer -> Nullable<Int4>,
+        item_types -> Nullable<Array<Varchar>>,
+        created -> Timestamp,
+    }
+}
+
+table! {
+    event_outbox (id) {
+        id -> Uuid,
+        payment_id -> Uuid,
+        routing_key -> Varchar,
+        payload -> Jsonb,
+        created -> Timestamp,
+        attempts -> Int4,
+        published -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    gateway_events (id) {
+        id -> Int8,
+        gateway -> Varchar,
+        order_code -> Varchar,
+        event_status -> Varchar,
+        payment_id -> Nullable<Uuid>,
+        timestamp -> Timestamp,
+        raw_body -> Jsonb,
+    }
+}
+
+table! {
+    idempotency_keys (scope, key) {
+        scope -> Varchar,
+        key -> Varchar,
+        request_hash -> Varchar,
+        response_status -> Nullable<Int4>,
+        response_body -> Nullable<Jsonb>,
+        created -> Timestamp,
+        expires_at -> Timestamp,
+        locked_until -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    invoices (id) {
+        id -> Int8,
+        payment_id -> Uuid,
+        issued -> Timestamp,
+    }
+}
+
+table! {
+    jobs (id) {
+        id -> Uuid,
+        job_type -> Varchar,
+        payload -> Jsonb,
+        state -> crate::models::JobStateMapping,
+        attempts -> Int4,
+        max_attempts -> Int4,
+        run_at -> Timestamp,
+        last_error -> Nullable<Varchar>,
+        created -> Timestamp,
+        updated -> Timestamp,
+    }
+}
+
+table! {
+    payment_admin_actions (id) {
+        id -> Int8,
+        payment_id -> Uuid,
+        actor -> Uuid,
+        action -> Varchar,
+        from_state -> Nullable<crate::models::PaymentStateMapping>,
+        to_state -> Nullable<crate::models::PaymentStateMapping>,
+        details -> Jsonb,
+        reason -> Nullable<Varchar>,
+        timestamp -> Timestamp,
+    }
+}
+
+table! {
+    payment_attempts (id) {
+        id -> Int8,
+        payment_id -> Uuid,
+        gateway -> Varchar,
+        order_code -> Nullable<Varchar>,
+        payment_status -> Nullable<Varchar>,
+        card_issuer -> Nullable<Varchar>,
+        masked_pan -> Nullable<Varchar>,
+        timestamp -> Timestamp,
+        raw_response -> Jsonb,
+    }
+}
+
+table! {
+    payment_events (id) {
+        id -> Int8,
+        payment_id -> Uuid,
+        actor_type -> crate::models::PaymentEventActorTypeMapping,
+        actor -> Nullable<Varchar>,
+        ip_address -> Nullable<Varchar>,
+        action -> Varchar,
+        old_values -> Nullable<Jsonb>,
+        new_values -> Nullable<Jsonb>,
+        timestamp -> Timestamp,
     }
 }
 
@@ -17,7 +148,11 @@ table! {
         item_data -> Jsonb,
         title -> Varchar,
         quantity -> Int4,
-        price -> Money,
+        price -> Int8,
+        tax_category -> crate::models::TaxCategoryMapping,
+        tax_rate -> Int4,
+        net_amount -> Int8,
+        tax_amount -> Int8,
     }
 }
 
@@ -29,6 +164,38 @@ table! {
         customer_id -> Uuid,
         environment -> crate::models::PaymentEnvironmentMapping,
         payment_method -> Nullable<Varchar>,
+        order_code -> Nullable<Varchar>,
+        gateway -> Varchar,
+        authorise_only -> Bool,
+        authorised_until -> Nullable<Timestamp>,
+        captured_amount -> Nullable<Int8>,
+        callback_url -> Nullable<Varchar>,
+        currency -> Varchar,
+        store_credit_amount -> Int8,
+        expires_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    payment_links (id) {
+        id -> Uuid,
+        payment_id -> Uuid,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+        created -> Timestamp,
+    }
+}
+
+table! {
+    payment_tenders (id) {
+        id -> Uuid,
+        payment_id -> Uuid,
+        gateway -> Varchar,
+        order_code -> Varchar,
+        payment_method -> Nullable<Varchar>,
+        amount -> Int8,
+        state -> crate::models::PaymentTenderStateMapping,
+        created -> Timestamp,
     }
 }
 
@@ -40,6 +207,33 @@ table! {
     }
 }
 
+table! {
+    refunds (id) {
+        id -> Uuid,
+        payment_id -> Uuid,
+        time -> Timestamp,
+        payment_item_id -> Nullable<Uuid>,
+        quantity -> Nullable<Int4>,
+        amount -> Int8,
+        order_code -> Varchar,
+        state -> crate::models::RefundStateMapping,
+    }
+}
+
+table! {
+    store_credit_entries (id) {
+        id -> Uuid,
+        customer_id -> Uuid,
+        currency -> Varchar,
+        amount -> Int8,
+        entry_type -> crate::models::StoreCreditEntryTypeMapping,
+        payment_id -> Nullable<Uuid>,
+        reason -> Nullable<Varchar>,
+        created_by -> Nullable<Uuid>,
+        timestamp -> Timestamp,
+    }
+}
+
 table! {
     threeds_datas (id) {
         id -> Int8,
@@ -51,13 +245,68 @@ table! {
     }
 }
 
+table! {
+    webhook_deliveries (id) {
+        id -> Uuid,
+        payment_id -> Uuid,
+        url -> Varchar,
+        event_type -> Varchar,
+        payload -> Jsonb,
+        created -> Timestamp,
+        attempts -> Int4,
+        next_attempt -> Timestamp,
+        delivered -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    webhook_delivery_attempts (id) {
+        id -> Int8,
+        delivery_id -> Uuid,
+        timestamp -> Timestamp,
+        status_code -> Nullable<Int4>,
+        error -> Nullable<Varchar>,
+    }
+}
+
+joinable!(discount_code_uses -> discount_codes (discount_code_id));
+joinable!(discount_code_uses -> payments (payment_id));
+joinable!(event_outbox -> payments (payment_id));
+joinable!(gateway_events -> payments (payment_id));
+joinable!(invoices -> payments (payment_id));
+joinable!(payment_admin_actions -> payments (payment_id));
+joinable!(payment_attempts -> payments (payment_id));
+joinable!(payment_events -> payments (payment_id));
 joinable!(payment_items -> payments (payment_id));
+joinable!(payment_links -> payments (payment_id));
+joinable!(payment_tenders -> payments (payment_id));
+joinable!(refunds -> payment_items (payment_item_id));
+joinable!(refunds -> payments (payment_id));
+joinable!(store_credit_entries -> payments (payment_id));
 joinable!(threeds_datas -> payments (payment_id));
+joinable!(webhook_deliveries -> payments (payment_id));
+joinable!(webhook_delivery_attempts -> webhook_deliveries (delivery_id));
 
 allow_tables_to_appear_in_same_query!(
     cards,
+    discount_code_uses,
+    discount_codes,
+    event_outbox,
+    gateway_events,
+    idempotency_keys,
+    invoices,
+    jobs,
+    payment_admin_actions,
+    payment_attempts,
+    payment_events,
     payment_items,
+    payment_links,
     payments,
+    payment_tenders,
     payment_tokens,
+    refunds,
+    store_credit_entries,
     threeds_datas,
+    webhook_deliveries,
+    webhook_delivery_attempts,
 );
@@ -0,0 +1,113 @@
+use diesel::prelude::*;
+use diesel::pg::PgConnection;
+
+use crate::{models, schema};
+
+#[derive(Debug)]
+pub enum TransitionError {
+    Invalid(models::PaymentState, models::PaymentState),
+    Conflict(models::PaymentState),
+    Database(diesel::result::Error),
+}
+
+impl std::fmt::Display for TransitionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransitionError::Invalid(from, to) => write!(f, "payment cannot move from {:?} to {:?}", from, to),
+            TransitionError::Conflict(expected) => write!(f, "payment is no longer {:?}", expected),
+            TransitionError::Database(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for TransitionError {}
+
+impl From<diesel::result::Error> for TransitionError {
+    fn from(e: diesel::result::Error) -> Self {
+        TransitionError::Database(e)
+    }
+}
+
+impl actix_web::error::ResponseError for TransitionError {
+    fn error_response(&self) -> actix_web::web::HttpResponse {
+        match self {
+            TransitionError::Invalid(_, _) | TransitionError::Conflict(_) => actix_web::web::HttpResponse::new(actix_web::http::StatusCode::CONFLICT),
+            TransitionError::Database(diesel::result::Error::NotFound) => actix_web::web::HttpResponse::new(actix_web::http::StatusCode::NOT_FOUND),
+            TransitionError::Database(_) => actix_web::web::HttpResponse::new(actix_web::http::StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+pub fn allowed(from: models::PaymentState, to: models::PaymentState) -> bool {
+    use models::PaymentState::*;
+
+    match (from, to) {
+        (OPEN, AUTHORISED) | (OPEN, PAID) | (OPEN, CANCELLED) | (OPEN, EXPIRED) => true,
+        (AUTHORISED, PAID) | (AUTHORISED, CANCELLED) | (AUTHORISED, CHARGED_BACK) => true,
+        (OPEN, CHARGED_BACK) => true,
+        (PAID, COMPLETE) | (PARTIALLY_REFUNDED, COMPLETE) => true,
+        (PAID, PARTIALLY_REFUNDED) | (COMPLETE, PARTIALLY_REFUNDED) | (PARTIALLY_REFUNDED, PARTIALLY_REFUNDED) => true,
+        (PAID, REFUNDED) | (COMPLETE, REFUNDED) | (PARTIALLY_REFUNDED, REFUNDED) => true,
+        (PAID, CHARGED_BACK) | (COMPLETE, CHARGED_BACK) | (PARTIALLY_REFUNDED, CHARGED_BACK) | (REFUNDED, CHARGED_BACK) => true,
+        _ => false
+    }
+}
+
+pub fn transition(conn: &PgConnection, payment: &models::Payment, to: models::PaymentState, actor: &crate::audit::Actor) -> Result<models::Payment, TransitionError> {
+    use schema::payments::dsl::*;
+
+    if !allowed(payment.state, to) {
+        return Err(TransitionError::Invalid(payment.state, to));
+    }
+
+    match diesel::update(payments.find(payment.id).filter(state.eq(payment.state)))
+        .set(state.eq(to))
+        .get_result::<models::Payment>(conn)
+        .optional()? {
+        Some(p) => {
+            crate::audit::record(
+                conn, &p.id, actor, crate::audit::STATE_CHANGED,
+                Some(&serde_json::json!({"state": payment.state})),
+                Some(&serde_json::json!({"state": p.state})),
+            )?;
+            Ok(p)
+        }
+        None => Err(TransitionError::Conflict(payment.state))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gateway::OrderStatus;
+    use models::PaymentState;
+
+    const STATES: [PaymentState; 9] = [
+        PaymentState::OPEN, PaymentState::AUTHORISED, PaymentState::PAID, PaymentState::COMPLETE, PaymentState::REFUNDED,
+        PaymentState::PARTIALLY_REFUNDED, PaymentState::CHARGED_BACK, PaymentState::CANCELLED, PaymentState::EXPIRED,
+    ];
+    const STATUSES: [OrderStatus; 11] = [
+        OrderStatus::Pending, OrderStatus::Authorised, OrderStatus::Paid, OrderStatus::Settled, OrderStatus::Cancelled,
+        OrderStatus::Expired, OrderStatus::Failed, OrderStatus::Refunded, OrderStatus::PartiallyRefunded,
+        OrderStatus::ChargedBack, OrderStatus::Unknown,
+    ];
+
+    #[test]
+    fn gateway_transitions_are_allowed() {
+        for status in STATUSES.iter() {
+            for from in STATES.iter() {
+                if let Some(to) = status.next_payment_state(*from) {
+                    assert!(allowed(*from, to), "{:?} maps {:?} to {:?}, which is not allowed", status, from, to);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn chargebacks_are_recorded_for_charged_payments() {
+        for from in [PaymentState::OPEN, PaymentState::AUTHORISED, PaymentState::PAID, PaymentState::COMPLETE].iter() {
+            assert_eq!(OrderStatus::ChargedBack.next_payment_state(*from), Some(PaymentState::CHARGED_BACK));
+        }
+        assert_eq!(OrderStatus::ChargedBack.next_payment_state(PaymentState::CHARGED_BACK), None);
+    }
+}
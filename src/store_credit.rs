@@ -0,0 +1,129 @@
+use diesel::prelude::*;
+use diesel::pg::PgConnection;
+use uuid::Uuid;
+
+use crate::{models, schema};
+
+pub const PAYMENT_METHOD: &str = "Store credit";
+
+#[derive(Debug)]
+pub enum StoreCreditError {
+    InsufficientBalance,
+    NothingToApply,
+    PaymentNotOpen,
+    Database(diesel::result::Error),
+}
+
+impl std::fmt::Display for StoreCreditError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StoreCreditError::InsufficientBalance => write!(f, "insufficient store credit balance"),
+            StoreCreditError::NothingToApply => write!(f, "no store credit can be applied"),
+            StoreCreditError::PaymentNotOpen => write!(f, "payment is not open"),
+            StoreCreditError::Database(e) => e.fmt(f),
+        }
+    }
+}
+
+impl From<diesel::result::Error> for StoreCreditError {
+    fn from(e: diesel::result::Error) -> Self {
+        StoreCreditError::Database(e)
+    }
+}
+
+pub fn lock(conn: &PgConnection, customer_id: &Uuid) -> Result<(), diesel::result::Error> {
+    diesel::sql_query("SELECT pg_advisory_xact_lock(hashtext($1))")
+        .bind::<diesel::sql_types::Text, _>(format!("store_credit:{}", customer_id))
+        .execute(conn)?;
+
+    Ok(())
+}
+
+pub fn balance(conn: &PgConnection, customer_id: &Uuid, currency: &str) -> Result<i64, diesel::result::Error> {
+    use schema::store_credit_entries::dsl;
+
+    Ok(dsl::store_credit_entries
+        .filter(dsl::customer_id.eq(customer_id))
+        .filter(dsl::currency.eq(currency))
+        .select(dsl::amount)
+        .load::<i64>(conn)?
+        .into_iter()
+        .sum())
+}
+
+pub fn record(
+    conn: &PgConnection, customer_id: &Uuid, currency: &str, amount: i64, entry_type: models::StoreCreditEntryType,
+    payment_id: Option<&Uuid>, reason: Option<&str>, created_by: Option<&Uuid>,
+) -> Result<models::StoreCreditEntry, diesel::result::Error> {
+    diesel::insert_into(schema::store_credit_entries::table)
+        .values(&models::NewStoreCreditEntry {
+            id: &Uuid::new_v4(),
+            customer_id,
+            currency,
+            amount,
+            entry_type,
+            payment_id,
+            reason,
+            created_by,
+        })
+        .get_result(conn)
+}
+
+pub fn balances(entries: &[models::StoreCreditEntry]) -> Vec<(String, i64)> {
+    let mut balances: Vec<(String, i64)> = vec![];
+    for entry in entries {
+        match balances.iter_mut().find(|(c, _)| *c == entry.currency) {
+            Some(b) => b.1 += entry.amount,
+            None => balances.push((entry.currency.clone(), entry.amount))
+        }
+    }
+    balances
+}
+
+pub fn capture_amount(total: i64, store_credit_amount: i64, requested: Option<i64>) -> Option<i64> {
+    let capturable = total - store_credit_amount;
+    let amount = requested.unwrap_or(capturable);
+    if amount < 0 || amount > capturable || (amount == 0 && capturable > 0) {
+        None
+    } else {
+        Some(amount)
+    }
+}
+
+pub fn refund_split(total: i64, store_credit_amount: i64, previously_refunded: i64, amount: i64) -> (i64, i64) {
+    let gateway_charged = total - store_credit_amount;
+    let gateway_refunded = previously_refunded.min(gateway_charged);
+    let to_gateway = amount.min(gateway_charged - gateway_refunded);
+    (to_gateway, amount - to_gateway)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn captures_exclude_store_credit() {
+        assert_eq!(capture_amount(10000, 3000, None), Some(7000));
+        assert_eq!(capture_amount(10000, 3000, Some(5000)), Some(5000));
+        assert_eq!(capture_amount(10000, 3000, Some(10000)), None);
+        assert_eq!(capture_amount(10000, 3000, Some(0)), None);
+        assert_eq!(capture_amount(10000, 10000, None), Some(0));
+    }
+
+    #[test]
+    fn refunds_after_capture_return_what_was_paid() {
+        let captured = capture_amount(10000, 3000, None).unwrap();
+        let total = captured + 3000;
+        assert_eq!(total, 10000);
+        assert_eq!(refund_split(total, 3000, 0, total), (7000, 3000));
+        assert_eq!(refund_split(total, 3000, 0, 2000), (2000, 0));
+        assert_eq!(refund_split(total, 3000, 2000, 8000), (5000, 3000));
+    }
+
+    #[test]
+    fn refund_splits_account_for_earlier_refunds() {
+        assert_eq!(refund_split(10000, 4000, 5000, 3000), (1000, 2000));
+        assert_eq!(refund_split(10000, 4000, 6000, 1000), (0, 1000));
+        assert_eq!(refund_split(10000, 4000, 2000, 1000), (1000, 0));
+    }
+}
@@ -0,0 +1,245 @@
+use actix_web::{HttpRequest, HttpResponse, web};
+use chrono::prelude::*;
+use crate::db;
+
+#[derive(Clone, Debug, Serialize)]
+struct StoreCreditBalanceResponseData {
+    currency: String,
+    amount: f64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct StoreCreditEntryResponseData {
+    id: uuid::Uuid,
+    currency: String,
+    amount: f64,
+    #[serde(rename = "type")]
+    entry_type: crate::models::StoreCreditEntryType,
+    payment_id: Option<uuid::Uuid>,
+    reason: Option<String>,
+    created_by: Option<uuid::Uuid>,
+    timestamp: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct StoreCreditResponseData {
+    customer_id: uuid::Uuid,
+    balances: Vec<StoreCreditBalanceResponseData>,
+    entries: Vec<StoreCreditEntryResponseData>,
+}
+
+fn to_major(currency: &str, amount: i64) -> f64 {
+    match crate::currency::Currency::lookup(currency) {
+        Some(c) => c.to_major(amount),
+        None => amount as f64
+    }
+}
+
+fn store_credit_error(e: crate::store_credit::StoreCreditError) -> actix_web::Error {
+    match e {
+        crate::store_credit::StoreCreditError::Database(e) => match e {
+            diesel::result::Error::NotFound => actix_web::error::ErrorNotFound(e),
+            e => actix_web::error::ErrorInternalServerError(e)
+        },
+        e => actix_web::error::ErrorBadRequest(e)
+    }
+}
+
+async fn store_credit_response(data: &web::Data<crate::config::AppState>, customer_id: &uuid::Uuid) -> actix_web::Result<HttpResponse> {
+    let entries = match match data.db.send(db::GetStoreCreditEntries::new(customer_id)).await {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    };
+
+    Ok(HttpResponse::Ok().json(StoreCreditResponseData {
+        customer_id: customer_id.to_owned(),
+        balances: crate::store_credit::balances(&entries).into_iter()
+            .map(|(currency, amount)| StoreCreditBalanceResponseData {
+                amount: to_major(&currency, amount),
+                currency,
+            })
+            .collect(),
+        entries: entries.into_iter()
+            .map(|entry| StoreCreditEntryResponseData {
+                id: entry.id,
+                amount: to_major(&entry.currency, entry.amount),
+                currency: entry.currency,
+                entry_type: entry.entry_type,
+                payment_id: entry.payment_id,
+                reason: entry.reason,
+                created_by: entry.created_by,
+                timestamp: DateTime::<Utc>::from_utc(entry.timestamp, Utc),
+            })
+            .collect(),
+    }))
+}
+
+pub async fn get_own_store_credit(data: web::Data<crate::config::AppState>, session: actix_session::Session) -> actix_web::Result<impl actix_web::Responder> {
+    let user_id = match crate::util::user_id_from_session(&session, &data.oauth).await? {
+        Some(u) => u,
+        None => return Err(actix_web::error::ErrorUnauthorized(""))
+    };
+
+    store_credit_response(&data, &user_id).await
+}
+
+async fn get_customer_payment(data: &web::Data<crate::config::AppState>, session: &actix_session::Session, payment_id: &uuid::Uuid) -> actix_web::Result<crate::models::Payment> {
+    let user_id = match crate::util::user_id_from_session(session, &data.oauth).await? {
+        Some(u) => u,
+        None => return Err(actix_web::error::ErrorUnauthorized(""))
+    };
+
+    let payment = match match data.db.send(db::GetPayment::new(payment_id)).await {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Ok(r) => r,
+        Err(e) => return match e {
+            diesel::result::Error::NotFound => Err(actix_web::error::ErrorNotFound(e)),
+            _ => Err(actix_web::error::ErrorInternalServerError(e))
+        }
+    };
+
+    if payment.customer_id != user_id {
+        return Err(actix_web::error::ErrorForbidden(""));
+    }
+
+    Ok(payment)
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ApplyStoreCreditData {
+    amount: Option<rust_decimal::Decimal>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct ApplyStoreCreditResponseData {
+    state: crate::models::PaymentState,
+    store_credit: f64,
+    remaining: f64,
+}
+
+pub async fn apply_store_credit(req: HttpRequest, data: web::Data<crate::config::AppState>, session: actix_session::Session, info: web::Path<uuid::Uuid>, credit_data: web::Json<ApplyStoreCreditData>) -> actix_web::Result<impl actix_web::Responder> {
+    let payment = get_customer_payment(&data, &session, &info.into_inner()).await?;
+    let actor = crate::audit::Actor::user(&req, &payment.customer_id);
+    let currency = crate::currency::Currency::for_payment(&payment);
+
+    let max_amount = match &credit_data.amount {
+        Some(a) => match currency.to_minor(a) {
+            Some(a) if a > 0 => Some(a),
+            _ => return Err(actix_web::error::ErrorBadRequest("invalid amount"))
+        },
+        None => None
+    };
+
+    let payment = match match data.db.send(db::ApplyStoreCredit::new(&payment.id, max_amount, &actor)).await {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Ok(r) => r,
+        Err(e) => return Err(store_credit_error(e))
+    };
+
+    let items = match match data.db.send(db::GetPaymentItems::new(&payment)).await {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    };
+    let tenders = match match data.db.send(db::GetPaymentTenders::new(&payment)).await {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    };
+    let remaining = crate::tenders::outstanding(&payment, &items, &tenders);
+
+    let mut state = payment.state;
+    if remaining == 0 {
+        state = crate::models::PaymentState::PAID;
+        match match data.db.send(db::UpdatePaymentState::new(
+            &payment.id, payment.state, state, crate::tenders::payment_method(&payment, &tenders).as_deref(), None, None, &actor,
+        )).await {
+            Ok(r) => r,
+            Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+        } {
+            Ok(_) => {}
+            Err(e) => return Err(e.into())
+        };
+    }
+
+    Ok(HttpResponse::Ok().json(ApplyStoreCreditResponseData {
+        state,
+        store_credit: currency.to_major(payment.store_credit_amount),
+        remaining: currency.to_major(remaining),
+    }))
+}
+
+pub async fn remove_store_credit(req: HttpRequest, data: web::Data<crate::config::AppState>, session: actix_session::Session, info: web::Path<uuid::Uuid>) -> actix_web::Result<impl actix_web::Responder> {
+    let payment = get_customer_payment(&data, &session, &info.into_inner()).await?;
+    let actor = crate::audit::Actor::user(&req, &payment.customer_id);
+
+    match match data.db.send(db::RemoveStoreCredit::new(&payment.id, &actor)).await {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Ok(_) => Ok(HttpResponse::Ok().finish()),
+        Err(e) => Err(store_credit_error(e))
+    }
+}
+
+pub async fn get_store_credit(data: web::Data<crate::config::AppState>, session: actix_session::Session, info: web::Path<uuid::Uuid>) -> actix_web::Result<impl actix_web::Responder> {
+    crate::util::staff_id_from_session(&session, &data.oauth, "manage-store-credit").await?;
+
+    store_credit_response(&data, &info.into_inner()).await
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct NewStoreCreditEntryData {
+    #[serde(rename = "type")]
+    entry_type: crate::models::StoreCreditEntryType,
+    amount: rust_decimal::Decimal,
+    currency: Option<String>,
+    reason: String,
+}
+
+pub async fn create_store_credit_entry(data: web::Data<crate::config::AppState>, session: actix_session::Session, info: web::Path<uuid::Uuid>, entry_data: web::Json<NewStoreCreditEntryData>) -> actix_web::Result<impl actix_web::Responder> {
+    let admin_id = crate::util::staff_id_from_session(&session, &data.oauth, "manage-store-credit").await?;
+    let customer_id = info.into_inner();
+
+    let currency = match crate::currency::Currency::lookup(
+        &entry_data.currency.as_deref().unwrap_or(crate::currency::DEFAULT).to_uppercase()
+    ) {
+        Some(c) => c,
+        None => return Err(actix_web::error::ErrorBadRequest("invalid currency"))
+    };
+    let amount = match currency.to_minor(&entry_data.amount) {
+        Some(a) => a,
+        None => return Err(actix_web::error::ErrorBadRequest("invalid amount"))
+    };
+    match entry_data.entry_type {
+        crate::models::StoreCreditEntryType::ISSUE if amount > 0 => {}
+        crate::models::StoreCreditEntryType::ADJUSTMENT if amount != 0 => {}
+        _ => return Err(actix_web::error::ErrorBadRequest("invalid entry"))
+    }
+    if entry_data.reason.trim().is_empty() {
+        return Err(actix_web::error::ErrorBadRequest("a reason is required"));
+    }
+
+    match match data.db.send(db::CreateStoreCreditEntry::new(
+        &customer_id, currency.code, amount, entry_data.entry_type, None, Some(entry_data.reason.trim()), Some(&admin_id),
+    )).await {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Ok(_) => {}
+        Err(e) => return Err(store_credit_error(e))
+    };
+
+    store_credit_response(&data, &customer_id).await
+}
@@ -0,0 +1,394 @@
+use std::collections::HashMap;
+use actix_web::{HttpRequest, HttpResponse, web};
+use futures::future::LocalBoxFuture;
+
+use crate::db;
+use crate::gateway::PaymentGateway;
+use crate::models;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct StripePaymentData {
+    payment_method_id: Option<String>,
+    payment_intent_id: Option<String>,
+    amount: Option<rust_decimal::Decimal>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum StripePaymentIntentStatus {
+    RequiresPaymentMethod,
+    RequiresConfirmation,
+    RequiresAction,
+    Processing,
+    RequiresCapture,
+    Canceled,
+    Succeeded,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct StripeCardDetails {
+    brand: String,
+    last4: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct StripePaymentMethodDetails {
+    card: Option<StripeCardDetails>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct StripeCharge {
+    payment_method_details: Option<StripePaymentMethodDetails>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct StripeCharges {
+    data: Vec<StripeCharge>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct StripePaymentIntent {
+    id: String,
+    status: StripePaymentIntentStatus,
+    amount: i64,
+    currency: String,
+    client_secret: String,
+    #[serde(default)]
+    metadata: HashMap<String, String>,
+    charges: Option<StripeCharges>,
+}
+
+impl StripePaymentIntent {
+    fn card(&self) -> Option<&StripeCardDetails> {
+        self.charges.as_ref()
+            .and_then(|c| c.data.first())
+            .and_then(|c| c.payment_method_details.as_ref())
+            .and_then(|d| d.card.as_ref())
+    }
+}
+
+impl From<StripePaymentIntent> for crate::gateway::AuthoriseResult {
+    fn from(intent: StripePaymentIntent) -> Self {
+        match intent.status {
+            StripePaymentIntentStatus::Succeeded | StripePaymentIntentStatus::RequiresCapture => crate::gateway::AuthoriseResult::Success {
+                payment_method: intent.card().map(|c| format!("{} {}", c.brand, c.last4)),
+                order_code: intent.id,
+            },
+            StripePaymentIntentStatus::RequiresAction => crate::gateway::AuthoriseResult::ActionRequired {
+                order_code: intent.id,
+                client_secret: intent.client_secret,
+            },
+            StripePaymentIntentStatus::RequiresPaymentMethod | StripePaymentIntentStatus::Canceled => crate::gateway::AuthoriseResult::Failed,
+            _ => crate::gateway::AuthoriseResult::Unknown
+        }
+    }
+}
+
+impl From<&StripePaymentIntentStatus> for crate::gateway::OrderStatus {
+    fn from(status: &StripePaymentIntentStatus) -> Self {
+        match status {
+            StripePaymentIntentStatus::Succeeded => crate::gateway::OrderStatus::Paid,
+            StripePaymentIntentStatus::RequiresCapture => crate::gateway::OrderStatus::Authorised,
+            StripePaymentIntentStatus::Canceled => crate::gateway::OrderStatus::Cancelled,
+            StripePaymentIntentStatus::RequiresPaymentMethod => crate::gateway::OrderStatus::Failed,
+            StripePaymentIntentStatus::RequiresConfirmation | StripePaymentIntentStatus::RequiresAction |
+            StripePaymentIntentStatus::Processing => crate::gateway::OrderStatus::Pending,
+        }
+    }
+}
+
+pub struct StripeGateway {
+    config: crate::config::StripeConfig,
+    db: actix::Addr<db::DbExecutor>,
+}
+
+impl StripeGateway {
+    pub fn new(config: &crate::config::StripeConfig, db: &actix::Addr<db::DbExecutor>) -> Self {
+        Self {
+            config: config.clone(),
+            db: db.clone(),
+        }
+    }
+
+    fn secret_key(&self, environment: models::PaymentEnvironment) -> &str {
+        match environment {
+            models::PaymentEnvironment::LIVE => &self.config.live_key,
+            models::PaymentEnvironment::TEST => &self.config.test_key,
+        }
+    }
+
+    fn intent_url(intent_id: &str, action: Option<&str>) -> Result<reqwest::Url, url::ParseError> {
+        match action {
+            Some(a) => reqwest::Url::parse(&format!("https://api.stripe.com/v1/payment_intents/{}/{}", intent_id, a)),
+            None => reqwest::Url::parse(&format!("https://api.stripe.com/v1/payment_intents/{}", intent_id)),
+        }
+    }
+
+    async fn send_intent_request(&self, payment: &models::Payment, request: reqwest::RequestBuilder) -> failure::Fallible<StripePaymentIntent> {
+        let (status, body) = match async {
+            let c = request.send().await?;
+            let status = c.status();
+            Ok::<_, reqwest::Error>((status, c.text().await?))
+        }.await {
+            Ok(r) => r,
+            Err(e) => {
+                crate::gateway::record_attempt(&self.db, payment, db::CreatePaymentAttempt::new(
+                    &payment.id, "stripe", None, None, None, None, &serde_json::json!({
+                        "error": e.to_string()
+                    }),
+                )).await;
+                return Err(e.into());
+            }
+        };
+        let raw_response = serde_json::from_str::<serde_json::Value>(&body)
+            .unwrap_or_else(|_| serde_json::Value::String(body.clone()));
+        let raw_intent = match raw_response.pointer("/error/payment_intent") {
+            Some(i) => i.clone(),
+            None => raw_response.clone()
+        };
+
+        crate::gateway::record_attempt(&self.db, payment, db::CreatePaymentAttempt::new(
+            &payment.id,
+            "stripe",
+            raw_intent.get("id").and_then(|v| v.as_str()),
+            raw_intent.get("status").and_then(|v| v.as_str()),
+            raw_intent.pointer("/charges/data/0/payment_method_details/card/brand").and_then(|v| v.as_str()),
+            raw_intent.pointer("/charges/data/0/payment_method_details/card/last4").and_then(|v| v.as_str())
+                .map(|l| format!("**** **** **** {}", l)).as_deref(),
+            &raw_response,
+        )).await;
+
+        if (status.is_client_error() || status.is_server_error()) && raw_response.pointer("/error/payment_intent").is_none() {
+            debug!("Got response with status code {} with body {:?}", status, body);
+            return Err(failure::err_msg(format!("stripe returned {}", status)));
+        }
+
+        let intent = serde_json::from_value::<StripePaymentIntent>(raw_intent)?;
+        if intent.metadata.get("payment_id") != Some(&payment.id.to_string()) {
+            return Err(failure::err_msg("payment intent does not belong to this payment"));
+        }
+
+        Ok(intent)
+    }
+}
+
+impl PaymentGateway for StripeGateway {
+    fn name(&self) -> &'static str {
+        "stripe"
+    }
+
+    fn authorisation_window(&self) -> chrono::Duration {
+        chrono::Duration::days(7)
+    }
+
+    fn tokenise_card<'a>(&'a self, _environment: models::PaymentEnvironment, _card: &'a crate::gateway::CardDetails) -> LocalBoxFuture<'a, failure::Fallible<crate::gateway::CardToken>> {
+        Box::pin(futures::future::ready(Err(failure::err_msg("stripe cards are tokenised client side"))))
+    }
+
+    fn delete_token<'a>(&'a self, environment: models::PaymentEnvironment, token: &'a str) -> LocalBoxFuture<'a, failure::Fallible<()>> {
+        Box::pin(async move {
+            crate::util::async_reqwest_to_error(
+                reqwest::Client::new().post(reqwest::Url::parse(&format!("https://api.stripe.com/v1/payment_methods/{}/detach", token))?)
+                    .bearer_auth(self.secret_key(environment))
+            ).await?;
+            Ok(())
+        })
+    }
+
+    fn authorise<'a>(&'a self, request: &'a crate::gateway::AuthoriseRequest) -> LocalBoxFuture<'a, failure::Fallible<crate::gateway::AuthoriseResult>> {
+        Box::pin(async move {
+            let form = vec![
+                ("amount", request.amount.to_string()),
+                ("currency", crate::currency::Currency::for_payment(&request.payment).code.to_lowercase()),
+                ("description", request.description()),
+                ("payment_method", request.source.clone()),
+                ("capture_method", if request.payment.authorise_only { "manual" } else { "automatic" }.to_string()),
+                ("confirmation_method", "manual".to_string()),
+                ("confirm", "true".to_string()),
+                ("metadata[payment_id]", request.payment.id.to_string()),
+            ];
+
+            let intent = self.send_intent_request(
+                &request.payment,
+                reqwest::Client::new().post("https://api.stripe.com/v1/payment_intents")
+                    .bearer_auth(self.secret_key(request.payment.environment))
+                    .form(&form),
+            ).await?;
+
+            Ok(intent.into())
+        })
+    }
+
+    fn continue_threeds<'a>(&'a self, payment: &'a models::Payment, order_code: &'a str, response: &'a crate::gateway::ThreedsResponse) -> LocalBoxFuture<'a, failure::Fallible<crate::gateway::AuthoriseResult>> {
+        Box::pin(async move {
+            let intent = self.send_intent_request(
+                payment,
+                reqwest::Client::new().get(Self::intent_url(order_code, None)?)
+                    .bearer_auth(self.secret_key(payment.environment)),
+            ).await?;
+            if !intent.currency.eq_ignore_ascii_case(crate::currency::Currency::for_payment(payment).code) {
+                return Err(failure::err_msg("payment intent currency does not match the payment"));
+            }
+            if response.amount.map_or(false, |a| a != intent.amount) {
+                return Err(failure::err_msg("payment intent amount does not match the amount outstanding"));
+            }
+
+            let intent = self.send_intent_request(
+                payment,
+                reqwest::Client::new().post(Self::intent_url(order_code, Some("confirm"))?)
+                    .bearer_auth(self.secret_key(payment.environment)),
+            ).await?;
+
+            Ok(intent.into())
+        })
+    }
+
+    fn capture<'a>(&'a self, payment: &'a models::Payment, order_code: &'a str, amount: Option<i64>) -> LocalBoxFuture<'a, failure::Fallible<()>> {
+        Box::pin(async move {
+            let mut form = vec![];
+            if let Some(amount) = amount {
+                form.push(("amount_to_capture", amount.to_string()));
+            }
+
+            crate::util::async_reqwest_to_error(
+                reqwest::Client::new().post(Self::intent_url(order_code, Some("capture"))?)
+                    .bearer_auth(self.secret_key(payment.environment))
+                    .form(&form)
+            ).await?;
+            Ok(())
+        })
+    }
+
+    fn cancel<'a>(&'a self, payment: &'a models::Payment, order_code: &'a str) -> LocalBoxFuture<'a, failure::Fallible<()>> {
+        Box::pin(async move {
+            crate::util::async_reqwest_to_error(
+                reqwest::Client::new().post(Self::intent_url(order_code, Some("cancel"))?)
+                    .bearer_auth(self.secret_key(payment.environment))
+            ).await?;
+            Ok(())
+        })
+    }
+
+    fn refund<'a>(&'a self, payment: &'a models::Payment, order_code: &'a str, amount: Option<i64>) -> LocalBoxFuture<'a, failure::Fallible<()>> {
+        Box::pin(async move {
+            let mut form = vec![
+                ("payment_intent", order_code.to_string()),
+            ];
+            if let Some(amount) = amount {
+                form.push(("amount", amount.to_string()));
+            }
+
+            crate::util::async_reqwest_to_error(
+                reqwest::Client::new().post("https://api.stripe.com/v1/refunds")
+                    .bearer_auth(self.secret_key(payment.environment))
+                    .form(&form)
+            ).await?;
+            Ok(())
+        })
+    }
+
+    fn status<'a>(&'a self, payment: &'a models::Payment, order_code: &'a str) -> LocalBoxFuture<'a, failure::Fallible<crate::gateway::OrderStatus>> {
+        Box::pin(async move {
+            let c = crate::util::async_reqwest_to_error(
+                reqwest::Client::new().get(Self::intent_url(order_code, None)?)
+                    .bearer_auth(self.secret_key(payment.environment))
+            ).await?;
+            let intent = c.json::<StripePaymentIntent>().await?;
+
+            Ok((&intent.status).into())
+        })
+    }
+}
+
+pub async fn process_stripe_payment(req: HttpRequest, token: crate::oauth::OptionalBearerAuthToken, data: web::Data<crate::config::AppState>, info: web::Path<uuid::Uuid>, session: actix_session::Session, payment_data: web::Json<StripePaymentData>) -> actix_web::Result<impl actix_web::Responder> {
+    let sess_id = match match session.get::<uuid::Uuid>("sess_id") {
+        Ok(s) => s,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Some(s) => s.to_string(),
+        None => "".to_string()
+    };
+
+    let payment = match match data.db.send(db::GetPayment::new(&info.into_inner())).await {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Ok(r) => r,
+        Err(e) => return match e {
+            diesel::result::Error::NotFound => Err(actix_web::error::ErrorNotFound(e)),
+            _ => Err(actix_web::error::ErrorInternalServerError(e))
+        }
+    };
+
+    let actor = crate::payment_views::check_payment_access(&req, &token, &data, &session, &payment).await?;
+
+    if payment.state != models::PaymentState::OPEN {
+        return Err(actix_web::error::ErrorBadRequest("payment is not open"));
+    }
+
+    let items = match match data.db.send(db::GetPaymentItems::new(&payment)).await {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    } {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    };
+
+    let gateway = match crate::gateway::get(&data, "stripe", payment.environment) {
+        Some(g) => g,
+        None => return Err(actix_web::error::ErrorInternalServerError("stripe gateway unavailable"))
+    };
+
+    let shopper = crate::gateway::Shopper {
+        name: "".to_string(),
+        email: None,
+        ip_address: req.connection_info().remote().unwrap_or("").to_string(),
+        user_agent: req.headers().get(actix_web::http::header::USER_AGENT)
+            .unwrap_or(&actix_web::http::header::HeaderValue::from_static("")).to_str()
+            .unwrap_or("").to_string(),
+        accept_header: req.headers().get(actix_web::http::header::ACCEPT)
+            .unwrap_or(&actix_web::http::header::HeaderValue::from_static("*/*")).to_str()
+            .unwrap_or("*/*").to_string(),
+        session_id: sess_id,
+        billing_address: None,
+    };
+
+    let (amount, result) = match (&payment_data.payment_method_id, &payment_data.payment_intent_id) {
+        (Some(payment_method_id), None) => {
+            let amount = crate::payment_views::tender_amount(&data, &payment, &items, payment_data.amount.as_ref()).await?;
+            (Some(amount), gateway.authorise(&crate::gateway::AuthoriseRequest {
+                payment: payment.clone(),
+                items,
+                amount,
+                source: payment_method_id.to_string(),
+                cvc: None,
+                shopper,
+            }).await?)
+        }
+        (None, Some(payment_intent_id)) => {
+            let tenders = match match data.db.send(db::GetPaymentTenders::new(&payment)).await {
+                Ok(r) => r,
+                Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+            } {
+                Ok(r) => r,
+                Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+            };
+            let amount = match tenders.iter().find(|t| {
+                t.gateway == "stripe" && &t.order_code == payment_intent_id && t.state == models::PaymentTenderState::PENDING
+            }) {
+                Some(t) => t.amount,
+                None => crate::tenders::outstanding(&payment, &items, &tenders)
+            };
+            (Some(amount), gateway.continue_threeds(&payment, payment_intent_id, &crate::gateway::ThreedsResponse {
+                response_code: None,
+                amount: Some(amount),
+                shopper,
+            }).await?)
+        }
+        _ => return Err(actix_web::error::ErrorBadRequest("one of payment_method_id and payment_intent_id must be given"))
+    };
+
+    let response = crate::payment_views::complete_authorisation(&req, &data, gateway.as_ref(), &payment, amount, result, &actor).await?;
+
+    Ok(HttpResponse::Ok().json(response))
+}
\ No newline at end of file
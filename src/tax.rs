@@ -0,0 +1,65 @@
+use crate::models;
+
+pub fn rate(category: models::TaxCategory) -> i32 {
+    match category {
+        models::TaxCategory::STANDARD => 2000,
+        models::TaxCategory::REDUCED => 500,
+        models::TaxCategory::ZERO | models::TaxCategory::EXEMPT => 0,
+    }
+}
+
+pub fn format_rate(rate: i32) -> String {
+    if rate % 100 == 0 {
+        format!("{}%", rate / 100)
+    } else {
+        format!("{}%", (rate as f64) / 100.0)
+    }
+}
+
+pub fn split_gross(gross: i64, rate: i32) -> (i64, i64) {
+    let rate = rate as i64;
+    let tax = (gross.abs() * rate + (10000 + rate) / 2) / (10000 + rate) * gross.signum();
+    (gross - tax, tax)
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TaxLine {
+    pub category: models::TaxCategory,
+    pub rate: i32,
+    pub net: i64,
+    pub tax: i64,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Totals {
+    pub net: i64,
+    pub tax: i64,
+    pub gross: i64,
+}
+
+pub fn breakdown(items: &[models::PaymentItem]) -> Vec<TaxLine> {
+    let mut lines: Vec<TaxLine> = vec![];
+    for item in items {
+        match lines.iter_mut().find(|l| l.category == item.tax_category && l.rate == item.tax_rate) {
+            Some(l) => {
+                l.net += item.net_amount;
+                l.tax += item.tax_amount;
+            }
+            None => lines.push(TaxLine {
+                category: item.tax_category,
+                rate: item.tax_rate,
+                net: item.net_amount,
+                tax: item.tax_amount,
+            })
+        }
+    }
+    lines
+}
+
+pub fn totals(items: &[models::PaymentItem]) -> Totals {
+    items.iter().fold(Totals::default(), |acc, item| Totals {
+        net: acc.net + item.net_amount,
+        tax: acc.tax + item.tax_amount,
+        gross: acc.gross + item.net_amount + item.tax_amount,
+    })
+}
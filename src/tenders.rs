@@ -0,0 +1,122 @@
+use crate::models;
+
+#[derive(Debug)]
+pub enum TenderError {
+    PaymentNotOpen(models::PaymentState),
+    UnknownTender,
+    Transition(crate::state_machine::TransitionError),
+}
+
+impl std::fmt::Display for TenderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TenderError::PaymentNotOpen(state) => write!(f, "payment is {:?}, not open", state),
+            TenderError::UnknownTender => write!(f, "no pending tender for this order"),
+            TenderError::Transition(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for TenderError {}
+
+impl From<diesel::result::Error> for TenderError {
+    fn from(e: diesel::result::Error) -> Self {
+        TenderError::Transition(e.into())
+    }
+}
+
+impl From<crate::state_machine::TransitionError> for TenderError {
+    fn from(e: crate::state_machine::TransitionError) -> Self {
+        TenderError::Transition(e)
+    }
+}
+
+pub fn total(items: &[models::PaymentItem]) -> i64 {
+    items.iter().map(|i| i.price * i.quantity as i64).sum()
+}
+
+pub fn paid(tenders: &[models::PaymentTender]) -> i64 {
+    tenders.iter()
+        .filter(|t| t.state == models::PaymentTenderState::SUCCEEDED)
+        .map(|t| t.amount)
+        .sum()
+}
+
+pub fn outstanding(payment: &models::Payment, items: &[models::PaymentItem], tenders: &[models::PaymentTender]) -> i64 {
+    total(items) - payment.store_credit_amount - paid(tenders)
+}
+
+pub fn payment_method(payment: &models::Payment, tenders: &[models::PaymentTender]) -> Option<String> {
+    let mut methods: Vec<&str> = vec![];
+    for tender in tenders.iter().filter(|t| t.state == models::PaymentTenderState::SUCCEEDED) {
+        if let Some(method) = &tender.payment_method {
+            if !methods.contains(&method.as_str()) {
+                methods.push(method);
+            }
+        }
+    }
+    if payment.store_credit_amount > 0 {
+        methods.push(crate::store_credit::PAYMENT_METHOD);
+    }
+
+    match methods.len() {
+        0 => None,
+        _ => Some(methods.join(", "))
+    }
+}
+
+pub fn allocate_refund(tenders: &[models::PaymentTender], previously_refunded: i64, amount: i64) -> Vec<(&models::PaymentTender, i64)> {
+    let mut skip = previously_refunded;
+    let mut remaining = amount;
+    let mut allocations = vec![];
+
+    for tender in tenders.iter().rev().filter(|t| t.state == models::PaymentTenderState::SUCCEEDED) {
+        let available = tender.amount - skip.min(tender.amount);
+        skip -= tender.amount - available;
+        let refund = available.min(remaining);
+        if refund > 0 {
+            allocations.push((tender, refund));
+            remaining -= refund;
+        }
+    }
+
+    allocations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tender(amount: i64, state: models::PaymentTenderState) -> models::PaymentTender {
+        models::PaymentTender {
+            id: uuid::Uuid::new_v4(),
+            payment_id: uuid::Uuid::nil(),
+            gateway: "mock".to_string(),
+            order_code: uuid::Uuid::new_v4().to_string(),
+            payment_method: None,
+            amount,
+            state,
+            created: chrono::Utc::now().naive_utc(),
+        }
+    }
+
+    #[test]
+    fn partial_refunds_continue_from_earlier_refunds() {
+        let tenders = vec![
+            tender(3000, models::PaymentTenderState::SUCCEEDED),
+            tender(2000, models::PaymentTenderState::SUCCEEDED),
+            tender(1000, models::PaymentTenderState::FAILED),
+            tender(5000, models::PaymentTenderState::SUCCEEDED),
+        ];
+
+        let allocations = allocate_refund(&tenders, 6000, 2500).into_iter()
+            .map(|(t, a)| (t.id, a))
+            .collect::<Vec<_>>();
+        assert_eq!(allocations, vec![(tenders[1].id, 1000), (tenders[0].id, 1500)]);
+
+        let allocations = allocate_refund(&tenders, 0, 10000).into_iter()
+            .map(|(t, a)| (t.id, a))
+            .collect::<Vec<_>>();
+        assert_eq!(allocations, vec![(tenders[3].id, 5000), (tenders[1].id, 2000), (tenders[0].id, 3000)]);
+    }
+}
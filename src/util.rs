@@ -39,4 +39,17 @@ pub async fn user_id_from_session(session: &actix_session::Session, oauth_client
         Ok(u) => Ok(Some(u)),
         Err(e) => Err(actix_web::error::ErrorInternalServerError(e))
     }
-}
\ No newline at end of file
+}
+pub async fn staff_id_from_session(session: &actix_session::Session, oauth_client: &crate::oauth::OAuthClient, role: &str) -> actix_web::Result<uuid::Uuid> {
+    let (introspect, oauth_token) = match user_token_from_session(session, oauth_client).await? {
+        Some(u) => u,
+        None => return Err(actix_web::error::ErrorForbidden(""))
+    };
+
+    oauth_client.verify_token(&oauth_token.access_token, role).await?;
+
+    match introspect.sub.as_deref().map(uuid::Uuid::parse_str) {
+        Some(Ok(u)) => Ok(u),
+        _ => Err(actix_web::error::ErrorInternalServerError(""))
+    }
+}
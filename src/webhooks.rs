@@ -0,0 +1,102 @@
+use chrono::prelude::*;
+use crypto::mac::Mac;
+use diesel::prelude::*;
+use diesel::pg::PgConnection;
+
+use crate::{models, schema};
+
+pub const MAX_ATTEMPTS: i32 = 10;
+pub const TIMEOUT_SECS: u64 = 10;
+pub const MAX_CONCURRENT_DELIVERIES: usize = 10;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum EventType {
+    PaymentPaid,
+    PaymentFailed,
+    PaymentRefunded,
+}
+
+impl EventType {
+    pub fn for_state(state: models::PaymentState) -> Option<Self> {
+        match state {
+            models::PaymentState::PAID => Some(EventType::PaymentPaid),
+            models::PaymentState::REFUNDED | models::PaymentState::PARTIALLY_REFUNDED => Some(EventType::PaymentRefunded),
+            _ => None
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventType::PaymentPaid => "payment.paid",
+            EventType::PaymentFailed => "payment.failed",
+            EventType::PaymentRefunded => "payment.refunded",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct EventData {
+    payment_id: uuid::Uuid,
+    state: models::PaymentState,
+    environment: models::PaymentEnvironment,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct Event {
+    id: uuid::Uuid,
+    #[serde(rename = "type")]
+    event_type: &'static str,
+    timestamp: DateTime<Utc>,
+    data: EventData,
+}
+
+pub fn record_event(conn: &PgConnection, payment: &models::Payment, event_type: EventType) -> Result<(), diesel::result::Error> {
+    let url = match &payment.callback_url {
+        Some(u) => u,
+        None => return Ok(())
+    };
+
+    let event = Event {
+        id: uuid::Uuid::new_v4(),
+        event_type: event_type.as_str(),
+        timestamp: Utc::now(),
+        data: EventData {
+            payment_id: payment.id,
+            state: payment.state,
+            environment: payment.environment,
+        },
+    };
+
+    let new_delivery = models::NewWebhookDelivery {
+        id: &event.id,
+        payment_id: &payment.id,
+        url,
+        event_type: event_type.as_str(),
+        payload: &serde_json::to_value(&event)
+            .map_err(|e| diesel::result::Error::SerializationError(Box::new(e)))?,
+    };
+
+    diesel::insert_into(schema::webhook_deliveries::table)
+        .values(&new_delivery)
+        .execute(conn)?;
+
+    Ok(())
+}
+
+pub fn sign(key: &[u8], body: &[u8]) -> String {
+    let mut hmac = crypto::hmac::Hmac::new(crypto::sha2::Sha256::new(), key);
+    hmac.input(body);
+    hmac.result().code().iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+pub fn sign_event(key: &[u8], timestamp: i64, body: &[u8]) -> String {
+    let mut payload = format!("{}.", timestamp).into_bytes();
+    payload.extend_from_slice(body);
+    sign(key, &payload)
+}
+
+pub fn retry_delay(attempts: i32) -> chrono::Duration {
+    chrono::Duration::minutes(2i64.pow(attempts.min(10) as u32))
+}
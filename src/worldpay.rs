@@ -2,9 +2,10 @@ use actix_web::{HttpRequest, HttpResponse, web};
 use chrono::prelude::*;
 use crypto::mac::Mac;
 use encoding::types::Encoding;
+use futures::future::LocalBoxFuture;
 
 use crate::db;
-use crate::jobs;
+use crate::gateway::PaymentGateway;
 use crate::models;
 use crate::util;
 
@@ -21,12 +22,9 @@ pub struct BillingAddressData {
 }
 
 #[derive(Clone, Deserialize)]
-pub struct CardData {
-    name: String,
-    exp_month: u32,
-    exp_year: u32,
-    card_number: String,
-    cvc: String,
+pub struct SavedCardData {
+    id: uuid::Uuid,
+    cvc: Option<String>,
 }
 
 #[derive(Clone, Deserialize)]
@@ -36,9 +34,11 @@ pub struct WorldpayPaymentData {
     phone: Option<String>,
     first_name: Option<String>,
     last_name: Option<String>,
-    card: CardData,
+    card: Option<crate::gateway::CardDetails>,
+    saved_card: Option<SavedCardData>,
     payment: Option<WorldpayNewPaymentData>,
     billing_address: BillingAddressData,
+    amount: Option<rust_decimal::Decimal>,
 }
 
 #[derive(Clone, Deserialize)]
@@ -50,6 +50,7 @@ struct WorldpayNewPaymentItemData {
     title: String,
     quantity: i32,
     price: rust_decimal::Decimal,
+    tax_category: Option<models::TaxCategory>,
     #[serde(with = "serde_hex::SerHex::<serde_hex::Strict>")]
     sig: [u8; 64],
 }
@@ -65,27 +66,10 @@ struct WorldpayNewCustomerData {
 struct WorldpayNewPaymentData {
     environment: models::PaymentEnvironment,
     customer: WorldpayNewCustomerData,
+    currency: Option<String>,
     items: Vec<WorldpayNewPaymentItemData>,
 }
 
-#[derive(Clone, Debug, Serialize)]
-enum WorldpayPaymentStatus {
-    SUCCESS,
-    FAILED,
-    #[serde(rename = "3DS")]
-    THREEDS,
-    #[serde(rename = "EXISTING_ACCOUNT")]
-    ExistingAccount,
-    UNKNOWN,
-}
-
-#[derive(Clone, Debug, Serialize)]
-struct WorldpayPaymentDataResp {
-    state: WorldpayPaymentStatus,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    frame: Option<String>,
-}
-
 #[derive(Clone, Debug, Serialize)]
 struct WorldpayBillingAddress {
     address1: String,
@@ -103,17 +87,30 @@ struct WorldpayBillingAddress {
     telephone_number: String,
 }
 
-impl From<&BillingAddressData> for WorldpayBillingAddress {
+impl From<&BillingAddressData> for crate::gateway::BillingAddress {
     fn from(data: &BillingAddressData) -> Self {
-        let address1 = match data.address_line.get(0) {
+        Self {
+            address_lines: data.address_line.clone(),
+            city: data.city.clone(),
+            region: data.region.clone(),
+            postal_code: data.postal_code.clone(),
+            country_code: data.country.clone(),
+            phone: data.phone.clone(),
+        }
+    }
+}
+
+impl From<&crate::gateway::BillingAddress> for WorldpayBillingAddress {
+    fn from(data: &crate::gateway::BillingAddress) -> Self {
+        let address1 = match data.address_lines.get(0) {
             Some(l) => l.to_string(),
             None => "".to_string()
         };
-        let address2 = match data.address_line.get(1) {
+        let address2 = match data.address_lines.get(1) {
             Some(l) => Some(l.to_string()),
             None => None
         };
-        let address3 = match data.address_line.get(2) {
+        let address3 = match data.address_lines.get(2) {
             Some(l) => Some(l.to_string()),
             None => None
         };
@@ -125,7 +122,7 @@ impl From<&BillingAddressData> for WorldpayBillingAddress {
             city: data.city.clone(),
             state: data.region.clone(),
             postal_code: data.postal_code.clone(),
-            country_code: data.country.clone(),
+            country_code: data.country_code.clone(),
             telephone_number: data.phone.clone(),
         }
     }
@@ -145,8 +142,8 @@ struct WorldpayOrder {
     name: String,
     #[serde(rename = "shopperEmailAddress")]
     shopper_email_address: String,
-    #[serde(rename = "billingAddress")]
-    billing_address: WorldpayBillingAddress,
+    #[serde(rename = "billingAddress", skip_serializing_if = "Option::is_none")]
+    billing_address: Option<WorldpayBillingAddress>,
     #[serde(rename = "shopperIpAddress")]
     shopper_ip_address: String,
     #[serde(rename = "shopperUserAgent")]
@@ -159,11 +156,10 @@ struct WorldpayOrder {
     is_3ds_order: bool,
     #[serde(rename = "authorizeOnly")]
     authorize_only: bool,
-    #[serde(rename = "paymentMethod")]
-    payment_method: WorldpayCard,
+    token: String,
 }
 
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Serialize)]
 struct WorldpayCard {
     name: String,
     #[serde(rename = "expiryMonth")]
@@ -193,6 +189,51 @@ impl WorldpayCard {
     }
 }
 
+#[derive(Clone, Serialize)]
+struct WorldpayTokenRequest {
+    reusable: bool,
+    #[serde(rename = "paymentMethod")]
+    payment_method: WorldpayCard,
+    #[serde(rename = "clientKey")]
+    client_key: String,
+}
+
+#[derive(Clone, Serialize)]
+struct WorldpayTokenCvcRequest {
+    #[serde(rename = "clientKey")]
+    client_key: String,
+    cvc: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct WorldpayTokenPaymentMethod {
+    name: String,
+    #[serde(rename = "expiryMonth")]
+    exp_month: u32,
+    #[serde(rename = "expiryYear")]
+    exp_year: u32,
+    #[serde(rename = "cardType")]
+    card_type: String,
+    #[serde(rename = "maskedCardNumber")]
+    masked_card_number: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct WorldpayTokenResp {
+    token: String,
+    #[serde(rename = "paymentMethod")]
+    payment_method: WorldpayTokenPaymentMethod,
+}
+
+impl WorldpayTokenResp {
+    fn last_four(&self) -> String {
+        let digits = self.payment_method.masked_card_number.chars()
+            .filter(|c| c.is_ascii_digit())
+            .collect::<Vec<_>>();
+        digits[digits.len().saturating_sub(4)..].iter().collect()
+    }
+}
+
 #[derive(Clone, Debug, Serialize)]
 struct WorldpayThreedsOrder {
     #[serde(rename = "threeDSResponseCode")]
@@ -237,6 +278,42 @@ enum WorldpayOrderStatus {
     InformationSupplied,
 }
 
+#[derive(Clone, Debug, Serialize)]
+struct WorldpayCapture {
+    #[serde(rename = "captureAmount")]
+    capture_amount: i64,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct WorldpayOrderStatusResp {
+    #[serde(rename = "paymentStatus")]
+    payment_status: WorldpayOrderStatus,
+}
+
+impl From<&WorldpayOrderStatus> for crate::gateway::OrderStatus {
+    fn from(status: &WorldpayOrderStatus) -> Self {
+        match status {
+            WorldpayOrderStatus::Success => crate::gateway::OrderStatus::Paid,
+            WorldpayOrderStatus::Failed => crate::gateway::OrderStatus::Failed,
+            WorldpayOrderStatus::SentForRefund | WorldpayOrderStatus::Refunded => crate::gateway::OrderStatus::Refunded,
+            WorldpayOrderStatus::PartialyRefunded => crate::gateway::OrderStatus::PartiallyRefunded,
+            WorldpayOrderStatus::Authorized => crate::gateway::OrderStatus::Authorised,
+            WorldpayOrderStatus::PreAuthorized => crate::gateway::OrderStatus::Pending,
+            WorldpayOrderStatus::Cancelled => crate::gateway::OrderStatus::Cancelled,
+            WorldpayOrderStatus::Expride => crate::gateway::OrderStatus::Expired,
+            WorldpayOrderStatus::Settled => crate::gateway::OrderStatus::Settled,
+            WorldpayOrderStatus::ChargedBack => crate::gateway::OrderStatus::ChargedBack,
+            WorldpayOrderStatus::InformationRequested | WorldpayOrderStatus::InformationSupplied => crate::gateway::OrderStatus::Unknown,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct WorldpayRefund {
+    #[serde(rename = "refundAmount")]
+    refund_amount: i64,
+}
+
 #[derive(Clone, Debug, Deserialize)]
 struct WorldpayOrderPaymentResponse {
     #[serde(rename = "cardIssuer")]
@@ -259,7 +336,287 @@ struct WorldpayOrderResp {
     one_time_3ds_token: Option<String>,
 }
 
-pub async fn process_worldpay_payment(req: HttpRequest, data: web::Data<crate::config::AppState>, info: web::Path<uuid::Uuid>, session: actix_session::Session, payment_data: web::Json<WorldpayPaymentData>) -> actix_web::Result<impl actix_web::Responder> {
+impl From<WorldpayOrderResp> for crate::gateway::AuthoriseResult {
+    fn from(r: WorldpayOrderResp) -> Self {
+        match r.payment_status {
+            WorldpayOrderStatus::Success | WorldpayOrderStatus::Authorized => crate::gateway::AuthoriseResult::Success {
+                payment_method: Some(format!("{} {}", r.payment_response.card_issuer, r.payment_response.masked_card_number)),
+                order_code: r.order_code,
+            },
+            WorldpayOrderStatus::PreAuthorized => match (r.redirect_url, r.one_time_3ds_token) {
+                (Some(redirect_url), Some(one_time_token)) => crate::gateway::AuthoriseResult::ThreedsRequired {
+                    order_code: r.order_code,
+                    redirect_url,
+                    one_time_token,
+                },
+                _ => crate::gateway::AuthoriseResult::Unknown
+            },
+            WorldpayOrderStatus::Failed => crate::gateway::AuthoriseResult::Failed,
+            _ => crate::gateway::AuthoriseResult::Unknown
+        }
+    }
+}
+
+pub struct WorldpayGateway {
+    config: crate::config::WorldpayConfig,
+    db: actix::Addr<db::DbExecutor>,
+}
+
+impl WorldpayGateway {
+    pub fn new(config: &crate::config::WorldpayConfig, db: &actix::Addr<db::DbExecutor>) -> Self {
+        Self {
+            config: config.clone(),
+            db: db.clone(),
+        }
+    }
+
+    fn service_key(&self, environment: models::PaymentEnvironment) -> &str {
+        match environment {
+            models::PaymentEnvironment::LIVE => &self.config.live_key,
+            models::PaymentEnvironment::TEST => &self.config.test_key,
+        }
+    }
+
+    fn client_key(&self, environment: models::PaymentEnvironment) -> &str {
+        match environment {
+            models::PaymentEnvironment::LIVE => &self.config.live_client_key,
+            models::PaymentEnvironment::TEST => &self.config.test_client_key,
+        }
+    }
+
+    fn order_url(order_code: &str, action: Option<&str>) -> Result<reqwest::Url, url::ParseError> {
+        match action {
+            Some(a) => reqwest::Url::parse(&format!("https://api.worldpay.com/v1/orders/{}/{}", order_code, a)),
+            None => reqwest::Url::parse(&format!("https://api.worldpay.com/v1/orders/{}", order_code)),
+        }
+    }
+
+    async fn send_order_request(&self, payment: &models::Payment, request: reqwest::RequestBuilder) -> failure::Fallible<WorldpayOrderResp> {
+        let (status, body) = match async {
+            let c = request.send().await?;
+            let status = c.status();
+            Ok::<_, reqwest::Error>((status, c.text().await?))
+        }.await {
+            Ok(r) => r,
+            Err(e) => {
+                crate::gateway::record_attempt(&self.db, payment, db::CreatePaymentAttempt::new(
+                    &payment.id, "worldpay", None, None, None, None, &serde_json::json!({
+                        "error": e.to_string()
+                    }),
+                )).await;
+                return Err(e.into());
+            }
+        };
+        let raw_response = serde_json::from_str::<serde_json::Value>(&body)
+            .unwrap_or_else(|_| serde_json::Value::String(body.clone()));
+
+        crate::gateway::record_attempt(&self.db, payment, db::CreatePaymentAttempt::new(
+            &payment.id,
+            "worldpay",
+            raw_response.get("orderCode").and_then(|v| v.as_str()),
+            raw_response.get("paymentStatus").and_then(|v| v.as_str()),
+            raw_response.pointer("/paymentResponse/cardIssuer").and_then(|v| v.as_str()),
+            raw_response.pointer("/paymentResponse/maskedCardNumber").and_then(|v| v.as_str()),
+            &raw_response,
+        )).await;
+
+        if status.is_client_error() || status.is_server_error() {
+            debug!("Got response with status code {} with body {:?}", status, body);
+            return Err(failure::err_msg(format!("worldpay returned {}", status)));
+        }
+
+        Ok(serde_json::from_value::<WorldpayOrderResp>(raw_response)?)
+    }
+
+    async fn update_token_cvc(&self, environment: models::PaymentEnvironment, token: &str, cvc: &str) -> failure::Fallible<()> {
+        util::async_reqwest_to_error(
+            reqwest::Client::new().put(reqwest::Url::parse(&format!("https://api.worldpay.com/v1/tokens/{}", token))?)
+                .json(&WorldpayTokenCvcRequest {
+                    client_key: self.client_key(environment).to_string(),
+                    cvc: cvc.to_string(),
+                })
+        ).await?;
+        Ok(())
+    }
+}
+
+impl PaymentGateway for WorldpayGateway {
+    fn name(&self) -> &'static str {
+        "worldpay"
+    }
+
+    fn authorisation_window(&self) -> chrono::Duration {
+        chrono::Duration::days(7)
+    }
+
+    fn tokenise_card<'a>(&'a self, environment: models::PaymentEnvironment, card: &'a crate::gateway::CardDetails) -> LocalBoxFuture<'a, failure::Fallible<crate::gateway::CardToken>> {
+        Box::pin(async move {
+            let token_data = WorldpayTokenRequest {
+                reusable: true,
+                payment_method: WorldpayCard::new(
+                    &card.name,
+                    &card.card_number,
+                    card.exp_month,
+                    card.exp_year,
+                    Some(&card.cvc),
+                ),
+                client_key: self.client_key(environment).to_string(),
+            };
+
+            let c = util::async_reqwest_to_error(
+                reqwest::Client::new().post("https://api.worldpay.com/v1/tokens")
+                    .json(&token_data)
+            ).await?;
+            let r = c.json::<WorldpayTokenResp>().await?;
+
+            Ok(crate::gateway::CardToken {
+                last_four: r.last_four(),
+                token: r.token,
+                brand: r.payment_method.card_type,
+                exp_month: r.payment_method.exp_month,
+                exp_year: r.payment_method.exp_year,
+                name: r.payment_method.name,
+            })
+        })
+    }
+
+    fn delete_token<'a>(&'a self, environment: models::PaymentEnvironment, token: &'a str) -> LocalBoxFuture<'a, failure::Fallible<()>> {
+        Box::pin(async move {
+            util::async_reqwest_to_error(
+                reqwest::Client::new().delete(reqwest::Url::parse(&format!("https://api.worldpay.com/v1/tokens/{}", token))?)
+                    .header(reqwest::header::AUTHORIZATION, self.service_key(environment))
+            ).await?;
+            Ok(())
+        })
+    }
+
+    fn authorise<'a>(&'a self, request: &'a crate::gateway::AuthoriseRequest) -> LocalBoxFuture<'a, failure::Fallible<crate::gateway::AuthoriseResult>> {
+        Box::pin(async move {
+            if let Some(cvc) = &request.cvc {
+                self.update_token_cvc(request.payment.environment, &request.source, cvc).await?;
+            }
+
+            let total = request.amount;
+            let order_data = WorldpayOrder {
+                order_type: "ECOM".to_string(),
+                order_description: request.description(),
+                customer_order_code: request.payment.id.to_string(),
+                amount: total,
+                currency_code: crate::currency::Currency::for_payment(&request.payment).code.to_string(),
+                name: String::from_utf8(
+                    encoding::all::ISO_8859_1.encode(&request.shopper.name, encoding::EncoderTrap::Ignore)
+                        .map_err(|e| failure::err_msg(e.into_owned()))?
+                )?,
+                shopper_email_address: request.shopper.email.clone().unwrap_or("".to_string()),
+                billing_address: request.shopper.billing_address.as_ref().map(WorldpayBillingAddress::from),
+                shopper_ip_address: request.shopper.ip_address.clone(),
+                shopper_user_agent: request.shopper.user_agent.clone(),
+                shopper_accept_header: request.shopper.accept_header.clone(),
+                shopper_session_id: request.shopper.session_id.clone(),
+                is_3ds_order: true,
+                authorize_only: total == 0 || request.payment.authorise_only,
+                token: request.source.clone(),
+            };
+
+            let r = self.send_order_request(
+                &request.payment,
+                reqwest::Client::new().post("https://api.worldpay.com/v1/orders")
+                    .header(reqwest::header::AUTHORIZATION, self.service_key(request.payment.environment))
+                    .json(&order_data),
+            ).await?;
+
+            Ok(r.into())
+        })
+    }
+
+    fn continue_threeds<'a>(&'a self, payment: &'a models::Payment, order_code: &'a str, response: &'a crate::gateway::ThreedsResponse) -> LocalBoxFuture<'a, failure::Fallible<crate::gateway::AuthoriseResult>> {
+        Box::pin(async move {
+            let order_data = WorldpayThreedsOrder {
+                threeds_response_code: response.response_code.clone().unwrap_or("".to_string()),
+                shopper_ip_address: response.shopper.ip_address.clone(),
+                shopper_user_agent: response.shopper.user_agent.clone(),
+                shopper_accept_header: response.shopper.accept_header.clone(),
+                shopper_session_id: response.shopper.session_id.clone(),
+            };
+
+            let r = self.send_order_request(
+                payment,
+                reqwest::Client::new().put(Self::order_url(order_code, None)?)
+                    .header(reqwest::header::AUTHORIZATION, self.service_key(payment.environment))
+                    .json(&order_data),
+            ).await?;
+
+            Ok(r.into())
+        })
+    }
+
+    fn capture<'a>(&'a self, payment: &'a models::Payment, order_code: &'a str, amount: Option<i64>) -> LocalBoxFuture<'a, failure::Fallible<()>> {
+        Box::pin(async move {
+            let mut r = reqwest::Client::new().post(Self::order_url(order_code, Some("capture"))?)
+                .header(reqwest::header::AUTHORIZATION, self.service_key(payment.environment));
+            if let Some(amount) = amount {
+                r = r.json(&WorldpayCapture {
+                    capture_amount: amount
+                });
+            }
+
+            util::async_reqwest_to_error(r).await?;
+            Ok(())
+        })
+    }
+
+    fn cancel<'a>(&'a self, payment: &'a models::Payment, order_code: &'a str) -> LocalBoxFuture<'a, failure::Fallible<()>> {
+        Box::pin(async move {
+            util::async_reqwest_to_error(
+                reqwest::Client::new().delete(Self::order_url(order_code, None)?)
+                    .header(reqwest::header::AUTHORIZATION, self.service_key(payment.environment))
+            ).await?;
+            Ok(())
+        })
+    }
+
+    fn refund<'a>(&'a self, payment: &'a models::Payment, order_code: &'a str, amount: Option<i64>) -> LocalBoxFuture<'a, failure::Fallible<()>> {
+        Box::pin(async move {
+            let mut r = reqwest::Client::new().post(Self::order_url(order_code, Some("refund"))?)
+                .header(reqwest::header::AUTHORIZATION, self.service_key(payment.environment));
+            if let Some(amount) = amount {
+                r = r.json(&WorldpayRefund {
+                    refund_amount: amount
+                });
+            }
+
+            util::async_reqwest_to_error(r).await?;
+            Ok(())
+        })
+    }
+
+    fn status<'a>(&'a self, payment: &'a models::Payment, order_code: &'a str) -> LocalBoxFuture<'a, failure::Fallible<crate::gateway::OrderStatus>> {
+        Box::pin(async move {
+            let c = util::async_reqwest_to_error(
+                reqwest::Client::new().get(Self::order_url(order_code, None)?)
+                    .header(reqwest::header::AUTHORIZATION, self.service_key(payment.environment))
+            ).await?;
+            let r = c.json::<WorldpayOrderStatusResp>().await?;
+
+            Ok((&r.payment_status).into())
+        })
+    }
+}
+
+pub async fn process_worldpay_payment(req: HttpRequest, data: web::Data<crate::config::AppState>, info: web::Path<uuid::Uuid>, session: actix_session::Session, body: web::Json<serde_json::Value>) -> actix_web::Result<impl actix_web::Responder> {
+    let payment_id = info.into_inner();
+    let payment_data: WorldpayPaymentData = match serde_json::from_value(body.clone()) {
+        Ok(p) => p,
+        Err(e) => return Err(actix_web::error::ErrorBadRequest(e))
+    };
+
+    crate::idempotency::run(
+        &req, &data, &format!("worldpay:{}", payment_id), &body,
+        |attempt| authorise_worldpay_payment(&req, &data, payment_id, &session, payment_data, attempt),
+    ).await
+}
+
+async fn authorise_worldpay_payment(req: &HttpRequest, data: &web::Data<crate::config::AppState>, payment_id: uuid::Uuid, session: &actix_session::Session, payment_data: WorldpayPaymentData, attempt: crate::idempotency::Attempt) -> actix_web::Result<crate::payment_views::AuthoriseResponseData> {
     let sess_id = match match session.get::<uuid::Uuid>("sess_id") {
         Ok(s) => s,
         Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
@@ -275,8 +632,9 @@ pub async fn process_worldpay_payment(req: HttpRequest, data: web::Data<crate::c
     };
 
     let token = data.oauth.clone().get_access_token().await?;
+    let actor = crate::audit::Actor::from_session(req, session, &data.oauth).await?;
 
-    let payment = match match data.db.send(db::GetPayment::new(&info)).await {
+    let payment = match match data.db.send(db::GetPayment::new(&payment_id)).await {
         Ok(r) => r,
         Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
     } {
@@ -290,23 +648,49 @@ pub async fn process_worldpay_payment(req: HttpRequest, data: web::Data<crate::c
                     Ok(r) => r,
                     Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
                 };
+                let currency = match crate::currency::Currency::lookup(
+                    payment.currency.as_deref().unwrap_or(crate::currency::DEFAULT)
+                ) {
+                    Some(c) => c,
+                    None => return Err(actix_web::error::ErrorBadRequest("invalid currency"))
+                };
                 let mut items: Vec<db::CreatePaymentItem> = vec![];
                 for i in payment.items.iter() {
                     let digest = crypto::sha2::Sha512::new();
-                    let mut price = i.price * rust_decimal::Decimal::new(100, 0);
-                    price.set_scale(0).unwrap();
-                    let hmac_data = format!("{}{}{}{}{}", i.item_type, i.item_data, i.title, i.quantity, price.to_string()).into_bytes();
+                    let price = match currency.to_minor(&i.price) {
+                        Some(p) => p,
+                        None => return Err(actix_web::error::ErrorBadRequest("invalid price"))
+                    };
+                    let hmac_data = format!(
+                        "{}{}{}{}{}{}{}", i.item_type, i.item_data, i.title, i.quantity, price,
+                        i.tax_category.map(|c| format!("{:?}", c)).unwrap_or_default(),
+                        payment.currency.as_deref().unwrap_or("")
+                    ).into_bytes();
+                    let legacy_hmac_data = if currency.code == "GBP" && i.tax_category.is_none() {
+                        let mut legacy_price = i.price * rust_decimal::Decimal::new(100, 0);
+                        legacy_price.set_scale(0).unwrap();
+                        Some(format!("{}{}{}{}{}", i.item_type, i.item_data, i.title, i.quantity, legacy_price.to_string()).into_bytes())
+                    } else {
+                        None
+                    };
                     let sig = crypto::mac::MacResult::new(&i.sig);
 
                     let mut validated = false;
                     for token in &tokens {
                         let mut hmac = crypto::hmac::Hmac::new(digest, &token.token);
                         hmac.input(&hmac_data);
-                        let res = hmac.result();
-
-                        if res == sig {
+                        if hmac.result() == sig {
                             validated = true;
                         }
+
+                        if let Some(legacy_hmac_data) = &legacy_hmac_data {
+                            let mut hmac = crypto::hmac::Hmac::new(digest, &token.token);
+                            hmac.input(legacy_hmac_data);
+                            if hmac.result() == sig {
+                                warn!("Accepted legacy item signature for payment {}", payment_id);
+                                validated = true;
+                            }
+                        }
                     }
                     if !validated {
                         return Err(actix_web::error::ErrorBadRequest("invalid signature"));
@@ -318,20 +702,22 @@ pub async fn process_worldpay_payment(req: HttpRequest, data: web::Data<crate::c
                         &i.item_data,
                         &i.title,
                         i.quantity,
-                        &i.price,
+                        price,
+                        i.tax_category.unwrap_or_default(),
                     ));
                 }
 
-                let user_id = match util::user_id_from_session(&session, &data.oauth).await? {
+                let user_id = match util::user_id_from_session(session, &data.oauth).await? {
                     Some(u) => u,
                     None => match data.keycloak.get_user_by_email(&payment.customer.email, &token).await? {
                         Some(_) => {
-                            return Ok(HttpResponse::Ok().json(WorldpayPaymentDataResp {
-                                state: WorldpayPaymentStatus::ExistingAccount,
+                            return Ok(crate::payment_views::AuthoriseResponseData {
+                                state: crate::payment_views::AuthoriseStatus::ExistingAccount,
+                                client_secret: None,
                                 frame: Some(format!("https://{}/login/auth/?{}", req.connection_info().host(), serde_urlencoded::to_string(&[
                                     ("next", format!("https://{}/payment/login-complete/", req.connection_info().host())),
                                 ]).unwrap())),
-                            }));
+                            });
                         }
                         None => {
                             let mut u = data.keycloak.create_user(&payment.customer.email, &token).await?;
@@ -350,12 +736,17 @@ pub async fn process_worldpay_payment(req: HttpRequest, data: web::Data<crate::c
                 };
 
                 let payment = db::CreatePayment::new(
-                    &info.into_inner(),
+                    &payment_id,
                     &Utc::now().naive_utc(),
                     models::PaymentState::OPEN,
                     payment.environment,
                     &user_id,
+                    false,
+                    None,
+                    &currency,
+                    None,
                     &items,
+                    &actor,
                 );
 
                 match match data.db.send(payment).await {
@@ -378,6 +769,7 @@ pub async fn process_worldpay_payment(req: HttpRequest, data: web::Data<crate::c
         Ok(r) => r,
         Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
     };
+    let amount = crate::payment_views::tender_amount(data, &payment, &items, payment_data.amount.as_ref()).await?;
 
     let mut user = data.keycloak.clone().get_user(payment.customer_id, &token).await?;
 
@@ -396,264 +788,192 @@ pub async fn process_worldpay_payment(req: HttpRequest, data: web::Data<crate::c
     }
     user.update(&token).await?;
 
-    let billing_address = WorldpayBillingAddress::from(&payment_data.billing_address);
-
-    let description: String = items.iter().map(|i| i.title.clone()).collect::<Vec<String>>().join(", ");
-    let total = items.iter().map(|i| i.price.0 * i.quantity as i64).fold(0, |acc, i| acc + i);
-    let name = format!("{} {}", user.first_name.unwrap_or("".to_string()), user.last_name.unwrap_or("".to_string()));
-
-    let order_data = WorldpayOrder {
-        order_type: "ECOM".to_string(),
-        order_description: description,
-        customer_order_code: payment.id.to_string(),
-        amount: total,
-        currency_code: "GBP".to_string(),
-        name: match encoding::all::ISO_8859_1.encode(&name, encoding::EncoderTrap::Ignore) {
-            Ok(s) => match String::from_utf8(s) {
-                Ok(s) => s,
-                Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
-            },
-            Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
-        },
-        shopper_email_address: user.email.unwrap_or("".to_string()),
-        billing_address,
-        shopper_ip_address: req.connection_info().remote().unwrap_or("").to_string(),
-        shopper_user_agent: req.headers().get(actix_web::http::header::USER_AGENT)
-            .unwrap_or(&actix_web::http::header::HeaderValue::from_static("")).to_str()
-            .unwrap_or("").to_string(),
-        shopper_accept_header: payment_data.accepts.clone(),
-        shopper_session_id: sess_id.to_string(),
-        is_3ds_order: true,
-        authorize_only: total == 0,
-        payment_method: WorldpayCard::new(
-            &payment_data.card.name,
-            &payment_data.card.card_number,
-            payment_data.card.exp_month,
-            payment_data.card.exp_year,
-            Some(&payment_data.card.cvc),
-        )
-    };
-
-    match match data.db.send(db::CreateCard::new(
-        &uuid::Uuid::new_v4(),
-        &payment.customer_id,
-        &payment_data.card.card_number,
-        payment_data.card.exp_month,
-        payment_data.card.exp_year,
-        &payment_data.card.name,
-    )).await {
-        Ok(r) => r,
-        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
-    } {
-        Ok(_) => {}
-        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
-    };
-
-    let worldpay_token = match payment.environment {
-        models::PaymentEnvironment::LIVE => &data.worldpay.live_key,
-        models::PaymentEnvironment::TEST => &data.worldpay.test_key,
-    };
-
-    let c = util::async_reqwest_to_error(
-        reqwest::Client::new().post("https://api.worldpay.com/v1/orders")
-            .header(reqwest::header::AUTHORIZATION, worldpay_token)
-            .json(&order_data)
-    ).await?;
-    let r = match c.json::<WorldpayOrderResp>().await {
-        Ok(c) => c,
-        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+    let gateway = match crate::gateway::get(data, "worldpay", payment.environment) {
+        Some(g) => g,
+        None => return Err(actix_web::error::ErrorInternalServerError("worldpay gateway unavailable"))
     };
 
-    match r.payment_status {
-        WorldpayOrderStatus::Success | WorldpayOrderStatus::Authorized => {
-            match match data.db.send(db::UpdatePaymentState::new(
-                &payment.id,
-                models::PaymentState::PAID,
-                Some(&format!("{} {}", r.payment_response.card_issuer, r.payment_response.masked_card_number)),
+    let (source, cvc) = match (&payment_data.card, &payment_data.saved_card) {
+        (Some(card), None) => {
+            let card_token = gateway.tokenise_card(payment.environment, card).await?;
+
+            match match data.db.send(db::CreateCard::new(
+                &uuid::Uuid::new_v4(),
+                &payment.customer_id,
+                &card_token.token,
+                &card_token.last_four,
+                &card_token.brand,
+                card_token.exp_month,
+                card_token.exp_year,
+                &card_token.name,
+                payment.environment,
             )).await {
                 Ok(r) => r,
                 Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
             } {
-                Ok(_) => {
-                    let job = jobs::CompletePayment::new(&payment.id);
-                    let job_state = data.jobs_state.clone();
-                    std::thread::spawn(move || {
-                        jobs::send_payment_notification(job, job_state)
-                    });
-                }
+                Ok((_, Some(old_token))) => if let Err(e) = gateway.delete_token(payment.environment, &old_token).await {
+                    warn!("Unable to delete replaced card token for customer {}: {}", payment.customer_id, e);
+                },
+                Ok((_, None)) => {}
                 Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
             };
 
-            Ok(HttpResponse::Ok().json(WorldpayPaymentDataResp {
-                state: WorldpayPaymentStatus::SUCCESS,
-                frame: None,
-            }))
+            (card_token.token, None)
         }
-        WorldpayOrderStatus::PreAuthorized => {
-            match match data.db.send(db::CreateThreedsData::new(
-                &payment.id,
-                &r.one_time_3ds_token.unwrap(),
-                &r.redirect_url.unwrap(),
-                &r.order_code,
-            )).await {
+        (None, Some(saved_card)) => {
+            match util::user_id_from_session(session, &data.oauth).await? {
+                Some(u) if u == payment.customer_id => {}
+                _ => return Err(actix_web::error::ErrorForbidden(""))
+            }
+
+            let card = match match data.db.send(db::GetCard::new(&saved_card.id)).await {
                 Ok(r) => r,
                 Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
             } {
-                Ok(_) => {}
-                Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+                Ok(r) => r,
+                Err(e) => return match e {
+                    diesel::result::Error::NotFound => Err(actix_web::error::ErrorNotFound(e)),
+                    _ => Err(actix_web::error::ErrorInternalServerError(e))
+                }
             };
+            if card.customer_id != payment.customer_id || card.environment != payment.environment {
+                return Err(actix_web::error::ErrorNotFound(""));
+            }
 
-            Ok(HttpResponse::Ok().json(WorldpayPaymentDataResp {
-                state: WorldpayPaymentStatus::THREEDS,
-                frame: Some(format!("https://{}/payment/3ds/{}/", req.connection_info().host(), payment.id)),
-            }))
-        }
-        WorldpayOrderStatus::Failed => Ok(HttpResponse::Ok().json(WorldpayPaymentDataResp {
-            state: WorldpayPaymentStatus::FAILED,
-            frame: None,
-        })),
-        _ => Ok(HttpResponse::Ok().json(WorldpayPaymentDataResp {
-            state: WorldpayPaymentStatus::UNKNOWN,
-            frame: None,
-        }))
-    }
-}
-
-pub async fn render_3ds_form<'a>(req: HttpRequest, data: web::Data<crate::config::AppState>, info: web::Path<uuid::Uuid>) -> actix_web::Result<impl actix_web::Responder> {
-    let payment = match match data.db.send(db::GetPayment::new(&info.into_inner())).await {
-        Ok(r) => r,
-        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
-    } {
-        Ok(r) => r,
-        Err(e) => return match e {
-            diesel::result::Error::NotFound => Err(actix_web::error::ErrorNotFound(e)),
-            _ => Err(actix_web::error::ErrorInternalServerError(e))
+            (card.token, saved_card.cvc.clone())
         }
+        _ => return Err(actix_web::error::ErrorBadRequest("one of card and saved_card must be given"))
     };
 
-    let threeds_data = match match data.db.send(db::GetThreedsData::new(&payment)).await {
-        Ok(r) => r,
-        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
-    } {
-        Ok(r) => r,
-        Err(e) => return match e {
-            diesel::result::Error::NotFound => Err(actix_web::error::ErrorNotFound(e)),
-            _ => Err(actix_web::error::ErrorInternalServerError(e))
-        }
+    let request = crate::gateway::AuthoriseRequest {
+        payment: payment.clone(),
+        items,
+        amount,
+        source,
+        cvc,
+        shopper: crate::gateway::Shopper {
+            name: format!("{} {}", user.first_name.unwrap_or("".to_string()), user.last_name.unwrap_or("".to_string())),
+            email: user.email,
+            ip_address: req.connection_info().remote().unwrap_or("").to_string(),
+            user_agent: req.headers().get(actix_web::http::header::USER_AGENT)
+                .unwrap_or(&actix_web::http::header::HeaderValue::from_static("")).to_str()
+                .unwrap_or("").to_string(),
+            accept_header: payment_data.accepts.clone(),
+            session_id: sess_id.to_string(),
+            billing_address: Some((&payment_data.billing_address).into()),
+        },
     };
 
-    let connection_info = req.connection_info();
-    let mut context = tera::Context::new();
-    context.insert("redirect", &format!("https://{}/payment/3ds-complete/{}/", connection_info.host(), payment.id));
-    context.insert("redirect_url", &threeds_data.redirect_url);
-    context.insert("one_time_3ds_token", &threeds_data.one_time_3ds_token);
-    context.insert("order_id", &threeds_data.order_id);
+    attempt.charging();
+    let result = gateway.authorise(&request).await?;
+    crate::payment_views::complete_authorisation(req, data, gateway.as_ref(), &payment, Some(amount), result, &actor).await
+}
 
-    match crate::TERA.render("3ds_form.html", &context) {
-        Ok(r) => Ok(HttpResponse::Ok().body(r)),
-        Err(e) => Err(actix_web::error::ErrorInternalServerError(e))
-    }
+#[derive(Clone, Debug, Deserialize)]
+struct WorldpayWebhookEvent {
+    #[serde(rename = "orderCode")]
+    order_code: String,
+    #[serde(rename = "customerOrderCode")]
+    customer_order_code: Option<String>,
+    #[serde(rename = "paymentStatus")]
+    payment_status: String,
 }
 
 #[derive(Clone, Debug, Deserialize)]
-pub struct ThreedsData {
-    #[serde(rename = "MD")]
-    order_id: String,
-    #[serde(rename = "PaRes")]
-    response_code: String,
+pub struct WorldpayWebhookAuth {
+    secret: Option<String>,
 }
 
-pub async fn render_3ds_complete<'a>(req: HttpRequest, data: web::Data<crate::config::AppState>, info: web::Path<uuid::Uuid>, session: actix_session::Session, form: web::Form<ThreedsData>) -> actix_web::Result<impl actix_web::Responder> {
-    let sess_id = match match session.get::<uuid::Uuid>("sess_id") {
-        Ok(s) => s,
-        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
-    } {
-        Some(s) => s.to_string(),
-        None => "".to_string()
+pub async fn process_worldpay_webhook(req: HttpRequest, data: web::Data<crate::config::AppState>, auth: web::Query<WorldpayWebhookAuth>, body: web::Json<serde_json::Value>) -> actix_web::Result<impl actix_web::Responder> {
+    let secret = match req.headers().get("X-Webhook-Secret").and_then(|h| h.to_str().ok()) {
+        Some(s) => Some(s),
+        None => auth.secret.as_deref()
+    };
+    match secret {
+        Some(s) if crypto::util::fixed_time_eq(s.as_bytes(), data.worldpay.webhook_secret.as_bytes()) => {}
+        _ => return Err(actix_web::error::ErrorForbidden(""))
+    }
+
+    let raw_body = body.into_inner();
+    let event = match serde_json::from_value::<WorldpayWebhookEvent>(raw_body.clone()) {
+        Ok(e) => e,
+        Err(e) => return Err(actix_web::error::ErrorBadRequest(e))
     };
+    let event_status = event.payment_status.clone();
 
-    let payment = match match data.db.send(db::GetPayment::new(&info.into_inner())).await {
-        Ok(r) => r,
-        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
-    } {
-        Ok(r) => r,
-        Err(e) => return match e {
-            diesel::result::Error::NotFound => Err(actix_web::error::ErrorNotFound(e)),
-            _ => Err(actix_web::error::ErrorInternalServerError(e))
-        }
+    let payment_id = event.customer_order_code.as_ref().and_then(|c| uuid::Uuid::parse_str(c).ok());
+    let payment = match payment_id {
+        Some(payment_id) => data.db.send(db::GetPayment::new(&payment_id)).await,
+        None => data.db.send(db::GetPaymentByOrderCode::new(&event.order_code)).await,
     };
-    match match data.db.send(db::DeleteThreedsData::new(&payment)).await {
+    let payment = match match payment {
         Ok(r) => r,
         Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
     } {
-        Ok(_) => {}
-        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+        Ok(r) => match &r.order_code {
+            Some(c) if c != &event.order_code => {
+                warn!("Worldpay event for order {} does not match order {} of payment {}", event.order_code, c, r.id);
+                None
+            }
+            _ => Some(r)
+        },
+        Err(e) => match e {
+            diesel::result::Error::NotFound => {
+                warn!("Worldpay event for unknown order {}", event.order_code);
+                None
+            }
+            _ => return Err(actix_web::error::ErrorInternalServerError(e))
+        }
     };
 
-    let worldpay_token = match payment.environment {
-        models::PaymentEnvironment::LIVE => &data.worldpay.live_key,
-        models::PaymentEnvironment::TEST => &data.worldpay.test_key,
+    let tenders = match &payment {
+        Some(p) if p.state == models::PaymentState::OPEN => match match data.db.send(db::GetPaymentTenders::new(p)).await {
+            Ok(r) => r,
+            Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+        } {
+            Ok(r) => r,
+            Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+        },
+        _ => vec![]
     };
 
-    let order_data = WorldpayThreedsOrder {
-        threeds_response_code: form.response_code.clone(),
-        shopper_ip_address: req.connection_info().remote().unwrap_or("").to_string(),
-        shopper_user_agent: req.headers().get(actix_web::http::header::USER_AGENT)
-            .unwrap_or(&actix_web::http::header::HeaderValue::from_static("")).to_str()
-            .unwrap_or("").to_string(),
-        shopper_accept_header: req.headers().get(actix_web::http::header::ACCEPT)
-            .unwrap_or(&actix_web::http::header::HeaderValue::from_static("*/*")).to_str()
-            .unwrap_or("*/*").to_string(),
-        shopper_session_id: sess_id.to_string(),
+    let status: crate::gateway::OrderStatus = match serde_json::from_value::<WorldpayOrderStatus>(serde_json::Value::String(event_status.clone())) {
+        Ok(s) => (&s).into(),
+        Err(_) => {
+            warn!("Unknown Worldpay payment status {} for order {}", event_status, event.order_code);
+            crate::gateway::OrderStatus::Unknown
+        }
+    };
+    let state_change = payment.as_ref()
+        .filter(|_| tenders.is_empty())
+        .and_then(|p| status.next_payment_state(p.state).map(|s| (p.state, s)));
+    let authorised_until = match (&payment, state_change) {
+        (Some(payment), Some((_, models::PaymentState::AUTHORISED))) => match crate::gateway::get(&data, "worldpay", payment.environment) {
+            Some(g) => Some(Utc::now().naive_utc() + g.authorisation_window()),
+            None => None
+        },
+        _ => None
     };
 
-    let mut context = tera::Context::new();
-    context.insert("payment_id", &payment.id);
-
-    match {
-        match util::async_reqwest_to_error(
-            reqwest::Client::new().put(reqwest::Url::parse(&format!("https://api.worldpay.com/v1/orders/{}", form.order_id)).unwrap())
-                .header(reqwest::header::AUTHORIZATION, worldpay_token)
-                .json(&order_data)
-        ).await {
-            Ok(c) => match c.json::<WorldpayOrderResp>().await {
-                Ok(r) => match r.payment_status {
-                    WorldpayOrderStatus::Success | WorldpayOrderStatus::Authorized => {
-                        match data.db.send(db::UpdatePaymentState::new(
-                            &payment.id,
-                            models::PaymentState::PAID,
-                            Some(&format!("{} {}", r.payment_response.card_issuer, r.payment_response.masked_card_number)),
-                        )).await {
-                            Ok(r) => match r {
-                                Ok(_) => {
-                                    let job = jobs::CompletePayment::new(&payment.id);
-                                    let job_state = data.jobs_state.clone();
-                                    std::thread::spawn(move || {
-                                        jobs::send_payment_notification(job, job_state)
-                                    });
-                                    context.insert("threeds_approved", &true);
-                                    Ok(())
-                                }
-                                Err(e) => Err(actix_web::error::ErrorInternalServerError(e))
-                            },
-                            Err(e) => Err(actix_web::error::ErrorInternalServerError(e))
-                        }
-                    }
-                    _ => Err(actix_web::error::ErrorBadRequest(""))
-                },
-                Err(e) => Err(actix_web::error::ErrorInternalServerError(e))
-            },
-            Err(e) => Err(actix_web::error::ErrorInternalServerError(e))
-        }
+    match match data.db.send(db::RecordGatewayEvent::new(
+        "worldpay",
+        &event.order_code,
+        &event_status,
+        payment.as_ref().map(|p| &p.id),
+        &raw_body,
+        state_change,
+        authorised_until.as_ref(),
+        &crate::audit::Actor::gateway(&req, "worldpay"),
+    )).await {
+        Ok(r) => r,
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
     } {
         Ok(_) => {}
-        Err(_) => {
-            context.insert("threeds_approved", &false);
+        Err(e) => {
+            warn!("Not applying Worldpay {} event for order {} yet: {}", event_status, event.order_code, e);
+            return Err(e.into());
         }
-    }
+    };
 
-    match crate::TERA.render("3ds_complete.html", &context) {
-        Ok(r) => Ok(HttpResponse::Ok().body(r)),
-        Err(e) => Err(actix_web::error::ErrorInternalServerError(e).into())
-    }
-}
\ No newline at end of file
+    Ok(HttpResponse::Ok().finish())
+}